@@ -1,21 +1,51 @@
-use crate::config::CommonOpt;
+use crate::{
+    config::CommonOpt,
+    cors::{preflight_response, CorsExt},
+};
 use geph4_protocol::binder::protocol::BinderClient;
+use governor::{clock::DefaultClock, state::keyed::DefaultKeyedStateStore, Quota, RateLimiter};
+use moka::sync::{Cache, CacheBuilder};
 use nanorpc::{DynRpcTransport, RpcTransport};
 use nanorpc::{JrpcError, JrpcRequest, JrpcResponse};
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use smol::io::AsyncBufReadExt;
-use std::sync::Arc;
+use smol_timeout::TimeoutExt;
+use std::{
+    io::Read,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU32,
+    sync::Arc,
+    time::Duration,
+};
+use stdcode::StdcodeSerializeExt;
 use structopt::StructOpt;
+use tmelcrypt::{HashVal, Hashable};
 
 #[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
 pub struct BinderProxyOpt {
     #[structopt(flatten)]
     pub common: CommonOpt,
+
+    #[structopt(long)]
+    /// Serves JSON-RPC requests POSTed over HTTP on this address, instead of reading them from stdin.
+    pub listen: Option<SocketAddr>,
+
+    #[structopt(long)]
+    /// With --listen, answers clients that send more than this many requests a minute, per IP address, with 429 Too Many Requests.
+    pub rate_limit: Option<NonZeroU32>,
+
+    #[structopt(long)]
+    /// With --listen, lets browser front-ends served from this origin call the binder proxy, by answering CORS preflight requests and sending Access-Control-Allow-Origin. "*" allows any origin.
+    pub cors: Option<String>,
 }
 
 pub async fn main_binderproxy(opt: BinderProxyOpt) -> anyhow::Result<()> {
-    log::info!("binder proxy mode started; send a JSON-RPC line on stdin to get a response");
     let binder_client = Arc::new(opt.common.get_binder_client());
+    if let Some(listen) = opt.listen {
+        return serve_http(listen, opt.rate_limit, opt.cors, binder_client).await;
+    }
+    log::info!("binder proxy mode started; send a JSON-RPC line on stdin to get a response");
     let mut input = smol::io::BufReader::new(smol::Unblock::new(std::io::stdin()));
     let mut line = String::new();
     loop {
@@ -26,14 +56,126 @@ pub async fn main_binderproxy(opt: BinderProxyOpt) -> anyhow::Result<()> {
     }
 }
 
+/// The largest request body served over HTTP. Binder requests are much smaller than this.
+const MAX_REQUEST_BODY: u64 = 64 * 1024;
+
+/// How long a client gets to send its request body.
+const REQUEST_BODY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Limits the requests of every client IP address, for --rate-limit.
+type ClientRateLimiter = RateLimiter<IpAddr, DefaultKeyedStateStore<IpAddr>, DefaultClock>;
+
+/// Serves JSON-RPC requests POSTed over HTTP, each answered like a line on stdin would be.
+async fn serve_http(
+    listen: SocketAddr,
+    rate_limit: Option<NonZeroU32>,
+    cors: Option<String>,
+    binder_client: Arc<BinderClient<DynRpcTransport>>,
+) -> anyhow::Result<()> {
+    let server = Arc::new(tiny_http::Server::http(listen).map_err(|err| anyhow::anyhow!(err))?);
+    log::info!("binder proxy mode started; POST JSON-RPC requests to http://{listen}");
+    let limiter: Option<Arc<ClientRateLimiter>> =
+        rate_limit.map(|n| Arc::new(RateLimiter::keyed(Quota::per_minute(n))));
+    loop {
+        let server = server.clone();
+        let mut request = smol::unblock(move || server.recv()).await?;
+        let binder_client = binder_client.clone();
+        let limiter = limiter.clone();
+        let cors = cors.clone();
+        smolscale::spawn(async move {
+            if let (Some(limiter), Some(client)) = (&limiter, request.remote_addr()) {
+                // forget clients that have been quiet for a while, so that this doesn't grow forever
+                if limiter.len() > 10000 {
+                    limiter.retain_recent();
+                }
+                if limiter.check_key(&client.ip()).is_err() {
+                    log::debug!("rate limiting binder proxy client {}", client.ip());
+                    request.respond_cors(cors.as_deref(), tiny_http::Response::empty(429))?;
+                    return anyhow::Ok(());
+                }
+            }
+            let is_preflight = request.method() == &tiny_http::Method::Options;
+            if let (Some(origin), true) = (&cors, is_preflight) {
+                request.respond(preflight_response(origin))?;
+                return anyhow::Ok(());
+            }
+            if request.method() != &tiny_http::Method::Post {
+                request.respond_cors(cors.as_deref(), tiny_http::Response::empty(405))?;
+                return anyhow::Ok(());
+            }
+            // the body is read with blocking IO, so it must not hold up a worker or wait forever on a slow client
+            let read_body = smol::unblock(move || {
+                let mut line = String::new();
+                let result = request
+                    .as_reader()
+                    .take(MAX_REQUEST_BODY + 1)
+                    .read_to_string(&mut line);
+                (request, result.map(|_| line))
+            });
+            let (request, line) = match read_body.timeout(REQUEST_BODY_TIMEOUT).await {
+                Some((request, result)) => (request, result?),
+                None => {
+                    log::debug!("timed out reading a binder proxy request body");
+                    return anyhow::Ok(());
+                }
+            };
+            if line.len() as u64 > MAX_REQUEST_BODY {
+                request.respond_cors(cors.as_deref(), tiny_http::Response::empty(413))?;
+                return anyhow::Ok(());
+            }
+            match binderproxy_once(binder_client, line).await {
+                Ok(resp) => {
+                    request.respond_cors(cors.as_deref(), tiny_http::Response::from_string(resp))?
+                }
+                Err(err) => request.respond_cors(
+                    cors.as_deref(),
+                    tiny_http::Response::from_string(err.to_string()).with_status_code(400),
+                )?,
+            }
+            anyhow::Ok(())
+        })
+        .detach();
+    }
+}
+
+/// Binder methods whose responses are the same for every client, and so can be served from [RESPONSE_CACHE].
+const CACHEABLE_METHODS: &[&str] = &[
+    "get_summary",
+    "get_mizaru_pk",
+    "get_mizaru_epoch_key",
+    "get_announcements",
+];
+
+/// Successful responses to cacheable requests, by method and parameters, so that clients asking the same thing in quick succession don't each reach the binder.
+static RESPONSE_CACHE: Lazy<Cache<HashVal, JrpcResponse>> = Lazy::new(|| {
+    CacheBuilder::new(1000)
+        .time_to_live(Duration::from_secs(30))
+        .build()
+});
+
 pub async fn binderproxy_once(
     binder_client: Arc<BinderClient<DynRpcTransport>>,
     line: String,
 ) -> anyhow::Result<String> {
     log::info!("binder proxy once");
     let req: JrpcRequest = serde_json::from_str(&line)?;
+    let cache_key = CACHEABLE_METHODS
+        .contains(&req.method.as_str())
+        .then(|| (&req.method, &req.params).stdcode().hash());
+    if let Some(mut res) = cache_key.and_then(|key| RESPONSE_CACHE.get(&key)) {
+        log::debug!("serving {} from the cache", req.method);
+        res.id = req.id;
+        return Ok(serde_json::to_string(&res)?);
+    }
     match binder_client.0.call_raw(req.clone()).await {
-        Ok(res) => Ok(serde_json::to_string(&res)?),
+        Ok(res) => {
+            if let Some(key) = cache_key {
+                if res.error.is_none() {
+                    RESPONSE_CACHE.insert(key, res.clone());
+                }
+            }
+            Ok(serde_json::to_string(&res)?)
+        }
         Err(err) => {
             let err = JrpcResponse {
                 jsonrpc: "2.0".into(),
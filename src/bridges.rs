@@ -0,0 +1,130 @@
+use std::sync::Arc;
+
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::{
+    config::{get_conninfo_store, AuthOpt, CommonOpt},
+    connect::tunnel::{
+        getsess::{bridge_allowed, probe_bridge, select_endpoints},
+        BinderTunnelParams,
+    },
+};
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub enum BridgesOpt {
+    /// Probes every bridge to an exit, and saves the fastest ones as the sticky set used by "connect --sticky-bridges".
+    Benchmark(BridgeBenchmarkOpt),
+}
+
+impl BridgesOpt {
+    pub fn common(&self) -> &CommonOpt {
+        match self {
+            BridgesOpt::Benchmark(opt) => &opt.common,
+        }
+    }
+
+    pub fn auth(&self) -> &AuthOpt {
+        match self {
+            BridgesOpt::Benchmark(opt) => &opt.auth,
+        }
+    }
+
+    pub fn auth_mut(&mut self) -> &mut AuthOpt {
+        match self {
+            BridgesOpt::Benchmark(opt) => &mut opt.auth,
+        }
+    }
+}
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub struct BridgeBenchmarkOpt {
+    #[structopt(flatten)]
+    pub common: CommonOpt,
+
+    #[structopt(flatten)]
+    pub auth: AuthOpt,
+
+    /// The exit whose bridges to benchmark. This should be the exit later passed to "connect --exit-server".
+    #[structopt(long)]
+    pub exit: String,
+
+    /// How many of the fastest bridges to save as the sticky set.
+    #[structopt(long, default_value = "5")]
+    pub top: usize,
+
+    /// Only benchmarks bridges that are not direct connections to the exit, as "connect --use-bridges" would use. Give this if "connect" will use bridges, such as in China, since a sticky set of direct connections is no use then.
+    #[structopt(long)]
+    pub use_bridges: bool,
+}
+
+/// Entry point to the bridges subcommand.
+pub async fn main_bridges(opt: BridgesOpt) -> anyhow::Result<()> {
+    match opt {
+        BridgesOpt::Benchmark(opt) => main_benchmark(opt).await,
+    }
+}
+
+async fn main_benchmark(opt: BridgeBenchmarkOpt) -> anyhow::Result<()> {
+    let cstore = Arc::new(get_conninfo_store(&opt.common, &opt.auth, &opt.exit).await?);
+    cstore.refresh().await?;
+    let params = BinderTunnelParams {
+        cstore: cstore.clone(),
+        exit_server: Some(opt.exit.clone()),
+        exit_pubkey: None,
+        chain_exit: None,
+        use_bridges: opt.use_bridges,
+        force_bridge: None,
+        force_protocol: None,
+        obfs: None,
+        bridges_in_use: None,
+        avoid_bridges: None,
+    };
+    let (_, bridges) = select_endpoints(&params)?;
+    let bridges: Vec<_> = bridges
+        .into_iter()
+        .filter(|bridge| bridge_allowed(&params, bridge))
+        .collect();
+    log::info!("probing {} bridges to {}", bridges.len(), opt.exit);
+    let results = join_all(bridges.into_iter().map(|bridge| async move {
+        let result = probe_bridge(bridge.clone()).await;
+        (bridge, result)
+    }))
+    .await;
+
+    let mut reachable: Vec<_> = results
+        .iter()
+        .filter_map(|(bridge, result)| {
+            cstore.record_bridge_result(bridge.endpoint, result.as_ref().ok().copied());
+            match result {
+                Ok(latency) => Some((bridge, *latency)),
+                Err(err) => {
+                    println!("{:<24} {:<20} !! {}", bridge.endpoint, bridge.protocol, err);
+                    None
+                }
+            }
+        })
+        .collect();
+    reachable.sort_by_key(|(_, latency)| *latency);
+    for (bridge, latency) in reachable.iter() {
+        println!(
+            "{:<24} {:<20} {:.1}ms",
+            bridge.endpoint,
+            bridge.protocol,
+            latency.as_secs_f64() * 1000.0
+        );
+    }
+    if reachable.is_empty() {
+        anyhow::bail!("no bridges to {} were reachable", opt.exit)
+    }
+
+    let sticky: Vec<_> = reachable
+        .iter()
+        .take(opt.top)
+        .map(|(bridge, _)| bridge.endpoint)
+        .collect();
+    log::info!("saving {} bridges as the sticky set", sticky.len());
+    cstore.set_sticky_bridges(sticky);
+    Ok(())
+}
@@ -49,6 +49,38 @@ pub fn is_chinese_host(host: &str) -> bool {
 /// Returns whether or not we're in China.
 #[cached::proc_macro::cached(result = true)]
 pub async fn test_china() -> http_types::Result<bool> {
+    match public_ip().await? {
+        IpAddr::V4(inner) => Ok(is_chinese_ip(inner)),
+        IpAddr::V6(_) => Err(anyhow::anyhow!("cannot tell for ipv6").into()),
+    }
+}
+
+/// Decides whether to use bridges, along with why, given whether --use-bridges was given, whether the China test was skipped, and if not, whether it found us in China (`None` if it failed).
+pub fn bridge_decision(
+    use_bridges: bool,
+    skip_test: bool,
+    in_china: Option<bool>,
+) -> (bool, &'static str) {
+    if skip_test {
+        return if use_bridges {
+            (true, "--use-bridges was given")
+        } else {
+            (
+                false,
+                "--use-bridges was not given and the China test was skipped",
+            )
+        };
+    }
+    match in_china {
+        None => (true, "the China test failed, so assuming China"),
+        Some(true) => (true, "in China"),
+        Some(false) if use_bridges => (true, "--use-bridges was given"),
+        Some(false) => (false, "not in China and --use-bridges was not given"),
+    }
+}
+
+/// Returns our public IP address, as seen from the Internet.
+pub async fn public_ip() -> http_types::Result<IpAddr> {
     let req = Request::new(
         Method::Get,
         Url::parse("http://checkip.amazonaws.com").unwrap(),
@@ -57,7 +89,7 @@ pub async fn test_china() -> http_types::Result<bool> {
 
     let response = {
         let connection =
-            smol::net::TcpStream::connect(connect_to.get(0).context("no addrs for checkip")?)
+            smol::net::TcpStream::connect(connect_to.first().context("no addrs for checkip")?)
                 .await?;
         async_h1::connect(connection, req)
             .await?
@@ -65,9 +97,5 @@ pub async fn test_china() -> http_types::Result<bool> {
             .await?
     };
     let response = response.trim();
-    let parsed: IpAddr = response.parse()?;
-    match parsed {
-        IpAddr::V4(inner) => Ok(is_chinese_ip(inner)),
-        IpAddr::V6(_) => Err(anyhow::anyhow!("cannot tell for ipv6").into()),
-    }
+    Ok(response.parse()?)
 }
@@ -1,4 +1,9 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use crate::{conninfo_store::ConnInfoStore, fronts::parse_fronts};
 use anyhow::Context;
@@ -6,6 +11,7 @@ use anyhow::Context;
 use geph4_protocol::binder::protocol::{BinderClient, Credentials};
 use once_cell::sync::{Lazy, OnceCell};
 
+use ipnet::{IpNet, Ipv4Net};
 use serde::{Deserialize, Serialize};
 use std::net::{Ipv4Addr, SocketAddr};
 use stdcode::StdcodeSerializeExt;
@@ -20,7 +26,32 @@ pub fn override_config(opt: Opt) {
 }
 
 /// The global configuration of the client.
-pub static CONFIG: Lazy<Opt> = Lazy::new(|| INIT_CONFIG.get_or_init(Opt::from_args).clone());
+pub static CONFIG: Lazy<Opt> = Lazy::new(|| {
+    let mut opt = INIT_CONFIG.get_or_init(Opt::from_args).clone();
+    if let Some(auth) = opt.auth_mut() {
+        if let Err(err) = auth.resolve_credentials() {
+            exit_invalid(err)
+        }
+    }
+    if let Opt::Connect(connect) = &opt {
+        if !connect.tunnel_uid.is_empty() && !cfg!(target_os = "linux") {
+            exit_invalid(anyhow::anyhow!("--tunnel-uid is only supported on Linux"))
+        }
+        if !connect.exit_chain.is_empty() && connect.exit_chain.len() != 2 {
+            exit_invalid(anyhow::anyhow!("--exit-chain must list exactly two exits"))
+        }
+    }
+    opt
+});
+
+/// Exits with a usage error, the way clap does, for arguments that parse but cannot be used.
+fn exit_invalid(err: anyhow::Error) -> ! {
+    structopt::clap::Error::with_description(
+        &format!("{:#}", err),
+        structopt::clap::ErrorKind::ValueValidation,
+    )
+    .exit()
+}
 
 #[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
 #[allow(clippy::large_enum_variant)]
@@ -30,6 +61,33 @@ pub enum Opt {
     Sync(crate::sync::SyncOpt),
     BinderProxy(crate::binderproxy::BinderProxyOpt),
     Debugpack(crate::debugpack::DebugPackOpt),
+    Logout(crate::logout::LogoutOpt),
+    Resolve(crate::resolve::ResolveOpt),
+    RouteTest(crate::route_test::RouteTestOpt),
+    RouteDump(crate::route_dump::RouteDumpOpt),
+    Geo(crate::geo::GeoOpt),
+    Version(crate::version::VersionOpt),
+    Bridges(crate::bridges::BridgesOpt),
+}
+
+impl Opt {
+    /// The authentication options of the subcommand, if it takes any.
+    fn auth_mut(&mut self) -> Option<&mut AuthOpt> {
+        match self {
+            Opt::Connect(opt) => Some(&mut opt.auth),
+            Opt::BridgeTest(opt) => Some(&mut opt.auth),
+            Opt::Sync(opt) => Some(&mut opt.auth),
+            Opt::Logout(opt) => Some(&mut opt.auth),
+            Opt::Resolve(opt) => Some(&mut opt.auth),
+            Opt::Bridges(opt) => Some(opt.auth_mut()),
+            Opt::BinderProxy(_)
+            | Opt::Debugpack(_)
+            | Opt::RouteTest(_)
+            | Opt::RouteDump(_)
+            | Opt::Geo(_)
+            | Opt::Version(_) => None,
+        }
+    }
 }
 
 #[derive(Debug, StructOpt, Clone, Deserialize, Serialize)]
@@ -44,10 +102,22 @@ pub struct ConnectOpt {
     /// Whether or not to use bridges
     pub use_bridges: bool,
 
+    #[structopt(long)]
+    /// Skip probing whether we're in China, and use bridges only if --use-bridges is given
+    pub no_china_test: bool,
+
+    #[structopt(long)]
+    /// Skip probing whether we're behind a captive portal, such as the login page of hotel wifi, at startup and when the binder can't be reached
+    pub no_captive_portal_check: bool,
+
     #[structopt(long)]
     /// Overrides everything else, forcing connection to a particular sosistab URL (of the form pk@host:port). This also disables any form of authentication.
     pub override_connect: Option<String>,
 
+    #[structopt(long)]
+    /// Connects using a pre-fetched endpoints file (as written by "sync --export-endpoints") instead of the binder, so that the binder is never contacted. The exit is the one in the file, so --exit-server is ignored.
+    pub endpoints_file: Option<PathBuf>,
+
     #[structopt(long)]
     /// Force a particular bridge
     pub force_bridge: Option<Ipv4Addr>,
@@ -68,52 +138,305 @@ pub struct ConnectOpt {
     /// Lifetime of a single TCP connection. Geph will switch to a different TCP connection within this many seconds.
     pub tcp_shard_lifetime: u64,
 
+    #[structopt(long)]
+    /// If given, replaces the session to the exit with a fresh one, possibly over a different bridge, once it is this many seconds old. The new session is set up before the old one stops taking new connections, and connections already on the old one get 5 more minutes to finish. Has no effect with --vpn-mode, since a fresh session may be assigned a different VPN address, which would drop every VPN flow.
+    pub max_session_secs: Option<u64>,
+
+    #[structopt(long)]
+    /// If given, a connection to a bridge or exit that has been sent traffic but has received nothing for this many seconds is considered dead and reconnected right away, instead of after the usual longer wait.
+    pub socket_read_timeout: Option<f64>,
+
+    #[structopt(long)]
+    /// If given, a connection to a bridge or exit whose writes stall for this many seconds is considered dead and reconnected right away.
+    pub socket_write_timeout: Option<f64>,
+
     #[structopt(long, default_value = "127.0.0.1:9910")]
     /// Where to listen for HTTP proxy connections
     pub http_listen: SocketAddr,
+
+    #[structopt(long)]
+    /// Doesn't run the HTTP proxy, leaving only SOCKS5, so that with --unified-proxy, HTTP clients are refused. This also saves starting the Tokio runtime that the HTTP proxy needs.
+    pub no_http_proxy: bool,
+
     #[structopt(long, default_value = "127.0.0.1:9909")]
     /// Where to listen for SOCKS5 connections
     pub socks5_listen: SocketAddr,
+
+    #[structopt(long)]
+    /// Serves the HTTP proxy on the SOCKS5 port too, telling the two apart by the first byte each client sends. --http-listen is then not listened on, and --socks5-allow-from applies to HTTP clients as well.
+    pub unified_proxy: bool,
+
+    #[structopt(long)]
+    /// Only accepts SOCKS5 clients from the given subnet, like 192.168.1.0/24. Can be given multiple times. Clients on this machine are always accepted. If not given, any client is accepted.
+    pub socks5_allow_from: Vec<IpNet>,
+
+    #[structopt(long, requires = "proxy-tls-key")]
+    /// PEM certificate chain to serve the SOCKS5 and HTTP proxies over TLS with, so that traffic to them is encrypted on the network. Requires --proxy-tls-key. Clients must then speak TLS to both proxies.
+    pub proxy_tls_cert: Option<PathBuf>,
+
+    #[structopt(long, requires = "proxy-tls-cert")]
+    /// PKCS#8 PEM private key for --proxy-tls-cert.
+    pub proxy_tls_key: Option<PathBuf>,
     #[structopt(long, default_value = "127.0.0.1:9809")]
     /// Where to listen for REST-based local connections
     pub stats_listen: SocketAddr,
 
+    #[structopt(long, parse(try_from_str = str_to_stats_cors))]
+    /// Lets browser front-ends served from this origin, such as "http://localhost:3000", call the stats API, by answering CORS preflight requests and sending Access-Control-Allow-Origin. That origin can then do anything the stats API allows, including downloading debug packs and connecting or disconnecting, so only name origins you trust. "*" is not accepted.
+    pub stats_cors: Option<String>,
+
+    #[structopt(long, default_value = "0.2", parse(try_from_str = str_to_fraction))]
+    /// Smoothing factor, between 0 and 1, of the smoothed latency reported in stats. Each new sample gets this much weight, so smaller values give a steadier but slower-moving figure.
+    pub latency_smoothing: f64,
+
+    #[structopt(long, default_value = "10000", parse(try_from_str = str_to_stats_interval))]
+    /// How often, in milliseconds, latency and traffic stats are sampled while the tunnel is in use, and sent to --stats-csv and --statsd. Each sample is a round trip to the exit, so smaller values give finer-grained stats at the cost of more overhead. Must be at least 100.
+    pub stats_interval_ms: u64,
+
+    #[structopt(long)]
+    /// If given, periodically sends byte counts, latency, and connection status to a StatsD server at this host:port over UDP.
+    pub statsd: Option<String>,
+
+    #[structopt(long)]
+    /// If given, appends a CSV row of the timestamp, bytes sent and received so far, latest latency, protocol, and exit to this file every --stats-interval-ms, for plotting connection metrics over time.
+    pub stats_csv: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Keep EDNS Client Subnet options in proxied DNS queries. By default they are removed, so that your subnet isn't revealed to DNS servers.
+    pub dns_keep_ecs: bool,
+
+    #[structopt(long, default_value = "split")]
+    /// Where names asked of the DNS proxy are resolved. Possible options are:
+    /// - "tunnel": through the tunnel
+    /// - "direct": by the system nameserver, while connections still go through the tunnel
+    /// - "split": by the system nameserver for names whose connections go directly (Chinese domains with --exclude-prc), and through the tunnel otherwise
+    ///
+    /// The system nameserver is never used in "tun-route" VPN mode, where it would loop back to us.
+    pub dns_route: DnsRoute,
+
+    #[structopt(long)]
+    /// Sends the DNS proxy's queries of one record type to their own upstream, as TYPE=UPSTREAM, like "A=https://cloudflare-dns.com/dns-query" or "PTR=tcp://9.9.9.9:53". TYPE is a name like A, AAAA, PTR, MX, or TXT, or a number. UPSTREAM is "tcp://HOST:PORT" for plain DNS over TCP or an "https://" URL for DNS-over-HTTPS, both through the tunnel, or "system" for the system nameserver, which is never used in "tun-route" VPN mode. Can be given multiple times. Other types follow --dns-route.
+    pub dns_upstream_for: Vec<DnsUpstreamFor>,
+
+    #[structopt(long)]
+    /// Logs every query to the DNS proxy, with its name, type, where it was answered from, how long it took, and the answer.
+    pub dns_log: bool,
+
+    #[structopt(long)]
+    /// If given, DNS responses are cached in this file, so that they survive restarts until their TTLs run out.
+    pub dns_cache_persist: Option<PathBuf>,
+
+    #[structopt(long, default_value = "0")]
+    /// Raises TTLs below this many seconds, in responses from the DNS proxy and in its cache, up to it. This reduces repeated lookups of records with tiny TTLs. 0 disables the floor.
+    pub dns_min_ttl: u32,
+
+    #[structopt(long)]
+    /// Refreshes cached DNS responses in the background when they are used close to the end of their TTL, so that names in use are answered from the cache without waiting.
+    pub dns_prefetch: bool,
+
+    #[structopt(long)]
+    /// Answers A and AAAA queries to the DNS proxy with the addresses at the end of any CNAME chain, directly under the queried name, for apps that mishandle CNAMEs. Targets missing from the upstream answer are looked up too. Chains that loop or are longer than 8 are passed through unchanged.
+    pub dns_flatten_cname: bool,
+
+    #[structopt(long, default_value = "0")]
+    /// Most queries the DNS proxy sends upstream at once, whether through the tunnel or to the system nameserver. Queries beyond this wait their turn, so bursts don't flood the tunnel. Answers from the cache are never held back. 0 means no limit.
+    pub dns_max_inflight: usize,
+
     #[structopt(long, default_value = "127.0.0.1:15353")]
     /// Where to listen for proxied DNS requests.
     pub dns_listen: SocketAddr,
 
+    #[structopt(long)]
+    /// If the HTTP, SOCKS5, or DNS listening port is already taken, listen on an OS-assigned port instead of failing. The port picked is logged.
+    pub auto_port: bool,
+
     #[structopt(long)]
     /// Which exit server to connect to. If there isn't an exact match, the exit server with the most similar hostname is picked. If not given, a random server will be selected.
     pub exit_server: Option<String>,
 
+    #[structopt(long, parse(try_from_str = str_to_exit_pubkey))]
+    /// Only connects to an exit whose signing key is this hex-encoded ed25519 public key, refusing to connect otherwise. Without --exit-server, the exit with this key is picked.
+    pub exit_pubkey: Option<[u8; 32]>,
+
+    #[structopt(long, use_delimiter = true, conflicts_with = "exit-balance")]
+    /// Chains two exits, as in "a.geph.io,b.geph.io", so that traffic goes through the first exit and then the second. Overrides --exit-server. The second exit must support accepting chained sessions, or else connecting fails with an error.
+    pub exit_chain: Vec<String>,
+
+    #[structopt(long, use_delimiter = true)]
+    /// Spreads new SOCKS5 and HTTP proxy connections round-robin across tunnels to all the given exits, as in "a.geph.io,b.geph.io", so that parallel connections can use several exits at once. Each connection stays on one exit. Exits that are down are skipped. The first exit is used as --exit-server if that isn't given.
+    pub exit_balance: Vec<String>,
+
+    #[structopt(long, conflicts_with = "exit-balance")]
+    /// Sends SOCKS5 and HTTP proxy connections through a separate tunnel to this exit, while VPN traffic, DNS, and everything else keep going through --exit-server. Cannot be combined with --exit-balance.
+    pub socks5_exit: Option<String>,
+
+    #[structopt(long, parse(try_from_str = str_to_regex))]
+    /// Gives SOCKS5 and HTTP proxy connections whose destination, as "host:port", matches this regex their own session to the exit, instead of sharing one with all other traffic. This avoids head-of-line blocking for latency-sensitive flows, at the cost of setting up a new session, within --dial-timeout-secs, for each of them.
+    pub dedicated_session: Option<String>,
+
+    #[structopt(long)]
+    /// Periodically checks the load of the exit, and once it has stayed above --exit-rebalance-load for several minutes, moves new connections and the VPN to the least-loaded exit in the same country. Open connections finish on the old exit.
+    pub exit_rebalance: bool,
+
+    #[structopt(long, default_value = "0.8")]
+    /// The exit load, as reported by the binder, beyond which --exit-rebalance looks for another exit.
+    pub exit_rebalance_load: f64,
+
     #[structopt(long)]
     /// Whether or not to exclude PRC domains
     pub exclude_prc: bool,
 
+    #[structopt(long)]
+    /// Sends SOCKS5 and HTTP proxy connections to loopback, private, and link-local addresses, as well as to hostnames without a public suffix, through the tunnel. By default, they go directly to the local network.
+    pub proxy_local: bool,
+
+    #[structopt(long)]
+    /// Only sends traffic from the given local user ID through Geph, on Linux. Can be given multiple times. SOCKS5 and HTTP proxy clients run by other users connect directly, including through --unified-proxy and --proxy-tls-cert, as do, with --vpn-mode tun-route, all other users' processes. Clients on other machines are unaffected. If not given, every user's traffic goes through Geph.
+    pub tunnel_uid: Vec<u32>,
+
+    #[structopt(long)]
+    /// Path to an executable that decides how SOCKS5 connections are routed. It is called with the destination host and port as arguments, and must print "direct" or "proxy". Decisions are cached, and take precedence over --exclude-prc. If the script fails or takes longer than 2 seconds, the connection is proxied.
+    pub route_script: Option<PathBuf>,
+
     #[structopt(long)]
     /// Whether or not to wait for VPN commands on stdio
     pub stdio_vpn: bool,
 
     #[structopt(long)]
-    /// Whether or not to stick to the same set of bridges
+    /// On Unix, detaches from the terminal and keeps running in the background once the listening ports are set up. The command returns successfully only if startup got that far.
+    pub daemonize: bool,
+
+    #[structopt(long)]
+    /// Writes the process ID to this file, which with --daemonize is the ID of the background process.
+    pub pid_file: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// With --daemonize, where log output goes. By default, it is discarded, though it is still kept in the debug pack.
+    pub log_file: Option<PathBuf>,
+
+    #[structopt(long, default_value = "stderr")]
+    /// Where log output goes: "stderr", or "syslog" (Unix only) to send it to syslog or the systemd journal with matching severities. Either way, it is still kept in the debug pack.
+    pub log_target: LogTarget,
+
+    #[structopt(long)]
+    /// Whether or not to stick to the same set of bridges, as saved by "bridges benchmark"
     pub sticky_bridges: bool,
 
     #[structopt(long)]
-    /// Specify whether and how to create a L3 VPN tunnel. Possible options are:
+    /// Specify whether and how to create a L3 VPN tunnel. This can be a comma-separated list, such as "windivert,none", of modes tried in order until one starts, where "none" means no VPN. Possible options are:
     /// - nothing (no VPN)
     /// - "inherited-fd" (reads a TUN device file descriptor number, inherited from the parent process, from the GEPH_VPN_FD environment variable)
-    /// - "tun-no-route" (Unix only; creates and configures a TUN device named "tun-geph", but does not change the routing table)
+    /// - "tun-no-route" (Unix only; creates and configures a TUN device named by --vpn-tun-name, but does not change the routing table)
     /// - "tun-route" (Unix only; creates and configures a TUN device, as well as executing platform-specific actions to force all non-Geph traffic through the tunnel)
     /// - "windivert" (Windows only; uses WinDivert to capture non-Geph traffic to feed into the VPN)
-    pub vpn_mode: Option<VpnMode>,
+    /// - "tproxy" (Linux only; accepts TCP connections redirected by user-supplied iptables TPROXY rules on --tproxy-listen, and proxies them to their original destinations)
+    pub vpn_mode: Option<VpnModeList>,
+
+    #[structopt(long, default_value = "16384")]
+    /// MTU of the VPN's TUN device. TCP connections through the VPN are also limited to segments that fit in it. Lower this, to something like 1280, if a warning says that large packets are being lost.
+    pub vpn_mtu: u16,
+
+    #[structopt(long)]
+    /// File descriptor of the TUN device in "inherited-fd" VPN mode. If not given, it is read from the GEPH_VPN_FD environment variable.
+    pub vpn_fd: Option<i32>,
+
+    #[structopt(long, default_value = "tun-geph")]
+    /// Name of the TUN device created in the "tun-no-route" and "tun-route" VPN modes, for referring to it in firewall rules. Linux only, where it can be at most 15 characters; on macOS, the OS picks the name.
+    pub vpn_tun_name: String,
+
+    #[structopt(long)]
+    /// Routes the given subnet (like 10.1.0.0/16) through the TUN device, in the "tun-no-route" and "tun-route" VPN modes, so that it reaches the exit's side. Can be given multiple times.
+    pub vpn_route_cidr: Vec<Ipv4Net>,
+
+    #[structopt(long, default_value = "0.0.0.0:9950")]
+    /// Where to listen for connections redirected by TPROXY rules, in "tproxy" VPN mode.
+    pub tproxy_listen: SocketAddr,
+
+    #[structopt(long)]
+    /// Whether or not to keep a second, independent tunnel warm, so that traffic fails over to it instantly if the main tunnel goes down. It goes to the same exit as the main tunnel, but keeps off its bridges unless there are no others.
+    pub standby_tunnel: bool,
+
+    #[structopt(long)]
+    /// Starts with the tunnel paused: listeners and the stats API come up, but no connection is made until a `POST /connect` to the stats API.
+    pub start_paused: bool,
+
+    #[structopt(long, default_value = "off")]
+    /// Whether or not to carry IPv6 through the VPN. Possible options are:
+    /// - "off" (IPv6 is not carried, and is blocked entirely in "tun-route" mode to prevent leaks)
+    /// - "on" (IPv6 is always carried; on Linux, the TUN device gets a private IPv6 address and, in "tun-route" mode, the IPv6 default route)
+    /// - "auto" (like "on", but only if the exit turns out to have IPv6 connectivity, and like "off" otherwise)
+    pub vpn_ipv6: VpnIpv6,
+
+    #[structopt(long)]
+    /// Answers IPv4 DNS queries captured by the VPN through the local DNS proxy, with its caching and --dns-route, instead of forwarding them through the tunnel to a public resolver.
+    pub vpn_dns_proxy: bool,
+
+    #[structopt(long, default_value = "10000")]
+    /// How many packets can wait to go through the VPN in each direction. Smaller queues keep latency down when traffic comes in faster than the tunnel can carry it.
+    pub vpn_queue_depth: usize,
+
+    #[structopt(long, default_value = "drop-newest")]
+    /// What happens to a VPN packet that finds its queue full. Possible options are:
+    /// - "drop-newest" (the packet is dropped)
+    /// - "drop-oldest" (the packet that has waited longest is dropped to make room)
+    /// - "block" (the packet waits for room, slowing down whatever is sending)
+    pub vpn_queue_overflow: VpnQueueOverflow,
+
+    #[structopt(long)]
+    /// If given, obscures traffic timing by delaying every outgoing tunnel packet by a random amount of up to this many milliseconds, and by occasionally sending padding packets. This adds at most this much latency, unless the tunnel is already backed up. Off by default.
+    pub obfs_pacing: Option<u64>,
 
     #[structopt(long)]
     /// Forces the protocol selected to match the given regex.
     pub force_protocol: Option<String>,
 
     #[structopt(long)]
-    /// SSH-style local-remote port forwarding. For example, "0.0.0.0:8888:::example.com:22" will forward local port 8888 to example.com:22. Must be in form host:port:::host:port! May have multiple ones.
+    /// Only connects to bridges using the given obfuscation layer: "obfsudp" (obfuscated UDP) or "tls-mimic" (mimics a TLS connection).
+    pub obfs: Option<ObfsLayer>,
+
+    #[structopt(long)]
+    /// Prefers bridges of the given transport family ("udp" or "tcp"), only falling back to the other family if none of them connect. Unlike --force-protocol, this never prevents connecting.
+    pub prefer_transport: Option<TransportFamily>,
+
+    #[structopt(long, default_value = "both")]
+    /// Which address families bridges are dialed over: "ipv4", "ipv6", or "both". Useful on networks with broken IPv6.
+    pub underlying_family: AddressFamily,
+
+    #[structopt(long)]
+    /// If given, enables TCP keepalive with this idle interval (in seconds) on proxied TCP sockets, so that long-idle connections survive NAT timeouts. This covers the sockets of local clients and of directly-routed connections, but not the tunnel's own connections to bridges, which sosistab2 opens without exposing the socket. Off by default.
+    pub tcp_keepalive_secs: Option<u64>,
+
+    #[structopt(long, default_value = "120")]
+    /// How long, in seconds, SOCKS5 connections wait for the destination to be reached before failing with "host unreachable".
+    pub dial_timeout_secs: u64,
+
+    #[structopt(long, default_value = "0")]
+    /// Keeps up to this many streams open ahead of time to each destination that SOCKS5 connections were recently made to, so that repeated connections to it skip stream setup. Unused streams are closed after a few seconds. 0 (the default) turns this off.
+    pub stream_pool: usize,
+
+    #[structopt(long, default_value = "65536")]
+    /// Size, in bytes, of the buffer used in each direction when relaying proxied connections. Larger buffers help bulk transfers on fast links, at the cost of memory per connection.
+    pub relay_buffer_size: usize,
+
+    #[structopt(long, parse(try_from_str = str_to_positive_secs))]
+    /// If given, proxied connections whose destination sends nothing back within this many seconds of being sent something are closed as failed, so that connections to black-holed destinations don't hang. Off by default.
+    pub first_byte_timeout: Option<f64>,
+
+    #[structopt(long)]
+    /// SSH-style local-remote port forwarding. For example, "0.0.0.0:8888:::example.com:22" will forward local port 8888 to example.com:22. Must be in form host:port:::host:port! May have multiple ones. An exit hostname can be appended, as in "0.0.0.0:8888:::example.com:22@us-east.exits.geph.io", to send that forward through a different exit than the main one.
     pub forward_ports: Vec<String>,
+
+    #[structopt(long, default_value = "3")]
+    /// How many times a forwarded connection retries reaching its destination, with exponential backoff of up to 30 seconds between retries, before it is given up on.
+    pub forward_retry: u32,
+
+    #[structopt(long, default_value = "0")]
+    /// How many more times to try connecting to a bridge whose handshake failed before abandoning it, which can help on lossy links. All bridges together still have to connect within 30 seconds.
+    pub bridge_handshake_retries: u32,
+
+    #[structopt(long, default_value = "0", parse(try_from_str = str_to_nonnegative_secs))]
+    /// How many seconds to wait, after an established connection drops, before the first attempt to reconnect, giving the network time to recover. Failed reconnect attempts are then retried as usual.
+    pub reconnect_initial_delay: f64,
 }
 
 /// An enum represennting the various VPN modes.
@@ -124,6 +447,45 @@ pub enum VpnMode {
     TunRoute,
     WinDivert,
     Stdio,
+    Tproxy,
+}
+
+/// VPN modes to try in order, where `None` stands for no VPN.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VpnModeList(pub Vec<Option<VpnMode>>);
+
+impl FromStr for VpnModeList {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|mode| match mode.trim() {
+                "none" => Ok(None),
+                mode => mode.parse().map(Some),
+            })
+            .collect::<anyhow::Result<_>>()
+            .map(Self)
+    }
+}
+
+impl VpnMode {
+    /// The names of the VPN modes that work on this platform.
+    pub fn available() -> &'static [&'static str] {
+        if cfg!(target_os = "linux") {
+            &[
+                "inherited-fd",
+                "tun-no-route",
+                "tun-route",
+                "stdio",
+                "tproxy",
+            ]
+        } else if cfg!(unix) {
+            &["inherited-fd", "tun-no-route", "tun-route", "stdio"]
+        } else if cfg!(windows) {
+            &["windivert", "stdio"]
+        } else {
+            &["stdio"]
+        }
+    }
 }
 
 impl FromStr for VpnMode {
@@ -135,12 +497,257 @@ impl FromStr for VpnMode {
             "tun-route" => Ok(Self::TunRoute),
             "windivert" => Ok(Self::WinDivert),
             "stdio" => Ok(Self::Stdio),
+            "tproxy" => Ok(Self::Tproxy),
 
             x => anyhow::bail!("unrecognized VPN mode {}", x),
         }
     }
 }
 
+/// An enum representing whether IPv6 is carried through the VPN.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Ord, Eq, Hash, Serialize, Deserialize)]
+pub enum VpnIpv6 {
+    On,
+    Off,
+    Auto,
+}
+
+impl FromStr for VpnIpv6 {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "on" => Ok(Self::On),
+            "off" => Ok(Self::Off),
+            "auto" => Ok(Self::Auto),
+
+            x => anyhow::bail!("unrecognized VPN IPv6 setting {}", x),
+        }
+    }
+}
+
+/// Where log output goes.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Ord, Eq, Hash, Serialize, Deserialize)]
+pub enum LogTarget {
+    Stderr,
+    Syslog,
+}
+
+impl FromStr for LogTarget {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stderr" => Ok(Self::Stderr),
+            "syslog" => Ok(Self::Syslog),
+
+            x => anyhow::bail!("unrecognized log target {}", x),
+        }
+    }
+}
+
+/// What happens to a VPN packet that finds its queue full.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Ord, Eq, Hash, Serialize, Deserialize)]
+pub enum VpnQueueOverflow {
+    DropNewest,
+    DropOldest,
+    Block,
+}
+
+impl FromStr for VpnQueueOverflow {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-newest" => Ok(Self::DropNewest),
+            "drop-oldest" => Ok(Self::DropOldest),
+            "block" => Ok(Self::Block),
+
+            x => anyhow::bail!("unrecognized VPN queue overflow policy {}", x),
+        }
+    }
+}
+
+/// An obfuscation layer that bridges can be reached through.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Ord, Eq, Hash, Serialize, Deserialize)]
+pub enum ObfsLayer {
+    ObfsUdp,
+    TlsMimic,
+}
+
+impl ObfsLayer {
+    /// The names accepted by --obfs.
+    pub const SUPPORTED: &'static [&'static str] = &["obfsudp", "tls-mimic"];
+
+    /// The bridge protocol implementing this obfuscation layer.
+    pub fn bridge_protocol(&self) -> &'static str {
+        match self {
+            Self::ObfsUdp => "sosistab2-obfsudp",
+            Self::TlsMimic => "sosistab2-obfstls",
+        }
+    }
+}
+
+impl FromStr for ObfsLayer {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "obfsudp" => Ok(Self::ObfsUdp),
+            "tls-mimic" => Ok(Self::TlsMimic),
+
+            x => anyhow::bail!(
+                "unrecognized obfuscation layer {}, supported: {}",
+                x,
+                Self::SUPPORTED.join(", ")
+            ),
+        }
+    }
+}
+
+/// Where the DNS proxy resolves names.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Ord, Eq, Hash, Serialize, Deserialize)]
+pub enum DnsRoute {
+    Tunnel,
+    Direct,
+    Split,
+}
+
+impl FromStr for DnsRoute {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tunnel" => Ok(Self::Tunnel),
+            "direct" => Ok(Self::Direct),
+            "split" => Ok(Self::Split),
+
+            x => anyhow::bail!("unrecognized DNS route {}", x),
+        }
+    }
+}
+
+/// A --dns-upstream-for override: where DNS queries of one record type are sent.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DnsUpstreamFor {
+    pub qtype: u16,
+    pub upstream: DnsUpstream,
+}
+
+/// An upstream for DNS queries, other than the default one.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DnsUpstream {
+    /// Plain DNS over TCP to this host:port, through the tunnel.
+    Tcp(String),
+    /// DNS-over-HTTPS to this URL, through the tunnel.
+    Https(String),
+    /// The system nameserver.
+    System,
+}
+
+impl FromStr for DnsUpstreamFor {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (qtype, upstream) = s
+            .split_once('=')
+            .context("DNS upstream override must be in form TYPE=UPSTREAM")?;
+        let qtype = match qtype.to_ascii_uppercase().as_str() {
+            "A" => 1,
+            "NS" => 2,
+            "CNAME" => 5,
+            "SOA" => 6,
+            "PTR" => 12,
+            "MX" => 15,
+            "TXT" => 16,
+            "AAAA" => 28,
+            "SRV" => 33,
+            "SVCB" => 64,
+            "HTTPS" => 65,
+            "ANY" => 255,
+            x => x
+                .parse()
+                .with_context(|| format!("unrecognized DNS record type {}", x))?,
+        };
+        let upstream = if upstream == "system" {
+            DnsUpstream::System
+        } else if let Some(addr) = upstream.strip_prefix("tcp://") {
+            if !addr.contains(':') {
+                anyhow::bail!("DNS upstream {} has no port", upstream)
+            }
+            DnsUpstream::Tcp(addr.to_string())
+        } else if upstream.starts_with("https://") {
+            http_types::Url::parse(upstream)
+                .with_context(|| format!("invalid DNS-over-HTTPS URL {}", upstream))?;
+            DnsUpstream::Https(upstream.to_string())
+        } else {
+            anyhow::bail!(
+                "unrecognized DNS upstream {}, supported: tcp://HOST:PORT, https://URL, system",
+                upstream
+            )
+        };
+        Ok(Self { qtype, upstream })
+    }
+}
+
+/// A family of transport protocols used by bridges.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Ord, Eq, Hash, Serialize, Deserialize)]
+pub enum TransportFamily {
+    Udp,
+    Tcp,
+}
+
+impl TransportFamily {
+    /// The family of the given bridge or pipe protocol, such as "sosistab2-obfsudp" or "obfstls-1".
+    pub fn of_protocol(protocol: &str) -> Option<Self> {
+        if protocol.contains("udp") {
+            Some(Self::Udp)
+        } else if protocol.contains("tls") || protocol.contains("tcp") {
+            Some(Self::Tcp)
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for TransportFamily {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "udp" => Ok(Self::Udp),
+            "tcp" => Ok(Self::Tcp),
+
+            x => anyhow::bail!("unrecognized transport family {}", x),
+        }
+    }
+}
+
+/// Which IP address families may be used for the connections underlying the tunnel.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Ord, Eq, Hash, Serialize, Deserialize)]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+    Both,
+}
+
+impl AddressFamily {
+    /// Whether the given address may be dialed.
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        match self {
+            Self::Ipv4 => addr.is_ipv4(),
+            Self::Ipv6 => addr.is_ipv6(),
+            Self::Both => true,
+        }
+    }
+}
+
+impl FromStr for AddressFamily {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ipv4" => Ok(Self::Ipv4),
+            "ipv6" => Ok(Self::Ipv6),
+            "both" => Ok(Self::Both),
+
+            x => anyhow::bail!("unrecognized address family {}", x),
+        }
+    }
+}
+
 #[derive(Debug, StructOpt, Clone, Deserialize, Serialize)]
 pub struct CommonOpt {
     #[structopt(
@@ -181,11 +788,24 @@ pub struct CommonOpt {
     /// mizaru master key of the binder, for PLUS
     binder_mizaru_plus: mizaru::PublicKey,
 
+    #[structopt(long, default_value = "60")]
+    /// How long, in seconds, a request to the binder may take, across all retries, before it fails.
+    pub binder_timeout_secs: u64,
+
+    #[structopt(long)]
+    /// Maximum age, in seconds, of cached binder data (exit list, bridges, and authentication token). Older data is refreshed before it is used, independently of the background refresh.
+    pub conninfo_max_age_secs: Option<u64>,
+
     #[structopt(long, default_value = "file::memory:?cache=shared")]
     pub debugpack_path: String,
 }
 
 impl CommonOpt {
+    /// A short fingerprint of the binder master key, for checking which binder a build talks to.
+    pub fn binder_master_fingerprint(&self) -> String {
+        hex::encode(&blake3::hash(self.binder_master.as_bytes()).as_bytes()[..8])
+    }
+
     /// Connects to the binder, given these parameters.
     pub fn get_binder_client(&self) -> BinderClient {
         BinderClient(parse_fronts(
@@ -194,6 +814,7 @@ impl CommonOpt {
                 .split(',')
                 .zip(self.binder_http_hosts.split(','))
                 .map(|(k, v)| (k.to_string(), v.to_string())),
+            Duration::from_secs(self.binder_timeout_secs),
         ))
     }
 }
@@ -208,8 +829,144 @@ pub struct AuthOpt {
     /// where to store Geph's credential cache. The default value is "auto", meaning a platform-specific path that Geph gets to pick.
     pub credential_cache: PathBuf,
 
+    #[structopt(long)]
+    /// Reads the credentials from the environment variable of this name, which must contain JSON such as {"AuthPassword":{"username":"...","password":"..."}}, instead of from an auth-password or auth-keypair subcommand.
+    pub auth_env: Option<String>,
+
+    #[structopt(long)]
+    /// Name of a credential profile kept in the credential cache. Credentials given along with --profile are saved to it; with --profile alone, the saved ones are used. Each profile has its own stored login.
+    pub profile: Option<String>,
+
+    #[structopt(long)]
+    /// Keeps the cached data of at most this many accounts in the credential cache, deleting that of the accounts least recently used. By default, it is kept forever.
+    pub cache_max_entries: Option<usize>,
+
     #[structopt(subcommand)]
-    pub auth_kind: AuthKind,
+    pub auth_kind: Option<AuthKind>,
+}
+
+impl AuthOpt {
+    /// The credentials to log in with. These are always present once [CONFIG] has resolved them, since it rejects configurations without any.
+    pub fn auth_kind(&self) -> anyhow::Result<&AuthKind> {
+        self.auth_kind.as_ref().context(
+            "credentials must be given, either as a subcommand, through --auth-env, or with --profile",
+        )
+    }
+
+    /// Fills in the credentials from --auth-env or --profile, and saves credentials given along with --profile to that profile. Fails if no credentials can be found this way.
+    fn resolve_credentials(&mut self) -> anyhow::Result<()> {
+        self.load_auth_env()?;
+        if let Some(profile) = self.profile.as_ref() {
+            if profile.is_empty()
+                || !profile
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                anyhow::bail!("profile names may only contain letters, digits, '-' and '_'")
+            }
+            let mut path = self.credential_cache.clone();
+            path.push("profiles");
+            path.push(format!("{profile}.json"));
+            match self.auth_kind.as_ref() {
+                Some(auth_kind) => save_profile(&path, auth_kind)
+                    .with_context(|| format!("cannot save profile {profile} to {:?}", path))?,
+                None => {
+                    let json = std::fs::read(&path).with_context(|| {
+                        format!("cannot read profile {profile} from {:?}; give credentials along with --profile to create it", path)
+                    })?;
+                    self.auth_kind = Some(serde_json::from_slice(&json).with_context(|| {
+                        format!("profile {profile} at {:?} is not valid", path)
+                    })?);
+                }
+            }
+        }
+        self.auth_kind()?;
+        Ok(())
+    }
+
+    /// Fills in the credentials from the environment variable named by --auth-env, if given.
+    fn load_auth_env(&mut self) -> anyhow::Result<()> {
+        if let Some(var) = self.auth_env.as_ref() {
+            if self.auth_kind.is_some() {
+                anyhow::bail!("--auth-env cannot be combined with an auth subcommand")
+            }
+            let json = std::env::var(var).with_context(|| {
+                format!("environment variable {var} given by --auth-env is not set")
+            })?;
+            let auth_kind = serde_json::from_str(&json).with_context(|| {
+                format!("environment variable {var} does not contain valid credentials")
+            })?;
+            self.auth_kind = Some(auth_kind);
+        }
+        Ok(())
+    }
+
+    /// Returns the directory, within the credential cache, that holds the cached data of this particular account.
+    pub fn user_cache_dir(&self) -> anyhow::Result<PathBuf> {
+        // hash the username together with the password
+        let user_cache_key = hex::encode(blake3::hash(&self.auth_kind()?.stdcode()).as_bytes());
+        let mut dir = self.credential_cache.clone();
+        dir.push(user_cache_key);
+        Ok(dir)
+    }
+
+    /// Marks the cached data of this account as just used, then, with --cache-max-entries, deletes that of the accounts least recently used beyond the limit.
+    fn touch_user_cache(&self) -> anyhow::Result<()> {
+        let dir = self.user_cache_dir()?;
+        std::fs::write(
+            dir.join(LAST_USED_FILE),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)?
+                .as_secs()
+                .to_string(),
+        )?;
+        let max_entries = match self.cache_max_entries {
+            Some(max_entries) => max_entries.max(1),
+            None => return Ok(()),
+        };
+        let mut entries = vec![];
+        for entry in std::fs::read_dir(&self.credential_cache)? {
+            let path = entry?.path();
+            // account directories are named by a hex blake3 hash, unlike "profiles" and the like
+            let is_account = path.is_dir()
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| {
+                        name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit())
+                    });
+            if is_account && path != dir {
+                entries.push((last_used(&path), path));
+            }
+        }
+        // the current account counts as the most recently used one
+        if entries.len() < max_entries {
+            return Ok(());
+        }
+        entries.sort_unstable();
+        for (_, path) in &entries[..entries.len() + 1 - max_entries] {
+            log::debug!("deleting stale account cache {:?}", path);
+            if let Err(err) = std::fs::remove_dir_all(path) {
+                log::warn!("cannot delete stale account cache {:?}: {:?}", path, err);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The file, in the cache directory of each account, recording when it was last used.
+const LAST_USED_FILE: &str = "last-used";
+
+/// When the account cache directory was last used, as a UNIX timestamp. Directories from before last use was recorded fall back to their modification time.
+fn last_used(dir: &Path) -> u64 {
+    std::fs::read_to_string(dir.join(LAST_USED_FILE))
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .or_else(|| {
+            let modified = std::fs::metadata(dir).ok()?.modified().ok()?;
+            Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs())
+        })
+        .unwrap_or(0)
 }
 
 #[derive(Debug, StructOpt, Clone, Deserialize, Serialize)]
@@ -232,6 +989,34 @@ pub enum AuthKind {
     },
 }
 
+/// Writes a credential profile, readable only by the current user where possible.
+fn save_profile(path: &Path, auth_kind: &AuthKind) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    write_private(path, &serde_json::to_vec_pretty(auth_kind)?)?;
+    Ok(())
+}
+
+/// Writes a file that holds secrets, creating it readable only by the current user where possible.
+pub(crate) fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    // the mode only applies to newly created files
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents)
+}
+
 fn str_to_path(src: &str) -> PathBuf {
     // if it's auto then generate
     if src == "auto" {
@@ -254,20 +1039,94 @@ fn str_to_mizaru_pk(src: &str) -> mizaru::PublicKey {
     mizaru::PublicKey(raw_bts)
 }
 
+fn str_to_exit_pubkey(src: &str) -> anyhow::Result<[u8; 32]> {
+    let raw_bts = hex::decode(src).context("exit public key is not hex")?;
+    raw_bts
+        .as_slice()
+        .try_into()
+        .ok()
+        .context("exit public key must be 32 bytes")
+}
+
+fn str_to_fraction(src: &str) -> anyhow::Result<f64> {
+    let fraction: f64 = src.parse().context("not a number")?;
+    if !(0.0..=1.0).contains(&fraction) {
+        anyhow::bail!("must be between 0 and 1")
+    }
+    Ok(fraction)
+}
+
+fn str_to_positive_secs(src: &str) -> anyhow::Result<f64> {
+    let secs: f64 = src.parse().context("not a number of seconds")?;
+    if secs <= 0.0 || Duration::try_from_secs_f64(secs).is_err() {
+        anyhow::bail!("must be a finite, positive number of seconds")
+    }
+    Ok(secs)
+}
+
+fn str_to_nonnegative_secs(src: &str) -> anyhow::Result<f64> {
+    let secs: f64 = src.parse().context("not a number of seconds")?;
+    if secs < 0.0 || Duration::try_from_secs_f64(secs).is_err() {
+        anyhow::bail!("must be a finite, non-negative number of seconds")
+    }
+    Ok(secs)
+}
+
+fn str_to_stats_interval(src: &str) -> anyhow::Result<u64> {
+    let ms: u64 = src.parse().context("not a number of milliseconds")?;
+    if ms < 100 {
+        anyhow::bail!("must be at least 100 milliseconds")
+    }
+    Ok(ms)
+}
+
+fn str_to_regex(src: &str) -> Result<String, regex::Error> {
+    regex::Regex::new(src)?;
+    Ok(src.to_string())
+}
+
+fn str_to_stats_cors(src: &str) -> anyhow::Result<String> {
+    if src.trim() == "*" {
+        anyhow::bail!("--stats-cors must name an origin, since \"*\" would let any website control the daemon")
+    }
+    Ok(src.to_string())
+}
+
 /// Given the common and authentication options, produce a binder client.
 pub async fn get_conninfo_store(
     common_opt: &CommonOpt,
     auth_opt: &AuthOpt,
     exit_host: &str,
 ) -> anyhow::Result<ConnInfoStore> {
-    let auth_opt = auth_opt.clone();
+    open_conninfo_store(common_opt, auth_opt, exit_host, "conninfo.json").await
+}
+
+/// Like [get_conninfo_store], but keeps its cache separate from the main one. This is used when connecting to several exits at once, since every store caches bridges for one particular exit.
+pub async fn get_exit_conninfo_store(
+    common_opt: &CommonOpt,
+    auth_opt: &AuthOpt,
+    exit_host: &str,
+) -> anyhow::Result<ConnInfoStore> {
+    open_conninfo_store(
+        common_opt,
+        auth_opt,
+        exit_host,
+        &format!("conninfo-{}.json", exit_host),
+    )
+    .await
+}
 
-    // create a dbpath based on hashing the username together with the password
-    let mut dbpath = auth_opt.credential_cache.clone();
+async fn open_conninfo_store(
+    common_opt: &CommonOpt,
+    auth_opt: &AuthOpt,
+    exit_host: &str,
+    cache_name: &str,
+) -> anyhow::Result<ConnInfoStore> {
+    let auth_opt = auth_opt.clone();
 
-    let user_cache_key = hex::encode(blake3::hash(&auth_opt.auth_kind.stdcode()).as_bytes());
+    let mut dbpath = auth_opt.user_cache_dir()?;
 
-    let auth_kind = auth_opt.auth_kind;
+    let auth_kind = auth_opt.auth_kind()?.clone();
     let get_creds = move || match auth_kind.clone() {
         AuthKind::AuthPassword { username, password } => Credentials::Password {
             username: username.into(),
@@ -282,9 +1141,11 @@ pub async fn get_conninfo_store(
         }
     };
 
-    dbpath.push(&user_cache_key);
     std::fs::create_dir_all(&dbpath)?;
-    dbpath.push("conninfo.json");
+    if let Err(err) = auth_opt.touch_user_cache() {
+        log::warn!("cannot prune the credential cache: {:?}", err);
+    }
+    dbpath.push(cache_name);
 
     let cbc = ConnInfoStore::connect(
         &dbpath,
@@ -292,6 +1153,7 @@ pub async fn get_conninfo_store(
         common_opt.binder_mizaru_free.clone(),
         common_opt.binder_mizaru_plus.clone(),
         exit_host,
+        common_opt.conninfo_max_age_secs,
         get_creds,
     )
     .await?;
@@ -1,35 +1,79 @@
-use std::{convert::Infallible, ops::Deref, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
+use anyhow::Context;
 use async_compat::Compat;
 
-use china::test_china;
+use china::{bridge_decision, test_china};
 use futures_util::future::select_all;
 
 use once_cell::sync::Lazy;
 
 use parking_lot::RwLock;
 use rand::Rng;
+use regex::Regex;
 use smol::{prelude::*, Task};
 use smol_timeout::TimeoutExt;
 
 use crate::{
-    config::{get_conninfo_store, ConnectOpt, Opt, CONFIG},
-    connect::tunnel::{BinderTunnelParams, ClientTunnel, EndpointSource, TunnelStatus},
-    conninfo_store::ConnInfoStore,
+    config::{get_conninfo_store, get_exit_conninfo_store, AuthKind, ConnectOpt, Opt, CONFIG},
+    connect::tunnel::{
+        BinderTunnelParams, ClientTunnel, EndpointSource, SharedBridges, TunnelStatus,
+    },
+    conninfo_store::{ConnInfoStore, EndpointsFile, StoreErrorKind},
+    debugpack::DEBUGPACK,
 };
 
 use crate::china;
 
+mod captive_portal;
+pub(crate) mod daemon;
 mod dns;
+mod keepalive;
+mod listen;
 mod port_forwarder;
+mod proxy_tls;
+mod rebalance;
+mod relay;
+mod route_script;
+pub(crate) mod routing;
+pub(crate) mod socket_owner;
 mod socks5;
 mod stats;
-mod tunnel;
+mod stream_pool;
+pub(crate) mod tunnel;
+mod unified_proxy;
 pub(crate) mod vpn;
 
 /// Main function for `connect` subcommand
-pub fn start_main_connect() {
+pub fn start_main_connect() -> anyhow::Result<()> {
+    // bind the listening ports up front, so that conflicts are reported right away
+    proxy_tls::load_proxy_tls()?;
+    listen::bind_listeners()?;
+    record_config();
     Lazy::force(&CONNECT_TASK);
+    daemon::notify_ready();
+    Ok(())
+}
+
+/// Records the config in the debugpack, with the password redacted.
+fn record_config() {
+    let mut config = CONNECT_CONFIG.clone();
+    if let Some(AuthKind::AuthPassword { password, .. }) = &mut config.auth.auth_kind {
+        *password = "<redacted>".into();
+    }
+    match serde_json::to_string(&config) {
+        Ok(json) => DEBUGPACK.add_config(&json),
+        Err(err) => log::warn!("cannot serialize config: {:?}", err),
+    }
 }
 
 static METRIC_SESSION_ID: Lazy<i64> = Lazy::new(|| {
@@ -40,21 +84,35 @@ static METRIC_SESSION_ID: Lazy<i64> = Lazy::new(|| {
 /// The configured binder client
 static CONNINFO_STORE: Lazy<Arc<ConnInfoStore>> = Lazy::new(|| {
     Arc::new({
-        let (common, auth, exit_host) = match CONFIG.deref() {
-            Opt::Connect(c) => (
-                &c.common,
-                &c.auth,
-                c.exit_server.clone().unwrap_or_default(),
-            ),
-            _ => panic!(),
-        };
+        let (common, auth) = (&CONNECT_CONFIG.common, &CONNECT_CONFIG.auth);
         log::debug!("about to construct the global conninfo");
         smol::future::block_on(async move {
+            let exit_host = match (&CONNECT_CONFIG.exit_server, CONNECT_CONFIG.exit_pubkey) {
+                (Some(exit_server), _) => exit_server.clone(),
+                (None, Some(pubkey)) => exit_with_pubkey(pubkey).await,
+                (None, None) => String::new(),
+            };
             loop {
                 log::debug!("inside the blocked-on future for conninfo");
                 match get_conninfo_store(common, auth, &exit_host).await {
                     Ok(val) => return val,
-                    Err(err) => log::warn!("could not get conninfo store: {:?}", err),
+                    Err(err) => match StoreErrorKind::of(&err) {
+                        StoreErrorKind::Maintenance(maintenance) => {
+                            let delay = maintenance.retry_delay();
+                            log::warn!("{maintenance}, so retrying in {:?}", delay);
+                            smol::Timer::after(delay).await;
+                            continue;
+                        }
+                        StoreErrorKind::Auth => exit_bad_credentials(&err),
+                        StoreErrorKind::Transient => {
+                            if captive_portal::recheck().await {
+                                // already reported in a way that tells the user what to do
+                                log::debug!("could not get conninfo store, retrying: {:?}", err)
+                            } else {
+                                warn_limited!("could not get conninfo store, retrying: {:?}", err)
+                            }
+                        }
+                    },
                 }
                 smol::Timer::after(Duration::from_secs(1)).await;
             }
@@ -62,34 +120,116 @@ static CONNINFO_STORE: Lazy<Arc<ConnInfoStore>> = Lazy::new(|| {
     })
 });
 
+/// Finds the hostname of the exit with the given signing key, for --exit-pubkey without --exit-server.
+async fn exit_with_pubkey(pubkey: [u8; 32]) -> String {
+    loop {
+        let found = async {
+            let cstore =
+                get_conninfo_store(&CONNECT_CONFIG.common, &CONNECT_CONFIG.auth, "").await?;
+            cstore.refresh().await?;
+            cstore
+                .summary()
+                .exits
+                .into_iter()
+                .find(|exit| exit.signing_key.as_bytes() == &pubkey)
+                .map(|exit| exit.hostname.to_string())
+                .with_context(|| format!("no exit has the signing key {}", hex::encode(pubkey)))
+        };
+        match found.await {
+            Ok(hostname) => {
+                log::info!("exit with the given public key is {hostname}");
+                return hostname;
+            }
+            Err(err) if StoreErrorKind::of(&err) == StoreErrorKind::Auth => {
+                exit_bad_credentials(&err)
+            }
+            Err(err) => warn_limited!("could not find exit by public key: {:?}", err),
+        }
+        smol::Timer::after(Duration::from_secs(1)).await;
+    }
+}
+
+/// Gives up for good when the binder rejects our credentials, since retrying would only loop forever.
+fn exit_bad_credentials(err: &anyhow::Error) -> ! {
+    log::error!(
+        "the binder rejected the login ({err}), so check the username and password or key given"
+    );
+    std::process::exit(1)
+}
+
 static CONNECT_CONFIG: Lazy<ConnectOpt> = Lazy::new(|| match CONFIG.deref() {
-    Opt::Connect(c) => c.clone(),
+    Opt::Connect(c) => {
+        let mut c = c.clone();
+        // with an exit chain, the tunnel itself goes to the first exit of the chain
+        if let Some(first) = c.exit_chain.first() {
+            c.exit_server = Some(first.clone());
+        }
+        if c.exit_server.is_none() {
+            c.exit_server = c.exit_balance.first().cloned();
+        }
+        c
+    }
     _ => panic!(),
 });
 
-static SHOULD_USE_BRIDGES: Lazy<bool> = Lazy::new(|| {
-    smol::future::block_on(async {
-        // Test china
-        let is_china = test_china().timeout(Duration::from_secs(2)).await;
-        match is_china {
-            Some(Err(_)) | None => {
-                log::warn!(
-                    "could not tell whether or not we're in China , so assuming that we are!",
-                );
-                true
-            }
-            Some(Ok(true)) => {
-                log::info!("we are in CHINA :O");
-                true
-            }
-            _ => {
-                log::info!("not in China :)");
-                CONNECT_CONFIG.use_bridges
+static SHOULD_USE_BRIDGES: Lazy<bool> = Lazy::new(|| BRIDGE_DECISION.0);
+
+/// Whether to use bridges, along with why.
+static BRIDGE_DECISION: Lazy<(bool, &'static str)> = Lazy::new(|| {
+    let skip_test = CONNECT_CONFIG.no_china_test || !cfg!(feature = "china-test");
+    let in_china = if skip_test {
+        log::info!("skipping the China test");
+        None
+    } else {
+        smol::future::block_on(async {
+            // Test china
+            match test_china().timeout(Duration::from_secs(2)).await {
+                Some(Err(_)) | None => {
+                    log::warn!(
+                        "could not tell whether or not we're in China , so assuming that we are!",
+                    );
+                    None
+                }
+                Some(Ok(true)) => {
+                    log::info!("we are in CHINA :O");
+                    Some(true)
+                }
+                Some(Ok(false)) => {
+                    log::info!("not in China :)");
+                    Some(false)
+                }
             }
-        }
-    })
+        })
+    };
+    bridge_decision(CONNECT_CONFIG.use_bridges, skip_test, in_china)
 });
 
+/// Summarizes how the endpoints of the main tunnel are chosen, and why.
+fn log_endpoint_decision() {
+    let exit_reason = if !CONNECT_CONFIG.exit_chain.is_empty() {
+        "first exit of --exit-chain"
+    } else if !CONNECT_CONFIG.exit_balance.is_empty()
+        && CONNECT_CONFIG.exit_balance.first() == CONNECT_CONFIG.exit_server.as_ref()
+    {
+        "first exit of --exit-balance"
+    } else if CONNECT_CONFIG.exit_server.is_some() {
+        "--exit-server"
+    } else {
+        "none given"
+    };
+    let (use_bridges, bridges_reason) = *BRIDGE_DECISION;
+    log::info!(
+        "endpoint decision: exit = {:?} ({}), bridges = {} ({}), force_protocol = {:?}, force_bridge = {:?}, obfs = {:?}",
+        CONNECT_CONFIG.exit_server.as_deref().unwrap_or(""),
+        exit_reason,
+        if use_bridges { "yes" } else { "no" },
+        bridges_reason,
+        CONNECT_CONFIG.force_protocol,
+        CONNECT_CONFIG.force_bridge,
+        CONNECT_CONFIG.obfs,
+    );
+}
+
 type StatusCallback = Box<dyn Fn(TunnelStatus) + Send + Sync + 'static>;
 static TUNNEL_STATUS_CALLBACK: Lazy<RwLock<StatusCallback>> = Lazy::new(|| {
     RwLock::new(Box::new(|addr| {
@@ -98,25 +238,250 @@ static TUNNEL_STATUS_CALLBACK: Lazy<RwLock<StatusCallback>> = Lazy::new(|| {
 });
 
 pub static TUNNEL: Lazy<ClientTunnel> = Lazy::new(|| {
-    let endpoint = {
-        if let Some(override_url) = CONNECT_CONFIG.override_connect.clone() {
-            EndpointSource::Independent {
-                endpoint: override_url,
+    log::debug!("gonna construct the tunnel");
+    let endpoint = if CONNECT_CONFIG.standby_tunnel {
+        tunnel_endpoint_sharing(&PRIMARY_BRIDGES, &STANDBY_BRIDGES)
+    } else {
+        tunnel_endpoint()
+    };
+    ClientTunnel::new(endpoint, PAUSED.load(Ordering::SeqCst), |status| {
+        TUNNEL_STATUS_CALLBACK.read()(status)
+    })
+});
+
+/// A second, independent tunnel that is kept warm so that traffic can fail over instantly when the primary tunnel goes down. It goes to the same exit, but over different bridges where possible. Only exists if `--standby-tunnel` is given.
+static STANDBY_TUNNEL: Lazy<Option<ClientTunnel>> = Lazy::new(|| {
+    if CONNECT_CONFIG.standby_tunnel {
+        log::debug!("gonna construct the standby tunnel");
+        Some(ClientTunnel::new(
+            tunnel_endpoint_sharing(&STANDBY_BRIDGES, &PRIMARY_BRIDGES),
+            PAUSED.load(Ordering::SeqCst),
+            |status| TUNNEL_STATUS_CALLBACK.read()(status),
+        ))
+    } else {
+        None
+    }
+});
+
+/// Whether the tunnels are paused, as by `--start-paused` or `POST /disconnect`.
+static PAUSED: Lazy<AtomicBool> = Lazy::new(|| AtomicBool::new(CONNECT_CONFIG.start_paused));
+
+/// Pauses or resumes every tunnel. Tunnels that are yet to be constructed pick this up when they are.
+pub async fn set_paused(paused: bool) {
+    PAUSED.store(paused, Ordering::SeqCst);
+    if let Some(tunnel) = Lazy::get(&TUNNEL) {
+        tunnel.set_paused(paused);
+    }
+    if let Some(Some(standby)) = Lazy::get(&STANDBY_TUNNEL) {
+        standby.set_paused(paused);
+    }
+    for tunnel in EXIT_TUNNELS.lock().await.values() {
+        tunnel.set_paused(paused);
+    }
+    log::info!("tunnels {}", if paused { "paused" } else { "resumed" });
+}
+
+/// The tunnel to the exit that --exit-rebalance moved traffic to, if it moved away from the main one.
+static REBALANCED_TUNNEL: RwLock<Option<&'static ClientTunnel>> = RwLock::new(None);
+
+/// Returns the tunnel that traffic should currently go through. This is the primary tunnel, unless it is down while the standby tunnel is up.
+pub fn active_tunnel() -> &'static ClientTunnel {
+    if let Some(rebalanced) = *REBALANCED_TUNNEL.read() {
+        if rebalanced.status().connected() {
+            return rebalanced;
+        }
+    }
+    if !TUNNEL.status().connected() {
+        if let Some(Some(standby)) = Lazy::get(&STANDBY_TUNNEL) {
+            if standby.status().connected() {
+                return standby;
             }
-        } else {
-            EndpointSource::Binder(BinderTunnelParams {
-                cstore: CONNINFO_STORE.clone(),
-                exit_server: CONNECT_CONFIG.exit_server.clone(),
-                use_bridges: *SHOULD_USE_BRIDGES,
-                force_bridge: CONNECT_CONFIG.force_bridge,
-                force_protocol: CONNECT_CONFIG.force_protocol.clone(),
-            })
         }
+    }
+    &TUNNEL
+}
+
+/// Returns the tunnel that a new proxied connection should go through. With --socks5-exit, this is the tunnel to that exit. With --exit-balance, this rotates through the tunnels to the listed exits, skipping those that are down or cannot be set up, and fails only if none of them can be set up. Otherwise, this is the [active_tunnel].
+pub async fn balanced_tunnel() -> anyhow::Result<&'static ClientTunnel> {
+    static NEXT_EXIT: AtomicUsize = AtomicUsize::new(0);
+    if let Some(exit) = CONNECT_CONFIG.socks5_exit.as_deref() {
+        return tunnel_for_exit(exit).await;
+    }
+    let exits = &CONNECT_CONFIG.exit_balance;
+    if exits.is_empty() {
+        return Ok(active_tunnel());
+    }
+    let start = NEXT_EXIT.fetch_add(1, Ordering::Relaxed);
+    let mut fallback = None;
+    let mut last_err = None;
+    for i in 0..exits.len() {
+        let exit = &exits[(start + i) % exits.len()];
+        let tunnel = match tunnel_for_exit(exit).await {
+            Ok(tunnel) => tunnel,
+            Err(err) => {
+                warn_limited!("cannot set up the tunnel to {exit}, skipping it: {:?}", err);
+                last_err = Some(err);
+                continue;
+            }
+        };
+        if tunnel.status().connected() {
+            return Ok(tunnel);
+        }
+        fallback.get_or_insert(tunnel);
+    }
+    // nothing is up, so wait on the first tunnel that could be set up
+    match fallback {
+        Some(tunnel) => Ok(tunnel),
+        None => Err(last_err
+            .unwrap_or_else(|| anyhow::anyhow!("no exits"))
+            .context("none of the --exit-balance tunnels can be set up")),
+    }
+}
+
+/// Tunnels to exits other than the main one, keyed by exit hostname.
+static EXIT_TUNNELS: Lazy<smol::lock::Mutex<HashMap<String, &'static ClientTunnel>>> =
+    Lazy::new(Default::default);
+
+/// Destinations that get a session of their own, from --dedicated-session.
+static DEDICATED_SESSION: Lazy<Option<Regex>> = Lazy::new(|| {
+    CONNECT_CONFIG
+        .dedicated_session
+        .as_deref()
+        .map(|pattern| Regex::new(pattern).expect("--dedicated-session is checked when parsed"))
+});
+
+/// With --dedicated-session, returns a new tunnel, to the same endpoint as the given shared one, for a connection to a matching destination. The tunnel is torn down when dropped.
+pub fn dedicated_tunnel(dest: &str, shared: &ClientTunnel) -> Option<ClientTunnel> {
+    if !DEDICATED_SESSION.as_ref()?.is_match(dest) {
+        return None;
+    }
+    log::debug!("constructing a dedicated tunnel for {dest}");
+    let endpoint = match shared.get_endpoint() {
+        // dedicated sessions take whatever bridges they like, and must not touch the sets the main and standby tunnels share
+        EndpointSource::Binder(params) => EndpointSource::Binder(BinderTunnelParams {
+            bridges_in_use: None,
+            avoid_bridges: None,
+            ..params
+        }),
+        endpoint => endpoint,
     };
-    log::debug!("gonna construct the tunnel");
-    ClientTunnel::new(endpoint, |status| TUNNEL_STATUS_CALLBACK.read()(status))
+    Some(ClientTunnel::new(
+        endpoint,
+        PAUSED.load(Ordering::SeqCst),
+        |status| TUNNEL_STATUS_CALLBACK.read()(status),
+    ))
+}
+
+/// Returns a tunnel to the given exit, creating it if needed. The main tunnel is returned if the exit is the one it already goes to.
+pub async fn tunnel_for_exit(exit: &str) -> anyhow::Result<&'static ClientTunnel> {
+    if CONNECT_CONFIG.exit_server.as_deref() == Some(exit) {
+        return Ok(&TUNNEL);
+    }
+    if CONNECT_CONFIG.override_connect.is_some() {
+        anyhow::bail!("cannot connect to exit {exit} when --override-connect is given")
+    }
+    if CONNECT_CONFIG.endpoints_file.is_some() {
+        anyhow::bail!("cannot connect to exit {exit} when --endpoints-file is given")
+    }
+    let mut tunnels = EXIT_TUNNELS.lock().await;
+    if let Some(tunnel) = tunnels.get(exit) {
+        return Ok(tunnel);
+    }
+    log::debug!("constructing an extra tunnel to {exit}");
+    let cstore = Arc::new(
+        get_exit_conninfo_store(&CONNECT_CONFIG.common, &CONNECT_CONFIG.auth, exit).await?,
+    );
+    // keep the store fresh, just like the main one
+    let refresh_cstore = cstore.clone();
+    smolscale::spawn(async move {
+        loop {
+            smol::Timer::after(Duration::from_secs(120)).await;
+            if let Err(err) = refresh_cstore.refresh().await {
+                warn_limited!("error refreshing store: {:?}", err);
+            }
+        }
+    })
+    .detach();
+    let tunnel: &'static ClientTunnel = Box::leak(Box::new(ClientTunnel::new(
+        EndpointSource::Binder(BinderTunnelParams {
+            cstore,
+            exit_server: Some(exit.to_string()),
+            exit_pubkey: None,
+            chain_exit: None,
+            use_bridges: *SHOULD_USE_BRIDGES,
+            force_bridge: CONNECT_CONFIG.force_bridge,
+            force_protocol: CONNECT_CONFIG.force_protocol.clone(),
+            obfs: CONNECT_CONFIG.obfs,
+            bridges_in_use: None,
+            avoid_bridges: None,
+        }),
+        PAUSED.load(Ordering::SeqCst),
+        |status| TUNNEL_STATUS_CALLBACK.read()(status),
+    )));
+    tunnels.insert(exit.to_string(), tunnel);
+    Ok(tunnel)
+}
+
+/// The contents of --endpoints-file, if given. A file that cannot be loaded is fatal, since there is nothing else to connect to.
+static ENDPOINTS_FILE: Lazy<Option<Arc<EndpointsFile>>> = Lazy::new(|| {
+    let path = CONNECT_CONFIG.endpoints_file.as_ref()?;
+    match EndpointsFile::load(path) {
+        Ok(endpoints) => Some(Arc::new(endpoints)),
+        Err(err) => {
+            log::error!("could not load the endpoints file {:?}: {:?}", path, err);
+            std::process::exit(1)
+        }
+    }
 });
 
+fn tunnel_endpoint() -> EndpointSource {
+    if let Some(override_url) = CONNECT_CONFIG.override_connect.clone() {
+        log::info!("endpoint decision: --override-connect {override_url}");
+        EndpointSource::Independent {
+            endpoint: override_url,
+        }
+    } else if let Some(endpoints) = ENDPOINTS_FILE.clone() {
+        log::info!(
+            "endpoint decision: --endpoints-file {:?}",
+            CONNECT_CONFIG.endpoints_file
+        );
+        EndpointSource::File(endpoints)
+    } else {
+        log_endpoint_decision();
+        EndpointSource::Binder(BinderTunnelParams {
+            cstore: CONNINFO_STORE.clone(),
+            // with only --exit-pubkey, the store was opened for the exit with that key
+            exit_server: CONNECT_CONFIG.exit_server.clone().or_else(|| {
+                CONNECT_CONFIG
+                    .exit_pubkey
+                    .map(|_| CONNINFO_STORE.cached_exit())
+            }),
+            exit_pubkey: CONNECT_CONFIG.exit_pubkey,
+            chain_exit: CONNECT_CONFIG.exit_chain.get(1).cloned(),
+            use_bridges: *SHOULD_USE_BRIDGES,
+            force_bridge: CONNECT_CONFIG.force_bridge,
+            force_protocol: CONNECT_CONFIG.force_protocol.clone(),
+            obfs: CONNECT_CONFIG.obfs,
+            bridges_in_use: None,
+            avoid_bridges: None,
+        })
+    }
+}
+
+/// The bridges of the main tunnel and the standby tunnel respectively, which each keeps off the other's, so that they don't fail together when a bridge goes down.
+static PRIMARY_BRIDGES: Lazy<SharedBridges> = Lazy::new(Default::default);
+static STANDBY_BRIDGES: Lazy<SharedBridges> = Lazy::new(Default::default);
+
+/// Like [tunnel_endpoint], but keeping track of the bridges in use in `in_use`, and keeping off those in `avoid`.
+fn tunnel_endpoint_sharing(in_use: &SharedBridges, avoid: &SharedBridges) -> EndpointSource {
+    let mut endpoint = tunnel_endpoint();
+    if let EndpointSource::Binder(params) = &mut endpoint {
+        params.bridges_in_use = Some(in_use.clone());
+        params.avoid_bridges = Some(avoid.clone());
+    }
+    endpoint
+}
+
 static CONNECT_TASK: Lazy<Task<Infallible>> = Lazy::new(|| {
     smolscale::spawn(async {
         // print out config file
@@ -126,34 +491,118 @@ static CONNECT_TASK: Lazy<Task<Infallible>> = Lazy::new(|| {
             CONNECT_CONFIG.force_protocol,
             CONNECT_CONFIG.use_bridges
         );
+        Lazy::force(&ENDPOINTS_FILE);
+        // the stats API comes up first, since it is how a paused daemon is told to connect
+        Lazy::force(&stats::STATS_THREAD);
 
-        // http proxy
-        let _socks2h = smolscale::spawn(Compat::new(crate::socks2http::run_tokio(
-            CONNECT_CONFIG.http_listen,
-            {
-                let mut addr = CONNECT_CONFIG.socks5_listen;
-                addr.set_ip("127.0.0.1".parse().unwrap());
-                addr
-            },
-        )));
+        // http proxy, which needs a Tokio runtime, so only started if wanted
+        let listeners = listen::listeners();
+        let inner = &listeners.inner;
+        let _socks2h = match (CONNECT_CONFIG.no_http_proxy, &inner.http) {
+            (false, Some(http)) => {
+                Some(smolscale::spawn(Compat::new(crate::socks2http::run_tokio(
+                    http.take()
+                        .expect("the HTTP proxy listener is only taken here"),
+                    {
+                        let mut addr = inner.socks5.addr();
+                        addr.set_ip("127.0.0.1".parse().unwrap());
+                        addr
+                    },
+                    CONNECT_CONFIG.relay_buffer_size,
+                ))))
+            }
+            _ => None,
+        };
 
         // socks5 proxy
         let socks5_fut = smolscale::spawn(socks5::socks5_loop(
-            CONNECT_CONFIG.socks5_listen,
+            &inner.socks5,
             CONNECT_CONFIG.exclude_prc,
         ));
+        // TLS in front of both, if enabled, and the listener telling them apart with --unified-proxy
+        let (_socks5_tls, _http_tls, _unified) = if CONNECT_CONFIG.unified_proxy {
+            // one port for both, telling them apart behind TLS
+            let unified_inner = inner.unified.as_ref().expect("bound with --unified-proxy");
+            let http_inner = inner.http.as_ref().expect("bound with --unified-proxy");
+            let unified = smolscale::spawn(unified_proxy::unified_front(
+                unified_inner,
+                inner.socks5.addr(),
+                http_inner.addr(),
+            ));
+            let tls = smolscale::spawn(proxy_tls::tls_front(
+                "SOCKS5 and HTTP proxy",
+                &listeners.socks5,
+                unified_inner.addr(),
+                socks5::socks5_allowed,
+            ));
+            (tls, None, Some(unified))
+        } else {
+            let socks5_tls = smolscale::spawn(proxy_tls::tls_front(
+                "SOCKS5",
+                &listeners.socks5,
+                inner.socks5.addr(),
+                socks5::socks5_allowed,
+            ));
+            let http_tls = listeners.http.as_ref().zip(inner.http.as_ref());
+            let http_tls = http_tls.map(|(http, http_inner)| {
+                smolscale::spawn(proxy_tls::tls_front(
+                    "HTTP proxy",
+                    http,
+                    http_inner.addr(),
+                    |_| true,
+                ))
+            });
+            (socks5_tls, http_tls, None)
+        };
+        // start the tunnels of --exit-balance and --socks5-exit right away, so that they are up by the time connections come in
+        let _balance = smolscale::spawn(async {
+            for exit in CONNECT_CONFIG
+                .exit_balance
+                .iter()
+                .chain(CONNECT_CONFIG.socks5_exit.iter())
+            {
+                if let Err(err) = tunnel_for_exit(exit).await {
+                    log::warn!("cannot start the tunnel to {exit}: {:?}", err);
+                }
+            }
+        });
+        let _captive_portal = smolscale::spawn(captive_portal::captive_portal_loop());
+        // statsd
+        let _statsd = smolscale::spawn(stats::statsd_loop());
+        let _stats_csv = smolscale::spawn(stats::stats_csv_loop());
         // dns
-        let dns_fut = smolscale::spawn(dns::dns_loop(CONNECT_CONFIG.dns_listen));
+        let dns_fut = smolscale::spawn(dns::dns_loop(&listeners.dns));
+        let _dns_persist = smolscale::spawn(dns::dns_cache_persist_loop());
+        let _rebalance = smolscale::spawn(rebalance::rebalance_loop());
         // refresh
         let refresh_fut = smolscale::spawn(async {
+            if CONNECT_CONFIG.endpoints_file.is_some() {
+                // everything comes from the endpoints file, so the binder is never contacted
+                return smol::future::pending().await;
+            }
             loop {
                 if let Err(err) = CONNINFO_STORE.refresh().await {
-                    log::warn!("error refreshing store: {:?}", err);
+                    warn_limited!("error refreshing store: {:?}", err);
                 }
                 smol::Timer::after(Duration::from_secs(120)).await;
             }
         });
 
+        // the standby tunnel waits for the main one, so that it knows which bridges to keep off
+        if CONNECT_CONFIG.standby_tunnel {
+            smolscale::spawn(async {
+                let _ = async {
+                    while !TUNNEL.status().connected() {
+                        smol::Timer::after(Duration::from_millis(100)).await;
+                    }
+                }
+                .timeout(Duration::from_secs(30))
+                .await;
+                Lazy::force(&STANDBY_TUNNEL);
+            })
+            .detach();
+        }
+
         // port forwarders
         let port_forwarders: Vec<_> = CONNECT_CONFIG
             .forward_ports
@@ -164,8 +613,6 @@ static CONNECT_TASK: Lazy<Task<Infallible>> = Lazy::new(|| {
             smolscale::spawn(select_all(port_forwarders)).await;
         }
 
-        Lazy::force(&stats::STATS_THREAD);
-
         // ready, set, go!
         Lazy::force(&vpn::VPN_SHUFFLE_TASK);
         socks5_fut.race(dns_fut).race(refresh_fut).await.unwrap();
@@ -0,0 +1,96 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use http_types::{Method, Request, Url};
+use parking_lot::Mutex;
+use smol_timeout::TimeoutExt;
+
+use super::CONNECT_CONFIG;
+
+/// A page that, without a captive portal in the way, is exactly [PROBE_BODY]. Unlike Google's equivalent, it is reachable from China.
+const PROBE_URL: &str = "http://captive.apple.com/hotspot-detect.html";
+const PROBE_BODY: &str = "<HTML><HEAD><TITLE>Success</TITLE></HEAD><BODY>Success</BODY></HTML>";
+
+/// While a captive portal is detected, how often we check whether it's gone.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// How often failures to reach the binder may trigger a check.
+const MIN_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Whether we seem to be behind a captive portal.
+static DETECTED: AtomicBool = AtomicBool::new(false);
+
+static LAST_CHECK: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Whether we seemed to be behind a captive portal when last checked.
+pub fn captive_portal_detected() -> bool {
+    DETECTED.load(Ordering::Relaxed)
+}
+
+/// Checks at startup whether we're behind a captive portal, and keeps checking until it's gone if so.
+pub async fn captive_portal_loop() {
+    if CONNECT_CONFIG.no_captive_portal_check {
+        return;
+    }
+    while check().await {
+        smol::Timer::after(RECHECK_INTERVAL).await;
+    }
+}
+
+/// Checks whether we're behind a captive portal, unless that was checked recently, returning whether we are. This is meant for when the binder can't be reached, to tell the user what to do about it.
+pub async fn recheck() -> bool {
+    if CONNECT_CONFIG.no_captive_portal_check {
+        return false;
+    }
+    let recent = LAST_CHECK
+        .lock()
+        .is_some_and(|last| last.elapsed() < MIN_CHECK_INTERVAL);
+    if recent {
+        return captive_portal_detected();
+    }
+    check().await
+}
+
+/// Probes for a captive portal, logging when one appears or goes away, and returns whether there is one. A failed probe counts as no captive portal, since it says nothing either way.
+async fn check() -> bool {
+    *LAST_CHECK.lock() = Some(Instant::now());
+    let detected = match probe().timeout(Duration::from_secs(5)).await {
+        Some(Ok(detected)) => detected,
+        Some(Err(err)) => {
+            log::debug!("captive portal probe failed: {:?}", err);
+            false
+        }
+        None => {
+            log::debug!("captive portal probe timed out");
+            false
+        }
+    };
+    let was_detected = DETECTED.swap(detected, Ordering::Relaxed);
+    if detected && !was_detected {
+        log::error!(
+            "captive portal detected: this network wants you to log in or accept its terms first, so open a browser and visit any website to do so"
+        );
+    } else if !detected && was_detected {
+        log::info!("captive portal is gone");
+    }
+    detected
+}
+
+/// Fetches the probe page directly, returning whether something other than the expected page came back.
+async fn probe() -> anyhow::Result<bool> {
+    let url = Url::parse(PROBE_URL)?;
+    let host = url.host_str().context("no host in probe URL")?;
+    let connect_to = geph4_aioutils::resolve(&format!("{host}:80")).await?;
+    let connection =
+        smol::net::TcpStream::connect(connect_to.first().context("no addrs for probe")?).await?;
+    let mut response = async_h1::connect(connection, Request::new(Method::Get, url))
+        .await
+        .map_err(|err| err.into_inner())?;
+    let body = response
+        .body_string()
+        .await
+        .map_err(|err| err.into_inner())?;
+    Ok(response.status() != 200 || body.trim() != PROBE_BODY)
+}
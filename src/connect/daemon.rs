@@ -0,0 +1,87 @@
+use std::{fs::File, io::Write, path::Path};
+
+use anyhow::Context;
+use parking_lot::Mutex;
+
+use crate::config::ConnectOpt;
+
+/// The pipe on which the daemon tells its waiting parent that it has started.
+static READY_PIPE: Mutex<Option<File>> = Mutex::new(None);
+
+/// With --daemonize, forks into the background, returning only in the child. The parent waits until the child calls [notify_ready] once its listeners are set up, then exits, successfully only if the child got that far. Also writes the --pid-file, if given.
+///
+/// This must be called before any threads are started.
+pub fn setup(opt: &ConnectOpt) -> anyhow::Result<()> {
+    if opt.daemonize {
+        daemonize(opt.log_file.as_deref())?;
+    }
+    if let Some(path) = opt.pid_file.as_ref() {
+        std::fs::write(path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("cannot write PID file {:?}", path))?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn daemonize(log_file: Option<&Path>) -> anyhow::Result<()> {
+    use std::{
+        fs::OpenOptions,
+        io::Read,
+        os::unix::io::{AsRawFd, FromRawFd},
+    };
+
+    // opened before forking, so that problems are still reported on the terminal
+    let log = match log_file {
+        Some(path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("cannot open log file {:?}", path))?,
+        None => OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    let null = File::open("/dev/null")?;
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("cannot create pipe");
+    }
+    let (mut read_end, write_end) =
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error()).context("cannot fork"),
+        0 => {
+            drop(read_end);
+            unsafe {
+                libc::setsid();
+                libc::dup2(null.as_raw_fd(), 0);
+                libc::dup2(log.as_raw_fd(), 1);
+                libc::dup2(log.as_raw_fd(), 2);
+            }
+            *READY_PIPE.lock() = Some(write_end);
+            Ok(())
+        }
+        child => {
+            drop(write_end);
+            // the pipe is closed without anything written if the child dies first
+            let mut ready = [0u8; 1];
+            if read_end.read(&mut ready).unwrap_or(0) == 1 {
+                eprintln!("geph4-client started in the background with PID {child}");
+                std::process::exit(0)
+            } else {
+                eprintln!("geph4-client failed to start in the background; see its log");
+                std::process::exit(1)
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn daemonize(_log_file: Option<&Path>) -> anyhow::Result<()> {
+    anyhow::bail!("--daemonize is only supported on Unix")
+}
+
+/// Tells the parent waiting in [setup], if any, that the daemon has started.
+pub fn notify_ready() {
+    if let Some(mut pipe) = READY_PIPE.lock().take() {
+        let _ = pipe.write_all(b"1");
+    }
+}
@@ -1,49 +1,484 @@
-use std::net::SocketAddr;
+use anyhow::Context;
+use once_cell::sync::Lazy;
 use smol::{
     channel::{Receiver, Sender},
+    lock::{Semaphore, SemaphoreGuard},
     prelude::*,
 };
 use smol_timeout::TimeoutExt;
-use sosistab2::MuxStream;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use std::time::Duration;
 use std::{sync::Arc, time::Instant};
 
-use super::TUNNEL;
+use crate::{
+    china,
+    config::{DnsRoute, DnsUpstream, VpnMode},
+};
+
+use super::{
+    active_tunnel,
+    listen::{Bound, DNS_LISTEN},
+    tunnel::TunnelStream,
+    vpn::ACTIVE_VPN_MODE,
+    CONNECT_CONFIG,
+};
+
+mod cache;
+mod flatten;
+mod upstream;
+pub use cache::dns_cache_persist_loop;
+use cache::{raise_ttls, DNS_CACHE};
+use flatten::flatten_cname;
+use upstream::{upstream_for, upstream_request};
 
 /// Handle DNS requests from localhost
-pub async fn dns_loop(addr: SocketAddr) -> anyhow::Result<()> {
-    let socket = smol::net::UdpSocket::bind(addr).await?;
+pub async fn dns_loop(listen: &'static Bound<std::net::UdpSocket>) -> anyhow::Result<()> {
+    let socket = smol::net::UdpSocket::try_from(listen.take()?)?;
     let mut buf = [0; 2048];
     let pool = Arc::new(DnsPool::new());
     log::debug!("DNS loop started");
     loop {
         let (n, c_addr) = socket.recv_from(&mut buf).await?;
-        let buff = buf[..n].to_vec();
+        let buff = if CONNECT_CONFIG.dns_keep_ecs {
+            buf[..n].to_vec()
+        } else {
+            strip_ecs(&buf[..n])
+        };
         let socket = socket.clone();
         let pool = pool.clone();
         smolscale::spawn(async move {
+            let start = Instant::now();
+            let override_upstream = upstream_for(&buff);
+            let direct = match override_upstream {
+                Some(DnsUpstream::System) => system_usable(),
+                Some(_) => false,
+                None => is_direct_query(&buff),
+            };
+            let upstream = if direct {
+                "system"
+            } else if CONNECT_CONFIG.dns_log && DNS_CACHE.get(&buff).is_some() {
+                "cache"
+            } else if override_upstream.is_some() {
+                "override"
+            } else {
+                "tunnel"
+            };
             let fut = || async {
-                socket
-                    .send_to(&pool.request(&buff).await?, c_addr)
-                    .await
-                    .ok()?;
-                Some(())
+                let response = if direct {
+                    let mut response = system_request(&buff).await?;
+                    apply_min_ttl(&mut response);
+                    response
+                } else if let Some(upstream @ (DnsUpstream::Tcp(_) | DnsUpstream::Https(_))) =
+                    override_upstream
+                {
+                    upstream_request(upstream, &buff).await?
+                } else {
+                    pool.request(&buff).await?
+                };
+                let response = if CONNECT_CONFIG.dns_flatten_cname {
+                    flatten_cname(&buff, response, &pool, direct).await
+                } else {
+                    response
+                };
+                socket.send_to(&response, c_addr).await.ok()?;
+                Some(response)
             };
             for _ in 0u32..5 {
-                if fut().await.is_some() {
+                if let Some(response) = fut().await {
+                    log_query(&buff, upstream, start, Some(&response));
                     return;
                 }
             }
+            log_query(&buff, upstream, start, None);
         })
         .detach();
     }
 }
 
+/// With --dns-min-ttl, raises the TTLs in an upstream response to the configured floor. Malformed responses are left alone.
+fn apply_min_ttl(response: &mut [u8]) {
+    if CONNECT_CONFIG.dns_min_ttl > 0 && raise_ttls(response, CONNECT_CONFIG.dns_min_ttl).is_none()
+    {
+        log::debug!("not raising TTLs of malformed DNS response");
+    }
+}
+
+/// With --dns-log, logs a query answered by the given upstream, along with how long it took and what the answer was.
+fn log_query(query: &[u8], upstream: &str, start: Instant, response: Option<&[u8]>) {
+    if !CONNECT_CONFIG.dns_log {
+        return;
+    }
+    let (name, qtype) = match read_name(query, 12) {
+        Ok((name, offset)) => match query.get(offset..offset + 2) {
+            Some(qtype) => (name, u16::from_be_bytes([qtype[0], qtype[1]])),
+            None => return,
+        },
+        Err(_) => return,
+    };
+    let result = match response {
+        Some(response) => describe_response(response, qtype),
+        None => "failed".into(),
+    };
+    log::info!(
+        "DNS {name} type {qtype} via {upstream} in {:?}: {result}",
+        start.elapsed()
+    );
+}
+
+/// Summarizes a DNS response as its error, its addresses, or its number of answers.
+fn describe_response(response: &[u8], qtype: u16) -> String {
+    let rcode = match response.get(3) {
+        Some(flags) => flags & 0xf,
+        None => return "malformed".into(),
+    };
+    match rcode {
+        0 => {}
+        1 => return "FORMERR".into(),
+        2 => return "SERVFAIL".into(),
+        3 => return "NXDOMAIN".into(),
+        5 => return "REFUSED".into(),
+        x => return format!("RCODE {x}"),
+    }
+    let answers = match parse_answers(response, qtype) {
+        Ok(answers) => answers,
+        Err(_) => return "malformed".into(),
+    };
+    let addrs: Vec<String> = answers
+        .iter()
+        .filter_map(|rdata| match rdata.len() {
+            4 if qtype == QTYPE_A => {
+                Some(Ipv4Addr::from(<[u8; 4]>::try_from(rdata.as_slice()).ok()?).to_string())
+            }
+            16 if qtype == QTYPE_AAAA => {
+                Some(Ipv6Addr::from(<[u8; 16]>::try_from(rdata.as_slice()).ok()?).to_string())
+            }
+            _ => None,
+        })
+        .collect();
+    if addrs.is_empty() {
+        format!("{} answers", answers.len())
+    } else {
+        addrs.join(", ")
+    }
+}
+
+/// The nameserver the system is configured to use, for names that shouldn't be resolved through the tunnel.
+static SYSTEM_NAMESERVER: Lazy<Option<SocketAddr>> = Lazy::new(|| {
+    let resolv_conf = std::fs::read_to_string("/etc/resolv.conf").ok()?;
+    let nameserver = resolv_conf.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        if words.next() != Some("nameserver") {
+            return None;
+        }
+        words.next()?.parse::<std::net::IpAddr>().ok()
+    });
+    if nameserver.is_none() {
+        log::warn!(
+            "no nameserver in /etc/resolv.conf, so every name is resolved through the tunnel"
+        );
+    }
+    Some(SocketAddr::new(nameserver?, 53))
+});
+
+/// Whether a DNS query should be answered by the system nameserver rather than through the tunnel, according to --dns-route. With "split", these are the queries for names whose connections go directly, currently Chinese domains with --exclude-prc.
+fn is_direct_query(query: &[u8]) -> bool {
+    let direct = match CONNECT_CONFIG.dns_route {
+        DnsRoute::Tunnel => false,
+        DnsRoute::Direct => true,
+        DnsRoute::Split => {
+            CONNECT_CONFIG.exclude_prc
+                && match read_name(query, 12) {
+                    Ok((name, _)) => china::is_chinese_host(&name),
+                    Err(_) => false,
+                }
+        }
+    };
+    direct && system_usable()
+}
+
+/// Whether the system nameserver can be used at all.
+fn system_usable() -> bool {
+    // in tun-route mode, all DNS traffic is redirected back to us, so going to the system nameserver would loop
+    *ACTIVE_VPN_MODE.read() != Some(VpnMode::TunRoute) && SYSTEM_NAMESERVER.is_some()
+}
+
+/// Sends a DNS query to the system nameserver, bypassing the tunnel.
+async fn system_request(query: &[u8]) -> Option<Vec<u8>> {
+    let nameserver = (*SYSTEM_NAMESERVER)?;
+    let _permit = upstream_permit().await;
+    let socket = smol::net::UdpSocket::bind(if nameserver.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })
+    .await
+    .ok()?;
+    socket.connect(nameserver).await.ok()?;
+    socket.send(query).await.ok()?;
+    let mut buf = [0; 4096];
+    loop {
+        let n = socket
+            .recv(&mut buf)
+            .timeout(Duration::from_secs(5))
+            .await?
+            .ok()?;
+        if n >= 2 && buf[..2] == query[..2] {
+            return Some(buf[..n].to_vec());
+        }
+    }
+}
+
+/// Sends a DNS query to our own DNS proxy, so that it goes through the same caching and routing as queries from other programs.
+pub async fn local_request(query: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut proxy = *DNS_LISTEN;
+    if proxy.ip().is_unspecified() {
+        proxy.set_ip(Ipv4Addr::LOCALHOST.into());
+    }
+    let socket = smol::net::UdpSocket::bind(if proxy.is_ipv4() {
+        "127.0.0.1:0"
+    } else {
+        "[::1]:0"
+    })
+    .await?;
+    socket.connect(proxy).await?;
+    socket.send(query).await?;
+    let mut buf = [0; 4096];
+    let n = socket
+        .recv(&mut buf)
+        .timeout(Duration::from_secs(10))
+        .await
+        .context("DNS proxy timed out")??;
+    Ok(buf[..n].to_vec())
+}
+
+/// The pool used for resolving names on behalf of other parts of the client.
+static RESOLVE_POOL: Lazy<DnsPool> = Lazy::new(DnsPool::new);
+
+const QTYPE_A: u16 = 1;
+const QTYPE_PTR: u16 = 12;
+const QTYPE_AAAA: u16 = 28;
+
+/// Resolves a hostname to an IPv4 address through the tunnel.
+pub async fn resolve_remote(name: &str) -> anyhow::Result<Ipv4Addr> {
+    let response = RESOLVE_POOL
+        .request(&build_query(name, QTYPE_A)?)
+        .await
+        .context("DNS request through the tunnel failed")?;
+    parse_answers(&response, QTYPE_A)?
+        .into_iter()
+        .find_map(|rdata| Some(Ipv4Addr::from(<[u8; 4]>::try_from(rdata.as_slice()).ok()?)))
+        .context("no A records in DNS response")
+}
+
+/// Resolves an IPv4 address back to a hostname through the tunnel.
+pub async fn resolve_remote_ptr(addr: Ipv4Addr) -> anyhow::Result<String> {
+    let [a, b, c, d] = addr.octets();
+    let response = RESOLVE_POOL
+        .request(&build_query(
+            &format!("{d}.{c}.{b}.{a}.in-addr.arpa"),
+            QTYPE_PTR,
+        )?)
+        .await
+        .context("DNS request through the tunnel failed")?;
+    let (ptr_offset, _) = parse_answer_offsets(&response, QTYPE_PTR)?
+        .into_iter()
+        .next()
+        .context("no PTR records in DNS response")?;
+    Ok(read_name(&response, ptr_offset)?.0)
+}
+
+/// Checks whether the exit can reach the IPv6 Internet, by sending a DNS query through the tunnel to an IPv6-only resolver address.
+pub async fn probe_remote_ipv6() -> bool {
+    let probe = async {
+        let mut conn = active_tunnel()
+            .connect_stream("[2606:4700:4700::1111]:53")
+            .await?;
+        let query = build_query("cloudflare.com", QTYPE_A)?;
+        conn.write_all(&(query.len() as u16).to_be_bytes()).await?;
+        conn.write_all(&query).await?;
+        conn.flush().await?;
+        let mut n_buf = [0; 2];
+        conn.read_exact(&mut n_buf).await?;
+        anyhow::Ok(())
+    };
+    match probe.timeout(Duration::from_secs(10)).await {
+        Some(Ok(())) => true,
+        Some(Err(err)) => {
+            log::debug!("IPv6 probe failed: {:?}", err);
+            false
+        }
+        None => {
+            log::debug!("IPv6 probe timed out");
+            false
+        }
+    }
+}
+
+/// Builds a recursive DNS query for the given name and type.
+fn build_query(name: &str, qtype: u16) -> anyhow::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&fastrand::u16(..).to_be_bytes());
+    // flags: recursion desired; one question, nothing else
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            anyhow::bail!("invalid DNS name {:?}", name)
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes());
+    Ok(query)
+}
+
+const RTYPE_OPT: u16 = 41;
+const EDNS_CLIENT_SUBNET: u16 = 8;
+
+/// Removes any EDNS Client Subnet option from a query, so that the client's subnet isn't leaked to authoritative servers. Malformed queries are passed through unchanged.
+fn strip_ecs(query: &[u8]) -> Vec<u8> {
+    let strip = || -> anyhow::Result<Vec<u8>> {
+        let read_u16 = |msg: &[u8], offset: usize| -> anyhow::Result<u16> {
+            let bytes = msg
+                .get(offset..offset + 2)
+                .context("truncated DNS message")?;
+            Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+        };
+        let qdcount = read_u16(query, 4)?;
+        let rrcount = read_u16(query, 6)? as usize
+            + read_u16(query, 8)? as usize
+            + read_u16(query, 10)? as usize;
+        let mut offset = 12;
+        for _ in 0..qdcount {
+            offset = read_name(query, offset)?.1 + 4;
+        }
+        let mut out = query[..offset].to_vec();
+        for _ in 0..rrcount {
+            let rr_start = offset;
+            offset = read_name(query, offset)?.1;
+            let rtype = read_u16(query, offset)?;
+            let rdlen = read_u16(query, offset + 8)? as usize;
+            let rdata_start = offset + 10;
+            let rdata = query
+                .get(rdata_start..rdata_start + rdlen)
+                .context("truncated DNS message")?;
+            if rtype != RTYPE_OPT {
+                out.extend_from_slice(&query[rr_start..rdata_start + rdlen]);
+            } else {
+                let mut options = vec![];
+                let mut opt_offset = 0;
+                while opt_offset < rdata.len() {
+                    let code = read_u16(rdata, opt_offset)?;
+                    let len = read_u16(rdata, opt_offset + 2)? as usize;
+                    let option = rdata
+                        .get(opt_offset..opt_offset + 4 + len)
+                        .context("truncated EDNS option")?;
+                    if code != EDNS_CLIENT_SUBNET {
+                        options.extend_from_slice(option);
+                    }
+                    opt_offset += 4 + len;
+                }
+                out.extend_from_slice(&query[rr_start..offset + 8]);
+                out.extend_from_slice(&(options.len() as u16).to_be_bytes());
+                out.extend_from_slice(&options);
+            }
+            offset = rdata_start + rdlen;
+        }
+        Ok(out)
+    };
+    match strip() {
+        Ok(stripped) => stripped,
+        Err(err) => {
+            log::debug!("not stripping ECS from malformed DNS query: {:?}", err);
+            query.to_vec()
+        }
+    }
+}
+
+/// Returns the RDATA of every answer of the given type.
+fn parse_answers(msg: &[u8], qtype: u16) -> anyhow::Result<Vec<Vec<u8>>> {
+    Ok(parse_answer_offsets(msg, qtype)?
+        .into_iter()
+        .map(|(offset, len)| msg[offset..offset + len].to_vec())
+        .collect())
+}
+
+/// Returns the offset and length of the RDATA of every answer of the given type.
+fn parse_answer_offsets(msg: &[u8], qtype: u16) -> anyhow::Result<Vec<(usize, usize)>> {
+    let read_u16 = |offset: usize| -> anyhow::Result<u16> {
+        let bytes = msg
+            .get(offset..offset + 2)
+            .context("truncated DNS message")?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    let rcode = read_u16(2)? & 0xf;
+    if rcode != 0 {
+        anyhow::bail!("DNS server returned error code {rcode}")
+    }
+    let qdcount = read_u16(4)?;
+    let ancount = read_u16(6)?;
+    let mut offset = 12;
+    for _ in 0..qdcount {
+        offset = read_name(msg, offset)?.1 + 4;
+    }
+    let mut toret = vec![];
+    for _ in 0..ancount {
+        offset = read_name(msg, offset)?.1;
+        let rtype = read_u16(offset)?;
+        let rdlen = read_u16(offset + 8)? as usize;
+        offset += 10;
+        if offset + rdlen > msg.len() {
+            anyhow::bail!("truncated DNS message")
+        }
+        if rtype == qtype {
+            toret.push((offset, rdlen));
+        }
+        offset += rdlen;
+    }
+    Ok(toret)
+}
+
+/// Reads a possibly-compressed name at the given offset, returning the name and the offset right after it.
+fn read_name(msg: &[u8], mut offset: usize) -> anyhow::Result<(String, usize)> {
+    let mut labels = vec![];
+    let mut end = None;
+    for _ in 0..128 {
+        let len = *msg.get(offset).context("truncated DNS name")? as usize;
+        if len & 0xc0 == 0xc0 {
+            let lo = *msg.get(offset + 1).context("truncated DNS name")? as usize;
+            end.get_or_insert(offset + 2);
+            offset = ((len & 0x3f) << 8) | lo;
+        } else if len == 0 {
+            return Ok((labels.join("."), end.unwrap_or(offset + 1)));
+        } else {
+            let label = msg
+                .get(offset + 1..offset + 1 + len)
+                .context("truncated DNS name")?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            offset += 1 + len;
+        }
+    }
+    anyhow::bail!("DNS name compression loop")
+}
+
+/// With --dns-max-inflight, limits how many queries are sent upstream at once.
+static UPSTREAM_LIMIT: Lazy<Option<Semaphore>> = Lazy::new(|| {
+    Some(CONNECT_CONFIG.dns_max_inflight)
+        .filter(|&limit| limit > 0)
+        .map(Semaphore::new)
+});
+
+/// Waits until another query may be sent upstream. The query counts as in flight until the guard is dropped.
+async fn upstream_permit() -> Option<SemaphoreGuard<'static>> {
+    Some(UPSTREAM_LIMIT.as_ref()?.acquire().await)
+}
+
 /// A DNS connection pool
 pub struct DnsPool {
-    send_conn: Sender<(MuxStream, Instant)>,
-    recv_conn: Receiver<(MuxStream, Instant)>,
+    send_conn: Sender<(TunnelStream, Instant)>,
+    recv_conn: Receiver<(TunnelStream, Instant)>,
 }
 
 impl DnsPool {
@@ -56,8 +491,21 @@ impl DnsPool {
         }
     }
 
-    /// Do a DNS request.
+    /// Do a DNS request, answering from the cache when possible.
     pub async fn request(&self, buff: &[u8]) -> Option<Vec<u8>> {
+        if let Some(cached) = DNS_CACHE.get(buff) {
+            if DNS_CACHE.should_prefetch(buff) {
+                let query = buff.to_vec();
+                smolscale::spawn(async move { RESOLVE_POOL.fetch(&query).await }).detach();
+            }
+            return Some(cached);
+        }
+        self.fetch(buff).await
+    }
+
+    /// Do a DNS request through the tunnel, caching the response.
+    async fn fetch(&self, buff: &[u8]) -> Option<Vec<u8>> {
+        let _permit = upstream_permit().await;
         let dns_timeout = Duration::from_secs(10);
         let mut conn = {
             let lala = loop {
@@ -71,7 +519,7 @@ impl DnsPool {
             };
             match lala {
                 Some(v) => v,
-                _ => TUNNEL
+                _ => active_tunnel()
                     .connect_stream("1.0.0.1:53")
                     .timeout(dns_timeout)
                     .await?
@@ -95,6 +543,8 @@ impl DnsPool {
             .await?
             .ok()?;
         self.send_conn.try_send((conn, Instant::now())).unwrap();
+        apply_min_ttl(&mut true_buf);
+        DNS_CACHE.insert(buff, &true_buf);
         Some(true_buf)
     }
 }
@@ -0,0 +1,256 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use moka::sync::{Cache, CacheBuilder};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::connect::CONNECT_CONFIG;
+
+use super::read_name;
+
+/// How often the cache is written to the `--dns-cache-persist` file.
+const PERSIST_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Upper bound on how long any response is cached, whatever its TTL says.
+const MAX_TTL: u64 = 86400;
+
+/// Most responses kept in the cache. The least used ones are evicted beyond this.
+const MAX_ENTRIES: u64 = 10000;
+
+/// With --dns-prefetch, a response used when less than this fraction of its TTL remains is refreshed in the background.
+const PREFETCH_FRACTION: f64 = 0.1;
+
+/// The cache of DNS responses, loaded from `--dns-cache-persist` if given.
+pub static DNS_CACHE: Lazy<DnsCache> = Lazy::new(|| {
+    let cache = DnsCache::new();
+    if let Some(path) = CONNECT_CONFIG.dns_cache_persist.as_ref() {
+        match cache.load(path) {
+            Ok(n) => log::debug!("loaded {n} DNS cache entries from {:?}", path),
+            Err(err) => log::warn!("could not load DNS cache from {:?}: {:?}", path, err),
+        }
+    }
+    cache
+});
+
+/// A cache of DNS responses, keyed by their question and kept until their TTL runs out.
+pub struct DnsCache {
+    entries: Cache<String, CacheEntry>,
+    /// Keys of entries being refreshed by --dns-prefetch.
+    prefetching: Mutex<HashSet<String>>,
+    dirty: AtomicBool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CacheEntry {
+    response: Vec<u8>,
+    expires_unix: u64,
+    /// How long the entry was cached for in the first place, or 0 if not known.
+    #[serde(default)]
+    ttl: u64,
+}
+
+impl DnsCache {
+    fn new() -> Self {
+        Self {
+            entries: CacheBuilder::new(MAX_ENTRIES)
+                .time_to_live(Duration::from_secs(MAX_TTL))
+                .build(),
+            prefetching: Default::default(),
+            dirty: Default::default(),
+        }
+    }
+
+    /// Looks up a cached response to the given query, with the ID and TTLs fixed up to match.
+    pub fn get(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let key = cache_key(query)?;
+        let entry = self.entries.get(&key)?;
+        let remaining = entry.expires_unix.saturating_sub(unix_now());
+        if remaining == 0 {
+            self.entries.invalidate(&key);
+            return None;
+        }
+        let mut response = entry.response;
+        response[..2].copy_from_slice(&query[..2]);
+        rewrite_ttls(&mut response, remaining as u32)?;
+        Some(response)
+    }
+
+    /// Caches the response to the given query, if it is a successful one with a nonzero TTL.
+    pub fn insert(&self, query: &[u8], response: &[u8]) {
+        let (key, ttl) = match cache_key(query).zip(min_ttl(response)) {
+            Some(v) => v,
+            None => return,
+        };
+        if ttl == 0 {
+            return;
+        }
+        self.prefetching.lock().remove(&key);
+        let ttl = ttl.min(MAX_TTL);
+        self.entries.insert(
+            key,
+            CacheEntry {
+                response: response.to_vec(),
+                expires_unix: unix_now() + ttl,
+                ttl,
+            },
+        );
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// With --dns-prefetch, checks whether the cached response to this query is close enough to expiring that it should be refreshed in the background. This is true only once for each cached response, so that it is refreshed only once.
+    pub fn should_prefetch(&self, query: &[u8]) -> bool {
+        if !CONNECT_CONFIG.dns_prefetch {
+            return false;
+        }
+        let key = match cache_key(query) {
+            Some(key) => key,
+            None => return false,
+        };
+        let nearly_expired = match self.entries.get(&key) {
+            Some(entry) if entry.ttl > 0 => {
+                let remaining = entry.expires_unix.saturating_sub(unix_now());
+                (remaining as f64) < entry.ttl as f64 * PREFETCH_FRACTION
+            }
+            _ => false,
+        };
+        nearly_expired && self.prefetching.lock().insert(key)
+    }
+
+    /// Loads entries from a file written by [DnsCache::save], discarding those that have expired.
+    fn load(&self, path: &Path) -> anyhow::Result<usize> {
+        if !path.exists() {
+            return Ok(0);
+        }
+        let loaded: HashMap<String, CacheEntry> = serde_json::from_slice(&std::fs::read(path)?)?;
+        let now = unix_now();
+        let mut count = 0;
+        for (key, entry) in loaded {
+            if entry.expires_unix > now && entry.response.len() >= 12 {
+                self.entries.insert(key, entry);
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Writes the entries that haven't expired to the given file.
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let now = unix_now();
+        let entries: HashMap<String, CacheEntry> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.expires_unix > now)
+            .map(|(key, entry)| ((*key).clone(), entry))
+            .collect();
+        let json = serde_json::to_vec(&entries)?;
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, json)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Periodically writes the DNS cache to the `--dns-cache-persist` file, if any. Failed writes are retried at the next interval.
+pub async fn dns_cache_persist_loop() {
+    let path = match CONNECT_CONFIG.dns_cache_persist.as_ref() {
+        Some(path) => path,
+        None => return smol::future::pending().await,
+    };
+    loop {
+        smol::Timer::after(PERSIST_INTERVAL).await;
+        if DNS_CACHE.dirty.swap(false, Ordering::Relaxed) {
+            if let Err(err) = smol::unblock(move || DNS_CACHE.save(path)).await {
+                warn_limited!("could not save DNS cache to {:?}: {:?}", path, err);
+                DNS_CACHE.dirty.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The key of a query is its name, type, and class.
+fn cache_key(query: &[u8]) -> Option<String> {
+    if query.len() < 12 || query[4..6] != [0, 1] {
+        return None;
+    }
+    let (name, offset) = read_name(query, 12).ok()?;
+    let tail = query.get(offset..offset + 4)?;
+    Some(format!(
+        "{}/{}/{}",
+        name.to_ascii_lowercase(),
+        u16::from_be_bytes([tail[0], tail[1]]),
+        u16::from_be_bytes([tail[2], tail[3]])
+    ))
+}
+
+/// Calls the closure with the offset of the TTL field of every record other than EDNS ones, returning None if the message is malformed.
+fn for_each_ttl(msg: &[u8], mut f: impl FnMut(usize)) -> Option<()> {
+    let read_u16 = |offset: usize| {
+        let bytes = msg.get(offset..offset + 2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    let qdcount = read_u16(4)?;
+    let rrcount = read_u16(6)? as usize + read_u16(8)? as usize + read_u16(10)? as usize;
+    let mut offset = 12;
+    for _ in 0..qdcount {
+        offset = read_name(msg, offset).ok()?.1 + 4;
+    }
+    for _ in 0..rrcount {
+        offset = read_name(msg, offset).ok()?.1;
+        let rtype = read_u16(offset)?;
+        msg.get(offset + 4..offset + 8)?;
+        if rtype != 41 {
+            f(offset + 4);
+        }
+        offset += 10 + read_u16(offset + 8)? as usize;
+    }
+    (offset <= msg.len()).then_some(())
+}
+
+/// The smallest TTL in a successful response with answers.
+fn min_ttl(response: &[u8]) -> Option<u64> {
+    let flags = response.get(2..4)?;
+    if flags[1] & 0xf != 0 || flags[0] & 0x02 != 0 || response.get(6..8)? == [0, 0] {
+        return None;
+    }
+    let mut min = None;
+    for_each_ttl(response, |offset| {
+        let ttl = u32::from_be_bytes(response[offset..offset + 4].try_into().unwrap()) as u64;
+        min = Some(min.map_or(ttl, |min: u64| min.min(ttl)));
+    })?;
+    min
+}
+
+/// Raises every TTL in the response below the given number of seconds up to it.
+pub fn raise_ttls(response: &mut [u8], floor: u32) -> Option<()> {
+    let mut offsets = vec![];
+    for_each_ttl(response, |offset| offsets.push(offset))?;
+    for offset in offsets {
+        let ttl = u32::from_be_bytes(response[offset..offset + 4].try_into().unwrap());
+        response[offset..offset + 4].copy_from_slice(&ttl.max(floor).to_be_bytes());
+    }
+    Some(())
+}
+
+/// Caps every TTL in the response at the given number of seconds.
+fn rewrite_ttls(response: &mut [u8], remaining: u32) -> Option<()> {
+    let mut offsets = vec![];
+    for_each_ttl(response, |offset| offsets.push(offset))?;
+    for offset in offsets {
+        let ttl = u32::from_be_bytes(response[offset..offset + 4].try_into().unwrap());
+        response[offset..offset + 4].copy_from_slice(&ttl.min(remaining).to_be_bytes());
+    }
+    Some(())
+}
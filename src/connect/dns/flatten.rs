@@ -0,0 +1,174 @@
+use anyhow::Context;
+
+use super::{build_query, read_name, system_request, DnsPool, QTYPE_A, QTYPE_AAAA};
+
+const RTYPE_CNAME: u16 = 5;
+
+/// Longest CNAME chain that is flattened. Longer ones are passed through as they are.
+const MAX_CNAME_DEPTH: usize = 8;
+
+/// A record in the answer section of a DNS response.
+struct Answer {
+    name: String,
+    rtype: u16,
+    ttl: u32,
+    rdata: Vec<u8>,
+    /// For CNAME records, the decompressed target.
+    target: Option<String>,
+}
+
+/// With --dns-flatten-cname, replaces the CNAME chain in a response to an A or AAAA query with the addresses it ends at, all under the queried name. Targets whose addresses aren't in the response are resolved through the same upstream. Responses that can't be flattened, because of a loop, a chain that is too long, or a malformed message, are returned unchanged.
+pub(super) async fn flatten_cname(
+    query: &[u8],
+    response: Vec<u8>,
+    pool: &DnsPool,
+    direct: bool,
+) -> Vec<u8> {
+    match flatten_cname_inner(query, &response, pool, direct).await {
+        Ok(Some(flattened)) => flattened,
+        Ok(None) => response,
+        Err(err) => {
+            log::debug!("not flattening CNAMEs in DNS response: {:?}", err);
+            response
+        }
+    }
+}
+
+async fn flatten_cname_inner(
+    query: &[u8],
+    response: &[u8],
+    pool: &DnsPool,
+    direct: bool,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let (qname, offset) = read_name(query, 12)?;
+    let qtype = query
+        .get(offset..offset + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .context("truncated DNS query")?;
+    if qtype != QTYPE_A && qtype != QTYPE_AAAA {
+        return Ok(None);
+    }
+    let mut answers = parse_answer_records(response)?;
+    if !answers.iter().any(|answer| answer.rtype == RTYPE_CNAME) {
+        return Ok(None);
+    }
+
+    let mut name = qname;
+    let mut visited = vec![name.to_ascii_lowercase()];
+    let mut ttl = u32::MAX;
+    let mut requeried = false;
+    loop {
+        let cname = answers
+            .iter()
+            .find(|answer| answer.rtype == RTYPE_CNAME && answer.name.eq_ignore_ascii_case(&name));
+        if let Some(cname) = cname {
+            let target = cname.target.clone().context("CNAME without target")?;
+            if visited.contains(&target.to_ascii_lowercase()) {
+                anyhow::bail!("CNAME loop at {target}")
+            }
+            if visited.len() > MAX_CNAME_DEPTH {
+                anyhow::bail!("CNAME chain longer than {MAX_CNAME_DEPTH}")
+            }
+            visited.push(target.to_ascii_lowercase());
+            ttl = ttl.min(cname.ttl);
+            name = target;
+            requeried = false;
+            continue;
+        }
+
+        let addrs: Vec<&Answer> = answers
+            .iter()
+            .filter(|answer| answer.rtype == qtype && answer.name.eq_ignore_ascii_case(&name))
+            .collect();
+        if !addrs.is_empty() {
+            return Ok(Some(build_flattened(response, qtype, ttl, &addrs)?));
+        }
+        // the chain ends at a name with no addresses, even when asked directly
+        if requeried {
+            return Ok(None);
+        }
+        requeried = true;
+        let subquery = build_query(&name, qtype)?;
+        let subresponse = if direct {
+            system_request(&subquery).await
+        } else {
+            pool.request(&subquery).await
+        };
+        answers = parse_answer_records(&subresponse.context("cannot resolve CNAME target")?)?;
+    }
+}
+
+/// Builds a response with the header and question of the original, and the given addresses as answers for the queried name.
+fn build_flattened(
+    response: &[u8],
+    qtype: u16,
+    chain_ttl: u32,
+    addrs: &[&Answer],
+) -> anyhow::Result<Vec<u8>> {
+    let question_end = read_name(response, 12)?.1 + 4;
+    let question = response
+        .get(12..question_end)
+        .context("truncated DNS response")?;
+    let mut out = response[..4].to_vec();
+    out.extend_from_slice(&1u16.to_be_bytes());
+    out.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+    out.extend_from_slice(&[0, 0, 0, 0]);
+    out.extend_from_slice(question);
+    for addr in addrs {
+        // a pointer to the name in the question
+        out.extend_from_slice(&[0xc0, 0x0c]);
+        out.extend_from_slice(&qtype.to_be_bytes());
+        out.extend_from_slice(&1u16.to_be_bytes());
+        out.extend_from_slice(&chain_ttl.min(addr.ttl).to_be_bytes());
+        out.extend_from_slice(&(addr.rdata.len() as u16).to_be_bytes());
+        out.extend_from_slice(&addr.rdata);
+    }
+    Ok(out)
+}
+
+/// Parses the answer section of a successful DNS response.
+fn parse_answer_records(msg: &[u8]) -> anyhow::Result<Vec<Answer>> {
+    let read_u16 = |offset: usize| -> anyhow::Result<u16> {
+        let bytes = msg
+            .get(offset..offset + 2)
+            .context("truncated DNS message")?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    let rcode = read_u16(2)? & 0xf;
+    if rcode != 0 {
+        anyhow::bail!("DNS server returned error code {rcode}")
+    }
+    let qdcount = read_u16(4)?;
+    let ancount = read_u16(6)?;
+    let mut offset = 12;
+    for _ in 0..qdcount {
+        offset = read_name(msg, offset)?.1 + 4;
+    }
+    let mut answers = vec![];
+    for _ in 0..ancount {
+        let (name, rr_offset) = read_name(msg, offset)?;
+        offset = rr_offset;
+        let rtype = read_u16(offset)?;
+        let ttl = (read_u16(offset + 4)? as u32) << 16 | read_u16(offset + 6)? as u32;
+        let rdlen = read_u16(offset + 8)? as usize;
+        offset += 10;
+        let rdata = msg
+            .get(offset..offset + rdlen)
+            .context("truncated DNS message")?
+            .to_vec();
+        let target = if rtype == RTYPE_CNAME {
+            Some(read_name(msg, offset)?.0)
+        } else {
+            None
+        };
+        answers.push(Answer {
+            name,
+            rtype,
+            ttl,
+            rdata,
+            target,
+        });
+        offset += rdlen;
+    }
+    Ok(answers)
+}
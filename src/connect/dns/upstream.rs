@@ -0,0 +1,100 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use http_types::{Method, Request, Url};
+use smol::prelude::*;
+use smol_timeout::TimeoutExt;
+
+use crate::config::DnsUpstream;
+
+use super::{active_tunnel, apply_min_ttl, read_name, upstream_permit, CONNECT_CONFIG, DNS_CACHE};
+
+/// How long an upstream from --dns-upstream-for gets to answer.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The upstream that --dns-upstream-for gives for the type of this query, if any.
+pub(super) fn upstream_for(query: &[u8]) -> Option<&'static DnsUpstream> {
+    let (_, offset) = read_name(query, 12).ok()?;
+    let qtype = query.get(offset..offset + 2)?;
+    let qtype = u16::from_be_bytes([qtype[0], qtype[1]]);
+    CONNECT_CONFIG
+        .dns_upstream_for
+        .iter()
+        .find(|rule| rule.qtype == qtype)
+        .map(|rule| &rule.upstream)
+}
+
+/// Sends a query through the tunnel to an upstream from --dns-upstream-for, answering from the cache when possible.
+pub(super) async fn upstream_request(
+    upstream: &'static DnsUpstream,
+    query: &[u8],
+) -> Option<Vec<u8>> {
+    if let Some(cached) = DNS_CACHE.get(query) {
+        if DNS_CACHE.should_prefetch(query) {
+            let query = query.to_vec();
+            smolscale::spawn(async move { upstream_fetch(upstream, &query).await }).detach();
+        }
+        return Some(cached);
+    }
+    upstream_fetch(upstream, query).await
+}
+
+/// Sends a query through the tunnel to an upstream from --dns-upstream-for, caching the response.
+async fn upstream_fetch(upstream: &DnsUpstream, query: &[u8]) -> Option<Vec<u8>> {
+    let _permit = upstream_permit().await;
+    let result = match upstream {
+        DnsUpstream::Tcp(addr) => tcp_request(addr, query).timeout(UPSTREAM_TIMEOUT).await,
+        DnsUpstream::Https(url) => https_request(url, query).timeout(UPSTREAM_TIMEOUT).await,
+        // answered by the system nameserver in dns_loop instead
+        DnsUpstream::System => return None,
+    };
+    let mut response = match result {
+        Some(Ok(response)) => response,
+        Some(Err(err)) => {
+            log::debug!("DNS upstream {:?} failed: {:?}", upstream, err);
+            return None;
+        }
+        None => {
+            log::debug!("DNS upstream {:?} timed out", upstream);
+            return None;
+        }
+    };
+    apply_min_ttl(&mut response);
+    DNS_CACHE.insert(query, &response);
+    Some(response)
+}
+
+/// Sends a query as plain DNS over TCP.
+async fn tcp_request(addr: &str, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut conn = active_tunnel().connect_stream(addr).await?;
+    conn.write_all(&(query.len() as u16).to_be_bytes()).await?;
+    conn.write_all(query).await?;
+    conn.flush().await?;
+    let mut n_buf = [0; 2];
+    conn.read_exact(&mut n_buf).await?;
+    let mut response = vec![0u8; u16::from_be_bytes(n_buf) as usize];
+    conn.read_exact(&mut response).await?;
+    Ok(response)
+}
+
+/// Sends a query as DNS-over-HTTPS, POSTing it to the given URL.
+async fn https_request(url: &str, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let url = Url::parse(url)?;
+    let host = url.host_str().context("DNS-over-HTTPS URL has no host")?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    let conn = active_tunnel()
+        .connect_stream(&format!("{host}:{port}"))
+        .await?;
+    let conn = async_native_tls::connect(host, conn).await?;
+    let mut req = Request::new(Method::Post, url.clone());
+    req.insert_header("content-type", "application/dns-message");
+    req.insert_header("accept", "application/dns-message");
+    req.set_body(query.to_vec());
+    let mut response = async_h1::connect(conn, req)
+        .await
+        .map_err(|err| err.into_inner())?;
+    if !response.status().is_success() {
+        anyhow::bail!("DNS-over-HTTPS server returned {}", response.status())
+    }
+    response.body_bytes().await.map_err(|err| err.into_inner())
+}
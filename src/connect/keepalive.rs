@@ -0,0 +1,23 @@
+use std::{mem::ManuallyDrop, time::Duration};
+
+use super::CONNECT_CONFIG;
+
+/// Enables TCP keepalive on the given socket if `--tcp-keepalive-secs` was given, so that idle connections aren't silently dropped by NATs along the way. The tunnel's own connections to bridges are out of reach here, since sosistab2 opens them without exposing the socket.
+pub fn apply_tcp_keepalive(stream: &smol::net::TcpStream) {
+    if let Some(secs) = CONNECT_CONFIG.tcp_keepalive_secs {
+        // borrow the socket without taking ownership, so that the file descriptor isn't closed on drop
+        #[cfg(unix)]
+        let socket = {
+            use std::os::unix::prelude::{AsRawFd, FromRawFd};
+            ManuallyDrop::new(unsafe { socket2::Socket::from_raw_fd(stream.as_raw_fd()) })
+        };
+        #[cfg(windows)]
+        let socket = {
+            use std::os::windows::prelude::{AsRawSocket, FromRawSocket};
+            ManuallyDrop::new(unsafe { socket2::Socket::from_raw_socket(stream.as_raw_socket()) })
+        };
+        if let Err(err) = socket.set_keepalive(Some(Duration::from_secs(secs))) {
+            log::warn!("could not set TCP keepalive: {:?}", err);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    net::{SocketAddr, TcpListener, UdpSocket},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::Mutex;
+
+use super::CONNECT_CONFIG;
+
+/// Where the HTTP proxy actually listens. With --unified-proxy, this is the SOCKS5 port.
+pub static HTTP_LISTEN: Lazy<SocketAddr> = Lazy::new(|| match &listeners().http {
+    Some(http) => http.addr(),
+    None => *SOCKS5_LISTEN,
+});
+
+/// Where the SOCKS5 proxy actually listens.
+pub static SOCKS5_LISTEN: Lazy<SocketAddr> = Lazy::new(|| listeners().socks5.addr());
+
+/// Where the DNS proxy actually listens.
+pub static DNS_LISTEN: Lazy<SocketAddr> = Lazy::new(|| listeners().dns.addr());
+
+/// A socket that can be bound ahead of time.
+pub trait Bindable: Sized {
+    fn bind(addr: SocketAddr) -> std::io::Result<Self>;
+    fn local_addr(&self) -> std::io::Result<SocketAddr>;
+}
+
+impl Bindable for TcpListener {
+    fn bind(addr: SocketAddr) -> std::io::Result<Self> {
+        TcpListener::bind(addr)
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+}
+
+impl Bindable for UdpSocket {
+    fn bind(addr: SocketAddr) -> std::io::Result<Self> {
+        UdpSocket::bind(addr)
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+/// A socket that is bound ahead of time, so that its address is known before the server behind it starts, and nothing else can take the port in between.
+pub struct Bound<T> {
+    addr: SocketAddr,
+    socket: Mutex<Option<T>>,
+}
+
+/// A TCP listener bound ahead of time.
+pub type BoundListener = Bound<TcpListener>;
+
+impl<T: Bindable> Bound<T> {
+    fn bind(addr: SocketAddr) -> std::io::Result<Self> {
+        let socket = T::bind(addr)?;
+        Ok(Self {
+            // for port 0, this is the port the OS picked
+            addr: socket.local_addr()?,
+            socket: Mutex::new(Some(socket)),
+        })
+    }
+}
+
+impl<T> Bound<T> {
+    /// The address actually listened on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Takes the socket, for the one server that serves on it.
+    pub fn take(&self) -> anyhow::Result<T> {
+        self.socket
+            .lock()
+            .take()
+            .with_context(|| format!("the socket on {} is already in use", self.addr))
+    }
+}
+
+/// Everything connect mode listens on.
+pub struct Listeners {
+    /// The public SOCKS5 listener, which with --unified-proxy takes HTTP proxy clients too.
+    pub socks5: Arc<BoundListener>,
+    /// The public HTTP proxy listener, unless the HTTP proxy is disabled or shares the SOCKS5 port.
+    pub http: Option<Arc<BoundListener>>,
+    /// The DNS proxy's socket.
+    pub dns: Bound<UdpSocket>,
+    /// The plaintext proxies. With --proxy-tls-cert or --unified-proxy, these are on loopback ports behind [Self::socks5] and [Self::http]; otherwise they are those very listeners.
+    pub inner: InnerListeners,
+}
+
+/// The plaintext proxy listeners.
+pub struct InnerListeners {
+    /// The plaintext SOCKS5 proxy.
+    pub socks5: Arc<BoundListener>,
+    /// The plaintext HTTP proxy, unless it is disabled.
+    pub http: Option<Arc<BoundListener>>,
+    /// With --unified-proxy, the plaintext listener that sends clients on to the SOCKS5 or HTTP proxy.
+    pub unified: Option<Arc<BoundListener>>,
+}
+
+static LISTENERS: OnceCell<Listeners> = OnceCell::new();
+
+/// Binds all the [Listeners], failing if a listening address is taken and --auto-port isn't given.
+pub fn bind_listeners() -> anyhow::Result<()> {
+    let tls = CONNECT_CONFIG.proxy_tls_cert.is_some();
+    let unified = CONNECT_CONFIG.unified_proxy;
+    let socks5 = Arc::new(bind_public("SOCKS5 proxy", CONNECT_CONFIG.socks5_listen)?);
+    let http = if CONNECT_CONFIG.no_http_proxy || unified {
+        None
+    } else {
+        Some(Arc::new(bind_public(
+            "HTTP proxy",
+            CONNECT_CONFIG.http_listen,
+        )?))
+    };
+    let dns = bind_public("DNS proxy", CONNECT_CONFIG.dns_listen)?;
+
+    let loopback = |name: &str| {
+        BoundListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .map(Arc::new)
+            .with_context(|| format!("{name} cannot listen on a loopback port"))
+    };
+    let inner = InnerListeners {
+        socks5: if tls || unified {
+            loopback("SOCKS5 proxy")?
+        } else {
+            socks5.clone()
+        },
+        http: if unified {
+            Some(loopback("HTTP proxy")?)
+        } else if tls {
+            http.as_ref().map(|_| loopback("HTTP proxy")).transpose()?
+        } else {
+            http.clone()
+        },
+        unified: if !unified {
+            None
+        } else if tls {
+            Some(loopback("unified proxy")?)
+        } else {
+            Some(socks5.clone())
+        },
+    };
+    let _ = LISTENERS.set(Listeners {
+        socks5,
+        http,
+        dns,
+        inner,
+    });
+    Ok(())
+}
+
+/// The [Listeners], which [bind_listeners] must have bound.
+pub fn listeners() -> &'static Listeners {
+    LISTENERS
+        .get()
+        .expect("the listeners are bound when connect mode starts")
+}
+
+/// Binds the given public listening address. If it is taken, with `--auto-port`, an OS-assigned port on the same IP is bound instead; without it, the error says which port is taken.
+fn bind_public<T: Bindable>(name: &str, addr: SocketAddr) -> anyhow::Result<Bound<T>> {
+    match Bound::bind(addr) {
+        Ok(bound) => Ok(bound),
+        Err(err) if CONNECT_CONFIG.auto_port => {
+            let mut fallback = addr;
+            fallback.set_port(0);
+            let bound = Bound::bind(fallback).with_context(|| {
+                format!("{name} cannot listen on any port of {}", addr.ip())
+            })?;
+            log::warn!(
+                "{name} cannot listen on {addr} ({err}), listening on {} instead",
+                bound.addr()
+            );
+            Ok(bound)
+        }
+        Err(err) => Err(err).with_context(|| {
+            format!("{name} cannot listen on {addr} (pass --auto-port to pick a free port automatically)")
+        }),
+    }
+}
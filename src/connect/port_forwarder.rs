@@ -1,26 +1,84 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
 
-use super::TUNNEL;
+use super::{
+    active_tunnel, keepalive::apply_tcp_keepalive, relay::relay_proxied, tunnel_for_exit,
+    CONNECT_CONFIG,
+};
+
+/// The wait before the first retry of a failed forward, doubled on every further retry up to [FORWARD_RETRY_MAX].
+const FORWARD_RETRY_BASE: Duration = Duration::from_millis(500);
+
+/// The longest wait between retries of a failed forward.
+const FORWARD_RETRY_MAX: Duration = Duration::from_secs(30);
+
+/// The longest wait between attempts to set up the tunnel to the exit of an "@exit" forward.
+const EXIT_TUNNEL_RETRY_MAX: Duration = Duration::from_secs(60);
 
 /// Forwards ports using a particular description.
 pub async fn port_forwarder(desc: String) {
     let exploded = desc.split(":::").collect::<Vec<_>>();
     let listen_addr: SocketAddr = exploded[0].parse().expect("invalid port forwarding syntax");
+    // an optional "@exit" suffix sends this forward through a specific exit
+    let (remote_addr, exit) = match exploded[1].rsplit_once('@') {
+        Some((remote_addr, exit)) => (remote_addr.to_owned(), Some(exit.to_owned())),
+        None => (exploded[1].to_owned(), None),
+    };
+    let exit_tunnel = match exit {
+        Some(exit) => {
+            let mut delay = FORWARD_RETRY_BASE;
+            loop {
+                match tunnel_for_exit(&exit).await {
+                    Ok(tunnel) => break Some(tunnel),
+                    Err(err) => {
+                        warn_limited!(
+                            "cannot create tunnel to {exit} for forwarding {listen_addr}, retrying in {:?}: {:?}",
+                            delay,
+                            err
+                        );
+                        smol::Timer::after(delay).await;
+                        delay = (delay * 2).min(EXIT_TUNNEL_RETRY_MAX);
+                    }
+                }
+            }
+        }
+        None => None,
+    };
     let listener = smol::net::TcpListener::bind(listen_addr)
         .await
         .expect("could not listen for port forwarding");
     loop {
-        let (conn, _) = listener.accept().await.unwrap();
+        let conn = match listener.accept().await {
+            Ok((conn, _)) => conn,
+            Err(err) => {
+                warn_limited!("cannot accept port forwarding connection: {:?}", err);
+                smol::Timer::after(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        apply_tcp_keepalive(&conn);
 
-        let remote_addr = exploded[1].to_owned();
+        let remote_addr = remote_addr.clone();
+        let tunnel = exit_tunnel.unwrap_or_else(active_tunnel);
         smolscale::spawn(async move {
-            let remote = TUNNEL.connect_stream(&remote_addr).await.ok()?;
-            smol::future::race(
-                smol::io::copy(remote.clone(), conn.clone()),
-                smol::io::copy(conn, remote),
-            )
-            .await
-            .ok()
+            let mut attempt = 0;
+            let remote = loop {
+                match tunnel.connect_stream(&remote_addr).await {
+                    Ok(remote) => break remote,
+                    Err(err) if attempt < CONNECT_CONFIG.forward_retry => {
+                        log::debug!("forwarding to {remote_addr} failed, retrying: {:?}", err);
+                        let delay = FORWARD_RETRY_BASE
+                            .saturating_mul(2u32.saturating_pow(attempt))
+                            .min(FORWARD_RETRY_MAX);
+                        smol::Timer::after(delay).await;
+                        attempt += 1;
+                    }
+                    Err(err) => {
+                        warn_limited!("forwarding to {remote_addr} failed: {:?}", err);
+                        return None;
+                    }
+                }
+            };
+            relay_proxied(conn, remote, |_| (), |_| ()).await.ok()
         })
         .detach();
     }
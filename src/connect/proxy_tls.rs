@@ -0,0 +1,92 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::Context;
+use async_native_tls::TlsAcceptor;
+use futures_util::TryFutureExt;
+use once_cell::sync::OnceCell;
+use smol::prelude::*;
+use smol_timeout::TimeoutExt;
+
+use super::{
+    keepalive::apply_tcp_keepalive,
+    listen::BoundListener,
+    relay::copy_with_stats,
+    socket_owner::{client_uid_tunneled, forward_decision},
+    CONNECT_CONFIG,
+};
+
+/// How long a client gets to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The acceptor for proxy clients, once [load_proxy_tls] has loaded it.
+static PROXY_TLS: OnceCell<TlsAcceptor> = OnceCell::new();
+
+/// Loads the certificate and key for proxy clients, if --proxy-tls-cert and --proxy-tls-key are given.
+pub fn load_proxy_tls() -> anyhow::Result<()> {
+    let (cert, key) = match (
+        &CONNECT_CONFIG.proxy_tls_cert,
+        &CONNECT_CONFIG.proxy_tls_key,
+    ) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return Ok(()),
+    };
+    let cert = std::fs::read(cert).context("cannot read --proxy-tls-cert")?;
+    let key = std::fs::read(key).context("cannot read --proxy-tls-key")?;
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key).context(
+        "--proxy-tls-cert and --proxy-tls-key are not a valid PEM certificate chain and PKCS#8 key",
+    )?;
+    let acceptor =
+        native_tls::TlsAcceptor::new(identity).context("cannot set up TLS for the proxies")?;
+    let _ = PROXY_TLS.set(acceptor.into());
+    Ok(())
+}
+
+/// Accepts TLS connections on `listen`, forwarding the decrypted streams to the plaintext proxy at `inner`. Clients whose IP fails `allowed` are dropped before the handshake.
+pub async fn tls_front(
+    name: &'static str,
+    listen: &'static BoundListener,
+    inner: SocketAddr,
+    allowed: fn(IpAddr) -> bool,
+) -> anyhow::Result<()> {
+    let acceptor = match PROXY_TLS.get() {
+        Some(acceptor) => acceptor,
+        None => return smol::future::pending().await,
+    };
+    let listener = smol::net::TcpListener::try_from(listen.take()?)
+        .with_context(|| format!("cannot set up {name} over TLS"))?;
+    log::debug!("{name} over TLS started on {}", listen.addr());
+    loop {
+        let (client, client_addr) = listener
+            .accept()
+            .await
+            .with_context(|| format!("cannot accept {name} over TLS"))?;
+        if !allowed(client_addr.ip()) {
+            log::debug!("rejecting {name} client {client_addr}");
+            continue;
+        }
+        apply_tcp_keepalive(&client);
+        let tunneled = client_uid_tunneled(&client);
+        smolscale::spawn(
+            async move {
+                let client = acceptor
+                    .accept(client)
+                    .timeout(HANDSHAKE_TIMEOUT)
+                    .await
+                    .context("TLS handshake timed out")??;
+                let upstream = smol::net::TcpStream::connect(inner).await?;
+                upstream.set_nodelay(true)?;
+                forward_decision(upstream.local_addr()?, tunneled);
+                let (client_read, client_write) = smol::io::split(client);
+                copy_with_stats(client_read, upstream.clone(), |_| ())
+                    .race(copy_with_stats(upstream, client_write, |_| ()))
+                    .await?;
+                anyhow::Ok(())
+            }
+            .map_err(move |err| log::debug!("{name} over TLS from {client_addr} died: {:?}", err)),
+        )
+        .detach();
+    }
+}
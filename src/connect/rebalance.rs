@@ -0,0 +1,113 @@
+use std::time::{Duration, Instant};
+
+use geph4_protocol::binder::protocol::Level;
+use smol_timeout::TimeoutExt;
+
+use super::{tunnel_for_exit, CONNECT_CONFIG, CONNINFO_STORE, REBALANCED_TUNNEL, TUNNEL};
+
+/// How often the load of the current exit is checked.
+const REBALANCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long the current exit must stay overloaded before moving away from it.
+const REBALANCE_SUSTAIN: Duration = Duration::from_secs(300);
+
+/// How long a new exit gets to connect before the move is abandoned.
+const REBALANCE_CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// With --exit-rebalance, moves traffic to a less-loaded exit in the same country once the current exit has been loaded beyond --exit-rebalance-load for a while. Connections already open stay on the old exit until they close.
+pub async fn rebalance_loop() {
+    if !CONNECT_CONFIG.exit_rebalance {
+        return smol::future::pending().await;
+    }
+    if CONNECT_CONFIG.override_connect.is_some()
+        || CONNECT_CONFIG.endpoints_file.is_some()
+        || !CONNECT_CONFIG.exit_chain.is_empty()
+        || !CONNECT_CONFIG.exit_balance.is_empty()
+        || CONNECT_CONFIG.exit_pubkey.is_some()
+    {
+        log::warn!("--exit-rebalance has no effect with a fixed endpoint, an exit chain, --exit-balance, or --exit-pubkey");
+        return smol::future::pending().await;
+    }
+    let mut current_exit = match CONNECT_CONFIG.exit_server.clone() {
+        Some(exit) => exit,
+        None => return smol::future::pending().await,
+    };
+    let mut overloaded_since: Option<Instant> = None;
+    loop {
+        smol::Timer::after(REBALANCE_INTERVAL).await;
+        let summary = CONNINFO_STORE.summary();
+        let current = match summary.exits.iter().find(|e| e.hostname == current_exit) {
+            Some(current) => current,
+            None => continue,
+        };
+        if current.load <= CONNECT_CONFIG.exit_rebalance_load {
+            overloaded_since = None;
+            continue;
+        }
+        let since = *overloaded_since.get_or_insert_with(Instant::now);
+        if since.elapsed() < REBALANCE_SUSTAIN {
+            continue;
+        }
+
+        let level = CONNINFO_STORE
+            .user_info()
+            .subscription
+            .map(|sub| sub.level)
+            .unwrap_or(Level::Free);
+        let better = summary
+            .exits
+            .iter()
+            .filter(|e| {
+                e.hostname != current.hostname
+                    && e.country_code == current.country_code
+                    && e.allowed_levels.contains(&level)
+                    && e.load <= CONNECT_CONFIG.exit_rebalance_load
+            })
+            .min_by(|a, b| a.load.total_cmp(&b.load));
+        let better = match better {
+            Some(better) => better,
+            None => {
+                log::debug!(
+                    "exit {} has load {:.2}, but no less-loaded exit is available",
+                    current.hostname,
+                    current.load
+                );
+                continue;
+            }
+        };
+
+        log::info!(
+            "exit {} has had load {:.2} for {:?}; moving to {} with load {:.2}",
+            current.hostname,
+            current.load,
+            since.elapsed(),
+            better.hostname,
+            better.load
+        );
+        let tunnel = match tunnel_for_exit(&better.hostname).await {
+            Ok(tunnel) => tunnel,
+            Err(err) => {
+                log::warn!("cannot move to exit {}: {:?}", better.hostname, err);
+                continue;
+            }
+        };
+        let connected = async {
+            while !tunnel.status().connected() {
+                smol::Timer::after(Duration::from_secs(1)).await;
+            }
+        }
+        .timeout(REBALANCE_CONNECT_TIMEOUT)
+        .await;
+        if connected.is_none() {
+            log::warn!(
+                "exit {} did not connect in time, staying on {}",
+                better.hostname,
+                current.hostname
+            );
+            continue;
+        }
+        *REBALANCED_TUNNEL.write() = (!std::ptr::eq(tunnel, &*TUNNEL)).then_some(tunnel);
+        current_exit = better.hostname.to_string();
+        overloaded_since = None;
+    }
+}
@@ -0,0 +1,223 @@
+use std::time::Duration;
+
+use smol::prelude::*;
+
+use super::CONNECT_CONFIG;
+
+/// How long a relayed connection can be idle before it's torn down.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// Copies from a reader to a writer through a buffer of `--relay-buffer-size` bytes, calling a callback after every write. This is [geph4_aioutils::copy_with_stats], but with a configurable buffer size and flushing as described at [copy_with_buffer]; the `relay_throughput` test benchmarks the two against each other.
+pub async fn copy_with_stats(
+    reader: impl AsyncRead + Unpin,
+    writer: impl AsyncWrite + Unpin,
+    on_write: impl FnMut(usize),
+) -> std::io::Result<()> {
+    copy_with_buffer(
+        reader,
+        writer,
+        CONNECT_CONFIG.relay_buffer_size.max(1),
+        on_write,
+    )
+    .await
+}
+
+/// Copies from a reader to a writer through a buffer of the given size, calling a callback after every write.
+///
+/// Every chunk read is written out in full, however many short writes that takes. The writer is flushed only when the reader has nothing more ready, so that buffering writers such as TLS streams don't sit on data while the reader waits for more, without paying for a flush per chunk during bulk transfers.
+async fn copy_with_buffer(
+    mut reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+    buffer_size: usize,
+    mut on_write: impl FnMut(usize),
+) -> std::io::Result<()> {
+    let mut buffer = vec![0u8; buffer_size];
+    let mut timeout = smol::Timer::after(IDLE_TIMEOUT);
+    loop {
+        let n = match smol::future::poll_once(reader.read(&mut buffer)).await {
+            Some(res) => res?,
+            None => {
+                async {
+                    writer.flush().await?;
+                    reader.read(&mut buffer).await
+                }
+                .or(async {
+                    (&mut timeout).await;
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "copy_with_stats timeout",
+                    ))
+                })
+                .await?
+            }
+        };
+        if n == 0 {
+            return writer.flush().await;
+        }
+        timeout.set_after(IDLE_TIMEOUT);
+        writer
+            .write_all(&buffer[..n])
+            .or(async {
+                (&mut timeout).await;
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "copy_with_stats timeout",
+                ))
+            })
+            .await?;
+        on_write(n);
+    }
+}
+
+/// Relays a proxied connection between the client and the remote end in both directions, until either side is done, calling the callbacks after every write to the remote end and to the client respectively.
+///
+/// With --first-byte-timeout, fails if the remote end sends nothing back within that long of first being sent something.
+pub async fn relay_proxied(
+    client: impl AsyncRead + AsyncWrite + Clone + Unpin,
+    remote: impl AsyncRead + AsyncWrite + Clone + Unpin,
+    mut on_send: impl FnMut(usize),
+    mut on_recv: impl FnMut(usize),
+) -> std::io::Result<()> {
+    let first_byte_timeout = match CONNECT_CONFIG.first_byte_timeout {
+        Some(secs) => Duration::from_secs_f64(secs),
+        None => {
+            return copy_with_stats(remote.clone(), client.clone(), on_recv)
+                .race(copy_with_stats(client, remote, on_send))
+                .await
+        }
+    };
+    let (sent_send, sent_recv) = smol::channel::bounded(1);
+    let upload = copy_with_stats(client.clone(), remote.clone(), move |n| {
+        let _ = sent_send.try_send(());
+        on_send(n)
+    });
+    let download = async move {
+        let mut remote = remote;
+        let mut client = client;
+        let mut first = vec![0u8; CONNECT_CONFIG.relay_buffer_size.max(1)];
+        let n = remote
+            .read(&mut first)
+            .or(async {
+                let _ = sent_recv.recv().await;
+                smol::Timer::after(first_byte_timeout).await;
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "no response within --first-byte-timeout",
+                ))
+            })
+            .await?;
+        if n == 0 {
+            return Ok(());
+        }
+        client.write_all(&first[..n]).await?;
+        client.flush().await?;
+        on_recv(n);
+        copy_with_stats(remote, client, on_recv).await
+    };
+    upload.race(download).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use super::*;
+
+    /// A writer that accepts at most a few bytes per poll, and returns `Pending` on every other poll.
+    struct TrickleWriter {
+        written: Vec<u8>,
+        polls: usize,
+    }
+
+    impl AsyncWrite for TrickleWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.polls += 1;
+            if self.polls.is_multiple_of(2) {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let n = buf.len().min(3);
+            self.written.extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn short_and_pending_writes_lose_nothing() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let mut writer = TrickleWriter {
+            written: vec![],
+            polls: 0,
+        };
+        let mut reported = 0;
+        smol::block_on(copy_with_buffer(&data[..], &mut writer, 1024, |n| {
+            reported += n
+        }))
+        .unwrap();
+        assert_eq!(writer.written, data);
+        assert_eq!(reported, data.len());
+    }
+
+    /// Relays `total` bytes over loopback TCP with the given copy function, returning the throughput in MiB/s.
+    fn loopback_throughput<F: Future<Output = std::io::Result<()>>>(
+        total: usize,
+        copy: impl FnOnce(smol::net::TcpStream) -> F,
+    ) -> f64 {
+        smol::block_on(async {
+            let listener = smol::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let sender = smol::spawn(async move {
+                let mut conn = smol::net::TcpStream::connect(addr).await.unwrap();
+                let chunk = vec![0x5au8; 1 << 20];
+                for _ in 0..total / chunk.len() {
+                    conn.write_all(&chunk).await.unwrap();
+                }
+            });
+            let (conn, _) = listener.accept().await.unwrap();
+            let start = std::time::Instant::now();
+            copy(conn).await.unwrap();
+            sender.await;
+            total as f64 / (1 << 20) as f64 / start.elapsed().as_secs_f64()
+        })
+    }
+
+    /// Compares our copy loop against [geph4_aioutils::copy_with_stats], which has a fixed 32 KiB buffer. Run with `cargo test --release -- --ignored --nocapture relay_throughput`.
+    #[test]
+    #[ignore]
+    fn relay_throughput() {
+        const TOTAL: usize = 2 << 30;
+        let baseline = loopback_throughput(TOTAL, |conn| {
+            geph4_aioutils::copy_with_stats(conn, smol::io::sink(), |_| ())
+        });
+        eprintln!("geph4_aioutils, 32 KiB: {baseline:.0} MiB/s");
+        for buffer_size in [32 << 10, 64 << 10, 256 << 10] {
+            let ours = loopback_throughput(TOTAL, |conn| {
+                copy_with_buffer(conn, smol::io::sink(), buffer_size, |_| ())
+            });
+            eprintln!(
+                "copy_with_buffer, {} KiB: {ours:.0} MiB/s",
+                buffer_size >> 10
+            );
+            assert!(
+                ours > baseline * 0.9,
+                "{} KiB buffer is more than 10% slower than geph4_aioutils",
+                buffer_size >> 10
+            );
+        }
+    }
+}
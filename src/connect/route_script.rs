@@ -0,0 +1,67 @@
+use std::{path::Path, time::Duration};
+
+use moka::sync::{Cache, CacheBuilder};
+use once_cell::sync::Lazy;
+use smol_timeout::TimeoutExt;
+
+/// How long the routing script gets to make up its mind before the connection is proxied anyway.
+const ROUTE_SCRIPT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Cached decisions of the routing script, keyed by "host:port". `true` means direct.
+static ROUTE_CACHE: Lazy<Cache<String, bool>> = Lazy::new(|| {
+    CacheBuilder::new(10000)
+        .time_to_live(Duration::from_secs(600))
+        .build()
+});
+
+/// Asks the given routing script whether a connection to the given "host:port" should go direct rather than through the tunnel.
+///
+/// The script is run with the host and port as its two arguments, and must print either "direct" or "proxy". If it fails, times out, or prints anything else, the connection is proxied.
+pub async fn route_script_direct(script: &Path, addr: &str) -> bool {
+    if let Some(direct) = ROUTE_CACHE.get(addr) {
+        return direct;
+    }
+    let (host, port) = match addr.rsplit_once(':') {
+        Some(split) => split,
+        None => return false,
+    };
+    let output = smol::process::Command::new(script)
+        .arg(host)
+        .arg(port)
+        .kill_on_drop(true)
+        .output()
+        .timeout(ROUTE_SCRIPT_TIMEOUT)
+        .await;
+    let direct = match output {
+        Some(Ok(output)) if output.status.success() => {
+            match String::from_utf8_lossy(&output.stdout).trim() {
+                "direct" => true,
+                "proxy" => false,
+                other => {
+                    log::warn!("routing script said {:?} for {addr}, proxying", other);
+                    return false;
+                }
+            }
+        }
+        Some(Ok(output)) => {
+            log::warn!(
+                "routing script failed for {addr} with {}, proxying",
+                output.status
+            );
+            return false;
+        }
+        Some(Err(err)) => {
+            log::warn!(
+                "could not run routing script for {addr}, proxying: {:?}",
+                err
+            );
+            return false;
+        }
+        None => {
+            log::warn!("routing script timed out for {addr}, proxying");
+            return false;
+        }
+    };
+    ROUTE_CACHE.insert(addr.to_string(), direct);
+    direct
+}
@@ -0,0 +1,82 @@
+use std::{fmt, net::Ipv4Addr, path::Path};
+
+use psl::Psl;
+
+use crate::china;
+
+use super::route_script::route_script_direct;
+
+/// How proxied connections are routed.
+pub struct RoutingRules<'a> {
+    /// Whether connections to China go direct.
+    pub exclude_prc: bool,
+    /// Whether connections to local addresses go through the tunnel too.
+    pub proxy_local: bool,
+    /// A script deciding how connections are routed, taking precedence over `exclude_prc`.
+    pub route_script: Option<&'a Path>,
+}
+
+/// How, and why, a proxied connection was routed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RouteReason {
+    Tunnel,
+    DirectPrivate,
+    DirectPrc,
+    DirectRouteScript,
+    DirectUid,
+}
+
+impl RouteReason {
+    /// Whether the connection goes directly rather than through the tunnel.
+    pub fn is_direct(&self) -> bool {
+        *self != Self::Tunnel
+    }
+}
+
+impl fmt::Display for RouteReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Tunnel => "tunnel",
+            Self::DirectPrivate => "direct (local address)",
+            Self::DirectPrc => "direct (in China, with --exclude-prc)",
+            Self::DirectRouteScript => "direct (routing script)",
+            Self::DirectUid => "direct (user not in --tunnel-uid)",
+        })
+    }
+}
+
+/// Decides how a connection to the given "host:port" is routed.
+pub async fn classify(addr: &str, rules: &RoutingRules<'_>) -> RouteReason {
+    let host = addr.rsplit_once(':').map(|(host, _)| host).unwrap_or(addr);
+    let v4addr: Option<Ipv4Addr> = host.parse().ok();
+
+    // local destinations go direct, unless --proxy-local is given
+    let is_private = if rules.proxy_local {
+        false
+    } else if let Some(v4addr) = v4addr {
+        v4addr.is_private() || v4addr.is_loopback() || v4addr.is_link_local()
+    } else {
+        !psl::List
+            .suffix(host.as_bytes())
+            .map(|suf| suf.typ().is_some())
+            .unwrap_or_default()
+    };
+    if is_private {
+        return RouteReason::DirectPrivate;
+    }
+
+    // a routing script, if given, overrides --exclude-prc
+    if let Some(script) = rules.route_script {
+        return match route_script_direct(script, addr).await {
+            true => RouteReason::DirectRouteScript,
+            false => RouteReason::Tunnel,
+        };
+    }
+    if rules.exclude_prc
+        && (china::is_chinese_host(host) || v4addr.map(china::is_chinese_ip).unwrap_or(false))
+    {
+        RouteReason::DirectPrc
+    } else {
+        RouteReason::Tunnel
+    }
+}
@@ -0,0 +1,96 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use smol::net::TcpStream;
+
+use super::CONNECT_CONFIG;
+
+/// Under --tunnel-uid, the decisions made for clients that the HTTP proxy, the unified proxy, or the TLS front pass on to an inner proxy over loopback, by the address the inner proxy sees them connect from. Looking up the owner of such a connection would only find ourselves.
+static FORWARDED: Lazy<Mutex<HashMap<SocketAddr, bool>>> = Lazy::new(Default::default);
+
+/// Records whether the client behind a connection to one of our inner proxies, made from `from`, should go through the tunnel, so that the inner proxy uses that rather than looking up who owns the connection.
+pub fn forward_decision(from: SocketAddr, tunneled: bool) {
+    if !CONNECT_CONFIG.tunnel_uid.is_empty() {
+        FORWARDED.lock().insert(unmap(from), tunneled);
+    }
+}
+
+/// Whether a proxy client's traffic should go through the tunnel under --tunnel-uid. Clients owned by other local users go direct; clients on other machines are unaffected.
+pub fn client_uid_tunneled(client: &TcpStream) -> bool {
+    match (client.local_addr(), client.peer_addr()) {
+        (Ok(local), Ok(peer)) => client_tunneled(local, peer),
+        _ => true,
+    }
+}
+
+/// Like [client_uid_tunneled], for a client that connected from `peer` to our `local` address.
+pub fn client_tunneled(local: SocketAddr, peer: SocketAddr) -> bool {
+    let uids = &CONNECT_CONFIG.tunnel_uid;
+    if uids.is_empty() {
+        return true;
+    }
+    if let Some(tunneled) = FORWARDED.lock().remove(&unmap(peer)) {
+        return tunneled;
+    }
+    match tcp_client_uid(local, peer) {
+        Some(uid) => uids.contains(&uid),
+        None => true,
+    }
+}
+
+/// Finds the user owning the client end of a TCP connection made to `local` from `peer` on this machine, by looking for the socket in /proc/net/tcp{,6}. SO_PEERCRED only works on Unix sockets, so this is the only way to get at it for TCP.
+#[cfg(target_os = "linux")]
+fn tcp_client_uid(local: SocketAddr, peer: SocketAddr) -> Option<u32> {
+    let (local, peer) = (unmap(local), unmap(peer));
+    ["/proc/net/tcp", "/proc/net/tcp6"]
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .find_map(|table| {
+            table.lines().skip(1).find_map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                // the client's socket has the client's address as its local address
+                let sock_local = unmap(parse_proc_addr(fields.get(1)?)?);
+                let sock_remote = unmap(parse_proc_addr(fields.get(2)?)?);
+                if sock_local == peer && sock_remote == local {
+                    fields.get(7)?.parse().ok()
+                } else {
+                    None
+                }
+            })
+        })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn tcp_client_uid(_local: SocketAddr, _peer: SocketAddr) -> Option<u32> {
+    None
+}
+
+/// Parses an address like "0100007F:2382" from /proc/net/tcp{,6}, whose IP is printed as native-endian 32-bit words.
+#[cfg(target_os = "linux")]
+fn parse_proc_addr(field: &str) -> Option<SocketAddr> {
+    let (ip, port) = field.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let mut octets = Vec::with_capacity(16);
+    for word in 0..ip.len() / 8 {
+        let word = u32::from_str_radix(ip.get(word * 8..word * 8 + 8)?, 16).ok()?;
+        octets.extend_from_slice(&word.to_ne_bytes());
+    }
+    let ip: std::net::IpAddr = match octets.len() {
+        4 => <[u8; 4]>::try_from(octets).ok()?.into(),
+        16 => <[u8; 16]>::try_from(octets).ok()?.into(),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Turns IPv4-mapped IPv6 addresses, as seen by dual-stack listeners, back into IPv4 ones.
+fn unmap(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(v4.into(), v6.port()),
+            None => addr,
+        },
+        _ => addr,
+    }
+}
@@ -1,65 +1,138 @@
 use std::{sync::atomic::Ordering, time::Duration};
 
 use anyhow::Context;
-use futures_util::TryFutureExt;
-use psl::Psl;
+use futures_util::{Future, TryFutureExt};
+use smol::io::{AsyncReadExt, Cursor};
 use smol_timeout::TimeoutExt;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
 
-use crate::{
-    china,
-    connect::{
-        stats::{STATS_RECV_BYTES, STATS_SEND_BYTES},
-        tunnel::activity::notify_activity,
-        TUNNEL,
-    },
+use crate::connect::{
+    balanced_tunnel, dedicated_tunnel,
+    dns::{resolve_remote, resolve_remote_ptr},
+    keepalive::apply_tcp_keepalive,
+    listen::BoundListener,
+    relay::relay_proxied,
+    routing::{classify, RouteReason, RoutingRules},
+    socket_owner::client_uid_tunneled,
+    stats::{ROUTING_COUNTERS, STATS_RECV_BYTES, STATS_SEND_BYTES},
+    stream_pool::pooled_stream,
+    tunnel::activity::notify_activity,
+    CONNECT_CONFIG,
 };
 
+/// Tor's RESOLVE extension command, which asks the proxy to resolve a hostname.
+const TOR_RESOLVE: u8 = 0xF0;
+/// Tor's RESOLVE_PTR extension command, which asks the proxy for the hostname of an IPv4 address.
+const TOR_RESOLVE_PTR: u8 = 0xF1;
+
+/// Answers a Tor-style RESOLVE or RESOLVE_PTR request, doing the lookup through the tunnel.
+async fn handle_tor_resolve(
+    s5client: smol::net::TcpStream,
+    command: u8,
+    request: socksv5::v5::SocksV5Request,
+) -> anyhow::Result<()> {
+    use socksv5::v5::*;
+    let resolved = match (command, request.host) {
+        (TOR_RESOLVE, SocksV5Host::Domain(dom)) => {
+            let dom = String::from_utf8_lossy(&dom).into_owned();
+            match dom.parse::<Ipv4Addr>() {
+                Ok(addr) => Ok(addr),
+                Err(_) => resolve_remote(&dom).await,
+            }
+            .map(|addr| SocksV5Host::Ipv4(addr.octets()))
+        }
+        (TOR_RESOLVE, SocksV5Host::Ipv4(v4)) => Ok(SocksV5Host::Ipv4(v4)),
+        (TOR_RESOLVE_PTR, SocksV5Host::Ipv4(v4)) => resolve_remote_ptr(Ipv4Addr::from(v4))
+            .await
+            .map(|name| SocksV5Host::Domain(name.into_bytes())),
+        _ => {
+            write_request_status(
+                s5client,
+                SocksV5RequestStatus::AddrtypeNotSupported,
+                SocksV5Host::Ipv4([0; 4]),
+                0,
+            )
+            .await?;
+            anyhow::bail!("unsupported address type for Tor resolve")
+        }
+    };
+    match resolved {
+        Ok(host) => {
+            log::debug!("Tor-style resolve succeeded: {:?}", host);
+            write_request_status(s5client, SocksV5RequestStatus::Success, host, 0).await?;
+            Ok(())
+        }
+        Err(err) => {
+            write_request_status(
+                s5client,
+                SocksV5RequestStatus::HostUnreachable,
+                SocksV5Host::Ipv4([0; 4]),
+                0,
+            )
+            .await?;
+            Err(err)
+        }
+    }
+}
+
 /// Handles a socks5 client from localhost
 async fn handle_socks5(s5client: smol::net::TcpStream, exclude_prc: bool) -> anyhow::Result<()> {
     s5client.set_nodelay(true)?;
+    apply_tcp_keepalive(&s5client);
     use socksv5::v5::*;
-    let _handshake = read_handshake(s5client.clone()).await?;
+    let handshake = read_handshake(s5client.clone()).await?;
+    // we only speak no-auth. per RFC 1928, answer 0xFF when the client doesn't offer it, so that it can fall back or give up cleanly
+    if !handshake
+        .methods
+        .iter()
+        .any(|method| matches!(method, SocksV5AuthMethod::Noauth))
+    {
+        write_auth_method(s5client.clone(), SocksV5AuthMethod::NoAcceptableMethod).await?;
+        anyhow::bail!(
+            "client offered no supported SOCKS5 auth method: {:?}",
+            handshake.methods
+        );
+    }
     write_auth_method(s5client.clone(), SocksV5AuthMethod::Noauth).await?;
-    let request = read_request(s5client.clone()).await?;
+    // peek at the command first, since the SOCKS5 library rejects Tor's extended commands
+    let mut header = [0u8; 2];
+    s5client.clone().read_exact(&mut header).await?;
+    let command = header[1];
+    if command == TOR_RESOLVE || command == TOR_RESOLVE_PTR {
+        header[1] = SocksV5Command::Connect.to_u8();
+    }
+    let request = read_request(Cursor::new(header).chain(s5client.clone())).await?;
+    if command == TOR_RESOLVE || command == TOR_RESOLVE_PTR {
+        return handle_tor_resolve(s5client, command, request).await;
+    }
     let port = request.port;
-    let v4addr: Option<Ipv4Addr>;
     let addr: String = match &request.host {
         SocksV5Host::Domain(dom) => {
-            v4addr = String::from_utf8_lossy(dom).parse().ok();
             format!("{}:{}", String::from_utf8_lossy(dom), request.port)
         }
-        SocksV5Host::Ipv4(v4) => {
-            let v4addr_inner = Ipv4Addr::new(v4[0], v4[1], v4[2], v4[3]);
-            SocketAddr::V4(SocketAddrV4::new(
-                {
-                    v4addr = Some(v4addr_inner);
-                    v4addr.unwrap()
-                },
-                request.port,
-            ))
-            .to_string()
-        }
+        SocksV5Host::Ipv4(v4) => SocketAddr::V4(SocketAddrV4::new(
+            Ipv4Addr::new(v4[0], v4[1], v4[2], v4[3]),
+            request.port,
+        ))
+        .to_string(),
         _ => anyhow::bail!("not supported"),
     };
 
-    let is_private = if let Some(v4addr) = v4addr {
-        v4addr.is_private() || v4addr.is_loopback()
+    let rules = RoutingRules {
+        exclude_prc,
+        proxy_local: CONNECT_CONFIG.proxy_local,
+        route_script: CONNECT_CONFIG.route_script.as_deref(),
+    };
+    let reason = if client_uid_tunneled(&s5client) {
+        classify(&addr, &rules).await
     } else {
-        !psl::List
-            .suffix(addr.split(':').next().unwrap().as_bytes())
-            .map(|suf| suf.typ().is_some())
-            .unwrap_or_default()
+        RouteReason::DirectUid
     };
-
-    // true if the connection should not go through geph
-    let must_direct = is_private
-        || (exclude_prc
-            && (china::is_chinese_host(addr.split(':').next().unwrap())
-                || v4addr.map(china::is_chinese_ip).unwrap_or(false)));
-    if must_direct {
+    ROUTING_COUNTERS.record(reason);
+    if reason.is_direct() {
         log::debug!("bypassing {}", addr);
-        let conn = smol::net::TcpStream::connect(&addr).await?;
+        let conn = dial_or_reply(&s5client, smol::net::TcpStream::connect(&addr)).await?;
+        apply_tcp_keepalive(&conn);
         write_request_status(
             s5client.clone(),
             SocksV5RequestStatus::Success,
@@ -67,17 +140,27 @@ async fn handle_socks5(s5client: smol::net::TcpStream, exclude_prc: bool) -> any
             port,
         )
         .await?;
-        smol::future::race(
-            geph4_aioutils::copy_with_stats(conn.clone(), s5client.clone(), |_| ()),
-            geph4_aioutils::copy_with_stats(s5client.clone(), conn.clone(), |_| ()),
-        )
-        .await?;
+        relay_proxied(s5client, conn, |_| (), |_| ()).await?;
     } else {
-        let conn = TUNNEL
-            .connect_stream(&addr)
-            .timeout(Duration::from_secs(120))
-            .await
-            .context("open connection timeout")??;
+        let tunnel = match balanced_tunnel().await {
+            Ok(tunnel) => tunnel,
+            Err(err) => {
+                write_request_status(
+                    s5client,
+                    SocksV5RequestStatus::ServerFailure,
+                    SocksV5Host::Ipv4([0; 4]),
+                    0,
+                )
+                .await?;
+                return Err(err);
+            }
+        };
+        // kept alive until the connection is done
+        let dedicated = dedicated_tunnel(&addr, tunnel);
+        let conn = match &dedicated {
+            Some(dedicated) => dial_or_reply(&s5client, dedicated.connect_stream(&addr)).await?,
+            None => dial_or_reply(&s5client, pooled_stream(tunnel, &addr)).await?,
+        };
         write_request_status(
             s5client.clone(),
             SocksV5RequestStatus::Success,
@@ -85,31 +168,72 @@ async fn handle_socks5(s5client: smol::net::TcpStream, exclude_prc: bool) -> any
             port,
         )
         .await?;
-        smol::future::race(
-            geph4_aioutils::copy_with_stats(conn.clone(), s5client.clone(), |n| {
-                STATS_RECV_BYTES.fetch_add(n as u64, Ordering::Relaxed);
-                notify_activity();
-            }),
-            geph4_aioutils::copy_with_stats(s5client, conn, |n| {
+        relay_proxied(
+            s5client,
+            conn,
+            |n| {
                 STATS_SEND_BYTES.fetch_add(n as u64, Ordering::Relaxed);
                 notify_activity();
-            }),
+            },
+            |n| {
+                STATS_RECV_BYTES.fetch_add(n as u64, Ordering::Relaxed);
+                notify_activity();
+            },
         )
         .await?;
     }
     Ok(())
 }
 
-pub async fn socks5_loop(socks5_listen: SocketAddr, exclude_prc: bool) -> anyhow::Result<()> {
-    let socks5_listener = smol::net::TcpListener::bind(socks5_listen)
+/// Waits for a connection to the destination for up to --dial-timeout-secs, replying "host unreachable" to the client if it fails or times out.
+async fn dial_or_reply<T, E: Into<anyhow::Error>>(
+    s5client: &smol::net::TcpStream,
+    dial: impl Future<Output = Result<T, E>>,
+) -> anyhow::Result<T> {
+    use socksv5::v5::*;
+    let result = match dial
+        .timeout(Duration::from_secs(CONNECT_CONFIG.dial_timeout_secs))
         .await
-        .context("cannot bind socks5")?;
+    {
+        Some(result) => result.map_err(|e| e.into()),
+        None => Err(anyhow::anyhow!("open connection timeout")),
+    };
+    if result.is_err() {
+        write_request_status(
+            s5client.clone(),
+            SocksV5RequestStatus::HostUnreachable,
+            SocksV5Host::Ipv4([0; 4]),
+            0,
+        )
+        .await?;
+    }
+    result
+}
+
+/// Whether the given client may use the SOCKS5 proxy. Loopback clients, including our own HTTP proxy, are always allowed.
+pub(super) fn socks5_allowed(client: IpAddr) -> bool {
+    let allow_from = &CONNECT_CONFIG.socks5_allow_from;
+    allow_from.is_empty()
+        || client.is_loopback()
+        || allow_from.iter().any(|net| net.contains(&client))
+}
+
+pub async fn socks5_loop(
+    socks5_listen: &'static BoundListener,
+    exclude_prc: bool,
+) -> anyhow::Result<()> {
+    let socks5_listener = smol::net::TcpListener::try_from(socks5_listen.take()?)
+        .context("cannot set up socks5 listener")?;
     log::debug!("socks5 started");
     loop {
-        let (s5client, _) = socks5_listener
+        let (s5client, client_addr) = socks5_listener
             .accept()
             .await
             .context("cannot accept socks5")?;
+        if !socks5_allowed(client_addr.ip()) {
+            log::debug!("rejecting socks5 client {client_addr} not in --socks5-allow-from");
+            continue;
+        }
 
         smolscale::spawn(
             async move { handle_socks5(s5client, exclude_prc).await }
@@ -1,31 +1,47 @@
+mod csv;
 mod gatherer;
+mod prometheus;
+mod statsd;
 
 use std::{
     convert::Infallible,
+    net::SocketAddr,
     sync::atomic::{AtomicU64, Ordering},
     thread::JoinHandle,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use async_trait::async_trait;
 use itertools::Itertools;
 use smol_str::SmolStr;
 
-use crate::debugpack::DEBUGPACK;
+use crate::cors::{preflight_response, CorsExt};
+use crate::debugpack::{export_debugpak_bytes, DEBUGPACK, START_TIME};
 
 use self::gatherer::StatsGatherer;
+pub use csv::stats_csv_loop;
 pub use gatherer::StatItem;
 use nanorpc::nanorpc_derive;
 use nanorpc::RpcService;
 use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+pub use prometheus::LATENCY_HISTOGRAM;
 use serde::{Deserialize, Serialize};
+pub use statsd::statsd_loop;
 
-use super::{CONNECT_CONFIG, TUNNEL};
+use super::{
+    active_tunnel, captive_portal::captive_portal_detected, listen, routing::RouteReason,
+    set_paused, tunnel::ReconnectEvent, CONNECT_CONFIG, CONNINFO_STORE,
+};
+
+/// Where the stats API actually listens, once it has started.
+static STATS_LISTEN: RwLock<Option<SocketAddr>> = RwLock::new(None);
 
 /// The main stats-serving thread.
 pub static STATS_THREAD: Lazy<JoinHandle<Infallible>> = Lazy::new(|| {
     std::thread::spawn(|| loop {
         let server = tiny_http::Server::http(CONNECT_CONFIG.stats_listen).unwrap();
+        *STATS_LISTEN.write() = server.server_addr().to_ip();
         for mut request in server.incoming_requests() {
             smolscale::spawn(async move {
                 if let Ok(key) = std::env::var("GEPH_RPC_KEY") {
@@ -33,12 +49,122 @@ pub static STATS_THREAD: Lazy<JoinHandle<Infallible>> = Lazy::new(|| {
                         anyhow::bail!("missing rpc key")
                     }
                 }
+                let cors_origin = CONNECT_CONFIG.stats_cors.as_deref();
+                let is_preflight = request.method() == &tiny_http::Method::Options;
+                if let (Some(origin), true) = (cors_origin, is_preflight) {
+                    request.respond(preflight_response(origin))?;
+                    return anyhow::Ok(());
+                }
+                if request.url().starts_with("/routing-stats") {
+                    request.respond_cors(
+                        cors_origin,
+                        tiny_http::Response::from_data(serde_json::to_vec(
+                            &ROUTING_COUNTERS.snapshot(),
+                        )?),
+                    )?;
+                    return anyhow::Ok(());
+                }
+                if request.url().starts_with("/uptime") {
+                    request.respond_cors(
+                        cors_origin,
+                        tiny_http::Response::from_data(serde_json::to_vec(
+                            &DummyImpl.uptime().await,
+                        )?),
+                    )?;
+                    return anyhow::Ok(());
+                }
+                if request.url().starts_with("/listeners") {
+                    request.respond_cors(
+                        cors_origin,
+                        tiny_http::Response::from_data(serde_json::to_vec(&listeners())?),
+                    )?;
+                    return anyhow::Ok(());
+                }
+                if request.url().starts_with("/captive-portal") {
+                    request.respond_cors(
+                        cors_origin,
+                        tiny_http::Response::from_data(serde_json::to_vec(
+                            &serde_json::json!({ "detected": captive_portal_detected() }),
+                        )?),
+                    )?;
+                    return anyhow::Ok(());
+                }
+                if request.url().starts_with("/metrics") {
+                    request.respond_cors(
+                        cors_origin,
+                        tiny_http::Response::from_string(prometheus::render_metrics()).with_header(
+                            tiny_http::Header::from_bytes(
+                                &b"Content-Type"[..],
+                                &b"text/plain; version=0.0.4"[..],
+                            )
+                            .unwrap(),
+                        ),
+                    )?;
+                    return anyhow::Ok(());
+                }
+                if request.url().starts_with("/connect") || request.url().starts_with("/disconnect")
+                {
+                    if request.method() != &tiny_http::Method::Post {
+                        request.respond_cors(cors_origin, tiny_http::Response::empty(405))?;
+                        return anyhow::Ok(());
+                    }
+                    let paused = request.url().starts_with("/disconnect");
+                    set_paused(paused).await;
+                    request.respond_cors(cors_origin, tiny_http::Response::empty(200))?;
+                    return anyhow::Ok(());
+                }
+                if request.url().starts_with("/refresh") {
+                    if request.method() != &tiny_http::Method::Post {
+                        request.respond_cors(cors_origin, tiny_http::Response::empty(405))?;
+                        return anyhow::Ok(());
+                    }
+                    let (code, result) = refresh_now().await;
+                    request.respond_cors(
+                        cors_origin,
+                        tiny_http::Response::from_data(serde_json::to_vec(&result)?)
+                            .with_status_code(code),
+                    )?;
+                    return anyhow::Ok(());
+                }
+                if request.url().starts_with("/debugpack") {
+                    let redact = !request.url().contains("raw=1");
+                    let pack = smol::unblock(move || export_debugpak_bytes(redact)).await?;
+                    let response = tiny_http::Response::from_data(pack)
+                        .with_header(
+                            tiny_http::Header::from_bytes(
+                                &b"Content-Type"[..],
+                                &b"application/octet-stream"[..],
+                            )
+                            .unwrap(),
+                        )
+                        .with_header(
+                            tiny_http::Header::from_bytes(
+                                &b"Content-Disposition"[..],
+                                &b"attachment; filename=\"debugpack\""[..],
+                            )
+                            .unwrap(),
+                        );
+                    request.respond_cors(cors_origin, response)?;
+                    return anyhow::Ok(());
+                }
+                if request.url().starts_with("/wait-connected") {
+                    let url = request.url().to_string();
+                    let (code, body) = wait_connected(&url).await;
+                    request.respond_cors(
+                        cors_origin,
+                        tiny_http::Response::from_string(body).with_status_code(code),
+                    )?;
+                    return anyhow::Ok(());
+                }
                 let mut s = String::new();
                 request.as_reader().read_to_string(&mut s)?;
                 let resp = StatsControlService(DummyImpl)
                     .respond_raw(serde_json::from_str(&s)?)
                     .await;
-                request.respond(tiny_http::Response::from_data(serde_json::to_vec(&resp)?))?;
+                request.respond_cors(
+                    cors_origin,
+                    tiny_http::Response::from_data(serde_json::to_vec(&resp)?),
+                )?;
                 anyhow::Ok(())
             })
             .detach()
@@ -46,6 +172,93 @@ pub static STATS_THREAD: Lazy<JoinHandle<Infallible>> = Lazy::new(|| {
     })
 });
 
+/// The addresses the local listeners are actually bound to, as reported by `/listeners`, so that ports picked by the OS can be found out.
+#[derive(Serialize)]
+struct Listeners {
+    socks5: SocketAddr,
+    /// Absent with --no-http-proxy.
+    http: Option<SocketAddr>,
+    dns: SocketAddr,
+    stats: Option<SocketAddr>,
+}
+
+fn listeners() -> Listeners {
+    Listeners {
+        socks5: *listen::SOCKS5_LISTEN,
+        http: (!CONNECT_CONFIG.no_http_proxy).then(|| *listen::HTTP_LISTEN),
+        dns: *listen::DNS_LISTEN,
+        stats: *STATS_LISTEN.read(),
+    }
+}
+
+/// The result of a refresh triggered through `/refresh`.
+#[derive(Serialize)]
+struct RefreshResult {
+    ok: bool,
+    error: Option<String>,
+}
+
+/// Refreshes the conninfo store right away, without waiting for the periodic refresh, returning the status code and result for `/refresh`.
+async fn refresh_now() -> (u16, RefreshResult) {
+    if CONNECT_CONFIG.endpoints_file.is_some() {
+        return (
+            409,
+            RefreshResult {
+                ok: false,
+                error: Some(
+                    "everything comes from --endpoints-file, so there is nothing to refresh".into(),
+                ),
+            },
+        );
+    }
+    match CONNINFO_STORE.refresh().await {
+        Ok(()) => (
+            200,
+            RefreshResult {
+                ok: true,
+                error: None,
+            },
+        ),
+        Err(err) => {
+            log::warn!("refresh requested through the stats API failed: {:?}", err);
+            (
+                502,
+                RefreshResult {
+                    ok: false,
+                    error: Some(format!("{:?}", err)),
+                },
+            )
+        }
+    }
+}
+
+/// The longest a `/wait-connected` request may block.
+const MAX_WAIT_CONNECTED: Duration = Duration::from_secs(600);
+
+/// Answers `GET /wait-connected?timeout=<secs>`, blocking until the tunnel is connected (200) or the timeout, 30 seconds by default, runs out (504).
+async fn wait_connected(url: &str) -> (u16, &'static str) {
+    let timeout = url
+        .split_once('?')
+        .into_iter()
+        .flat_map(|(_, query)| query.split('&'))
+        .find_map(|pair| pair.strip_prefix("timeout="))
+        .and_then(|secs| secs.parse::<f64>().ok())
+        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+        .map(Duration::from_secs_f64)
+        .unwrap_or(Duration::from_secs(30))
+        .min(MAX_WAIT_CONNECTED);
+    let deadline = Instant::now() + timeout;
+    loop {
+        if active_tunnel().status().connected() {
+            return (200, "connected\n");
+        }
+        if Instant::now() >= deadline {
+            return (504, "timed out waiting for the tunnel to connect\n");
+        }
+        smol::Timer::after(Duration::from_millis(100)).await;
+    }
+}
+
 /// Basic tunnel statistics.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BasicStats {
@@ -53,8 +266,86 @@ pub struct BasicStats {
     pub total_recv_bytes: f32,
 
     pub last_ping: f32, // latency
+    /// Exponentially weighted moving average of the latency, in milliseconds.
+    pub smoothed_ping: f32,
     pub protocol: SmolStr,
+    /// The sosistab variant in use, such as "sosistab2-udp" or "sosistab2-tcp".
+    pub protocol_variant: SmolStr,
+    /// The revision of the pipe protocol in use, such as "1", or empty if unknown.
+    pub protocol_version: SmolStr,
     pub address: SmolStr,
+    /// A summary of how good the connection is, from the smoothed latency and recent reconnects.
+    pub quality: ConnectionQuality,
+    /// Streams currently multiplexed over the tunnel.
+    pub active_streams: u64,
+    /// Streams ever opened over the tunnel.
+    pub total_streams: u64,
+}
+
+/// A rough rating of the connection, for simple indicators in front-ends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionQuality {
+    Good,
+    Fair,
+    Poor,
+}
+
+/// Reconnects within this window count against the connection quality.
+const QUALITY_RECONNECT_WINDOW: Duration = Duration::from_secs(600);
+/// The highest smoothed latency, in milliseconds, rated good and fair respectively.
+const QUALITY_GOOD_PING_MS: f32 = 200.0;
+const QUALITY_FAIR_PING_MS: f32 = 500.0;
+/// The most recent reconnects allowed for a fair rating. A good rating allows none.
+const QUALITY_FAIR_RECONNECTS: usize = 2;
+
+/// Rates the connection from its smoothed latency and the number of recent reconnects.
+fn connection_quality(smoothed_ping_ms: f32) -> ConnectionQuality {
+    let window_start = SystemTime::now()
+        .checked_sub(QUALITY_RECONNECT_WINDOW)
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default()
+        .as_secs();
+    let recent_reconnects = active_tunnel()
+        .history()
+        .reconnects
+        .iter()
+        .filter(|event| event.unix_timestamp >= window_start)
+        .count();
+    if smoothed_ping_ms <= QUALITY_GOOD_PING_MS && recent_reconnects == 0 {
+        ConnectionQuality::Good
+    } else if smoothed_ping_ms <= QUALITY_FAIR_PING_MS
+        && recent_reconnects <= QUALITY_FAIR_RECONNECTS
+    {
+        ConnectionQuality::Fair
+    } else {
+        ConnectionQuality::Poor
+    }
+}
+
+/// Splits a pipe protocol name, like "obfsudp-1", into a sosistab variant and a revision.
+fn protocol_variant(protocol: &str) -> (SmolStr, SmolStr) {
+    let (name, version) = match protocol.rsplit_once('-') {
+        Some((name, version)) if version.chars().all(|c| c.is_ascii_digit()) => (name, version),
+        _ => (protocol, ""),
+    };
+    let variant = match name {
+        "obfsudp" => "sosistab2-udp",
+        "obfstls" => "sosistab2-tcp",
+        other => other,
+    };
+    (variant.into(), version.into())
+}
+
+/// Connection stability statistics.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UptimeStats {
+    /// How long the current session has been up, or zero if currently disconnected.
+    pub session_uptime_secs: f64,
+    /// How long the daemon has been running.
+    pub total_uptime_secs: f64,
+    /// Recent reconnects, oldest first.
+    pub reconnects: Vec<ReconnectEvent>,
 }
 
 #[derive(Copy, Clone)]
@@ -67,7 +358,7 @@ impl StatsControlProtocol for DummyImpl {}
 pub trait StatsControlProtocol {
     /// Obtains whether or not the daemon is connected.
     async fn is_connected(&self) -> bool {
-        TUNNEL.status().connected()
+        active_tunnel().status().connected()
     }
 
     /// Obtains statistics.
@@ -75,10 +366,23 @@ pub trait StatsControlProtocol {
         loop {
             let stats = STATS_GATHERER.all_items().last().cloned();
             if let Some(stats) = stats {
+                let (protocol_variant, protocol_version) = protocol_variant(&stats.protocol);
+                let (active_streams, total_streams) = active_tunnel().stream_counts();
+                let smoothed_ping = STATS_GATHERER
+                    .smoothed_ping()
+                    .unwrap_or(stats.ping)
+                    .as_secs_f32()
+                    * 1000.0;
                 return BasicStats {
                     address: stats.endpoint,
                     protocol: stats.protocol,
+                    protocol_variant,
+                    protocol_version,
                     last_ping: stats.ping.as_secs_f32() * 1000.0,
+                    smoothed_ping,
+                    quality: connection_quality(smoothed_ping),
+                    active_streams,
+                    total_streams,
                     total_recv_bytes: STATS_RECV_BYTES.load(Ordering::Relaxed) as f32,
                     total_sent_bytes: STATS_SEND_BYTES.load(Ordering::Relaxed) as f32,
                 };
@@ -87,6 +391,25 @@ pub trait StatsControlProtocol {
         }
     }
 
+    /// Obtains uptime and reconnect history.
+    async fn uptime(&self) -> UptimeStats {
+        let history = active_tunnel().history();
+        UptimeStats {
+            session_uptime_secs: history
+                .session_start
+                .and_then(|start| start.elapsed().ok())
+                .unwrap_or_default()
+                .as_secs_f64(),
+            total_uptime_secs: START_TIME.elapsed().as_secs_f64(),
+            reconnects: history.reconnects.into_iter().collect(),
+        }
+    }
+
+    /// Obtains how many proxied connections went through the tunnel and directly, by reason.
+    async fn routing_stats(&self) -> RoutingStats {
+        ROUTING_COUNTERS.snapshot()
+    }
+
     /// Get all logs after the given Unix timestamp.
     async fn get_logs(&self, timestamp: u64) -> Vec<(u64, String)> {
         let logs = match DEBUGPACK
@@ -181,3 +504,55 @@ pub static STATS_GATHERER: Lazy<StatsGatherer> = Lazy::new(Default::default);
 pub static STATS_SEND_BYTES: Lazy<AtomicU64> = Lazy::new(|| AtomicU64::new(0));
 
 pub static STATS_RECV_BYTES: Lazy<AtomicU64> = Lazy::new(|| AtomicU64::new(0));
+
+/// Counts of how proxied connections were routed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RoutingStats {
+    /// Connections sent through the tunnel.
+    pub tunneled: u64,
+    /// Connections sent directly because they go to a local address.
+    pub direct_private: u64,
+    /// Connections sent directly because they go to China, with --exclude-prc.
+    pub direct_prc: u64,
+    /// Connections sent directly because the --route-script said so.
+    pub direct_route_script: u64,
+    /// Connections sent directly because their client's user was not given in --tunnel-uid.
+    #[serde(default)]
+    pub direct_uid: u64,
+}
+
+/// Live counters behind [RoutingStats].
+#[derive(Default)]
+pub struct RoutingCounters {
+    tunneled: AtomicU64,
+    direct_private: AtomicU64,
+    direct_prc: AtomicU64,
+    direct_route_script: AtomicU64,
+    direct_uid: AtomicU64,
+}
+
+impl RoutingCounters {
+    /// Counts one connection routed for the given reason.
+    pub fn record(&self, reason: RouteReason) {
+        let counter = match reason {
+            RouteReason::Tunnel => &self.tunneled,
+            RouteReason::DirectPrivate => &self.direct_private,
+            RouteReason::DirectPrc => &self.direct_prc,
+            RouteReason::DirectRouteScript => &self.direct_route_script,
+            RouteReason::DirectUid => &self.direct_uid,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> RoutingStats {
+        RoutingStats {
+            tunneled: self.tunneled.load(Ordering::Relaxed),
+            direct_private: self.direct_private.load(Ordering::Relaxed),
+            direct_prc: self.direct_prc.load(Ordering::Relaxed),
+            direct_route_script: self.direct_route_script.load(Ordering::Relaxed),
+            direct_uid: self.direct_uid.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub static ROUTING_COUNTERS: Lazy<RoutingCounters> = Lazy::new(Default::default);
@@ -0,0 +1,62 @@
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use smol::prelude::*;
+
+use crate::connect::{active_tunnel, tunnel::EndpointSource, CONNECT_CONFIG};
+
+use super::{STATS_GATHERER, STATS_RECV_BYTES, STATS_SEND_BYTES};
+
+const CSV_HEADER: &str = "unix_timestamp,sent_bytes,recv_bytes,ping_ms,protocol,exit\n";
+
+/// Appends a row of stats to the CSV file given by `--stats-csv`, if any, every stats interval.
+pub async fn stats_csv_loop() -> anyhow::Result<()> {
+    let path = match CONNECT_CONFIG.stats_csv.as_ref() {
+        Some(path) => path,
+        None => return smol::future::pending().await,
+    };
+    let mut file = smol::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    if file.metadata().await?.len() == 0 {
+        file.write_all(CSV_HEADER.as_bytes()).await?;
+    }
+    loop {
+        smol::Timer::after(Duration::from_millis(CONNECT_CONFIG.stats_interval_ms)).await;
+        let tunnel = active_tunnel();
+        let last = STATS_GATHERER.all_items().last().cloned();
+        let exit = match tunnel.get_endpoint() {
+            EndpointSource::Binder(params) => params.exit_server.unwrap_or_default(),
+            EndpointSource::Independent { endpoint } => endpoint,
+            EndpointSource::File(_) => String::new(),
+        };
+        let row = format!(
+            "{},{},{},{},{},{}\n",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            STATS_SEND_BYTES.load(Ordering::Relaxed),
+            STATS_RECV_BYTES.load(Ordering::Relaxed),
+            last.as_ref()
+                .map(|item| item.ping.as_millis().to_string())
+                .unwrap_or_default(),
+            last.as_ref()
+                .map(|item| item.protocol.to_string())
+                .unwrap_or_default(),
+            exit,
+        );
+        if let Err(err) = async {
+            file.write_all(row.as_bytes()).await?;
+            file.flush().await
+        }
+        .await
+        {
+            log::debug!("could not write to --stats-csv: {:?}", err);
+        }
+    }
+}
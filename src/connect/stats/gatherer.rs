@@ -3,7 +3,9 @@ use smol_str::SmolStr;
 
 use std::time::{Duration, SystemTime};
 
-use crate::debugpack::DEBUGPACK;
+use crate::{connect::CONNECT_CONFIG, debugpack::DEBUGPACK};
+
+use super::LATENCY_HISTOGRAM;
 
 #[derive(Clone, Debug)]
 pub struct StatItem {
@@ -18,6 +20,7 @@ pub struct StatItem {
 #[derive(Default)]
 pub struct StatsGatherer {
     buffer: RwLock<im::Vector<StatItem>>,
+    smoothed_ping: RwLock<Option<Duration>>,
 }
 
 impl StatsGatherer {
@@ -26,6 +29,15 @@ impl StatsGatherer {
         DEBUGPACK.add_timeseries("send_mb", item.send_bytes as f64 / 1_000_000.0);
         DEBUGPACK.add_timeseries("recv_mb", item.recv_bytes as f64 / 1_000_000.0);
         DEBUGPACK.add_timeseries("latency_ms", item.ping.as_secs_f64() * 1000.0);
+        LATENCY_HISTOGRAM.observe(item.ping);
+        {
+            let mut smoothed = self.smoothed_ping.write();
+            let alpha = CONNECT_CONFIG.latency_smoothing;
+            *smoothed = Some(match *smoothed {
+                Some(prev) => prev.mul_f64(1.0 - alpha) + item.ping.mul_f64(alpha),
+                None => item.ping,
+            });
+        }
         let mut buffer = self.buffer.write();
         buffer.push_back(item);
         if buffer.len() > 10000 {
@@ -33,6 +45,11 @@ impl StatsGatherer {
         }
     }
 
+    /// Obtains the exponentially weighted moving average of the latency, if any latency has been measured.
+    pub fn smoothed_ping(&self) -> Option<Duration> {
+        *self.smoothed_ping.read()
+    }
+
     /// Obtains all the stats items.
     pub fn all_items(&self) -> im::Vector<StatItem> {
         self.buffer.read().clone()
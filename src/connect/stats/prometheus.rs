@@ -0,0 +1,96 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+
+use crate::connect::active_tunnel;
+
+use super::{STATS_GATHERER, STATS_RECV_BYTES, STATS_SEND_BYTES};
+
+/// Upper bounds, in seconds, of the buckets of the latency histogram.
+const LATENCY_BUCKETS: [f64; 10] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Histogram of every latency sample taken since startup.
+pub static LATENCY_HISTOGRAM: Lazy<Histogram> = Lazy::new(Default::default);
+
+/// A Prometheus-style histogram over [LATENCY_BUCKETS].
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    /// Records a sample.
+    pub fn observe(&self, sample: Duration) {
+        let secs = sample.as_secs_f64();
+        if let Some(idx) = LATENCY_BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(sample.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Writes the histogram in the Prometheus text format, with cumulative buckets.
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{name}_sum {sum}");
+        let _ = writeln!(out, "{name}_count {count}");
+    }
+}
+
+/// Renders the stats served at `/metrics`, in the Prometheus text format.
+pub fn render_metrics() -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: f64| {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        let _ = writeln!(out, "{name} {value}");
+    };
+    metric(
+        "geph_sent_bytes_total",
+        "counter",
+        "Bytes sent through the tunnel.",
+        STATS_SEND_BYTES.load(Ordering::Relaxed) as f64,
+    );
+    metric(
+        "geph_recv_bytes_total",
+        "counter",
+        "Bytes received through the tunnel.",
+        STATS_RECV_BYTES.load(Ordering::Relaxed) as f64,
+    );
+    metric(
+        "geph_connected",
+        "gauge",
+        "Whether the tunnel is connected.",
+        active_tunnel().status().connected() as u8 as f64,
+    );
+    if let Some(smoothed) = STATS_GATHERER.smoothed_ping() {
+        metric(
+            "geph_smoothed_latency_seconds",
+            "gauge",
+            "Smoothed round-trip latency to the exit.",
+            smoothed.as_secs_f64(),
+        );
+    }
+    LATENCY_HISTOGRAM.render(
+        &mut out,
+        "geph_latency_seconds",
+        "Round-trip latency samples to the exit.",
+    );
+    out
+}
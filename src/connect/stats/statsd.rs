@@ -0,0 +1,47 @@
+use std::{sync::atomic::Ordering, time::Duration};
+
+use crate::connect::{active_tunnel, CONNECT_CONFIG};
+
+use super::{STATS_GATHERER, STATS_RECV_BYTES, STATS_SEND_BYTES};
+
+/// Every stats interval, sends the stats to the StatsD server given by `--statsd`, if any, as UDP packets.
+pub async fn statsd_loop() -> anyhow::Result<()> {
+    let statsd = match CONNECT_CONFIG.statsd.as_ref() {
+        Some(statsd) => statsd,
+        None => return smol::future::pending().await,
+    };
+    let socket = smol::net::UdpSocket::bind("0.0.0.0:0").await?;
+    let mut last_sent = STATS_SEND_BYTES.load(Ordering::Relaxed);
+    let mut last_recv = STATS_RECV_BYTES.load(Ordering::Relaxed);
+    loop {
+        smol::Timer::after(Duration::from_millis(CONNECT_CONFIG.stats_interval_ms)).await;
+        let sent = STATS_SEND_BYTES.load(Ordering::Relaxed);
+        let recv = STATS_RECV_BYTES.load(Ordering::Relaxed);
+        let tunnel = active_tunnel();
+        let mut lines = vec![
+            format!("geph.sent_bytes:{}|c", sent - last_sent),
+            format!("geph.recv_bytes:{}|c", recv - last_recv),
+            format!("geph.connected:{}|g", tunnel.status().connected() as u8),
+        ];
+        last_sent = sent;
+        last_recv = recv;
+        if let Some(start) = tunnel.history().session_start {
+            lines.push(format!(
+                "geph.session_uptime_secs:{}|g",
+                start.elapsed().unwrap_or_default().as_secs()
+            ));
+        }
+        if let Some(item) = STATS_GATHERER.all_items().last() {
+            lines.push(format!("geph.ping_ms:{}|ms", item.ping.as_millis()));
+        }
+        if let Some(smoothed) = STATS_GATHERER.smoothed_ping() {
+            lines.push(format!("geph.smoothed_ping_ms:{}|g", smoothed.as_millis()));
+        }
+        if let Err(err) = socket
+            .send_to(lines.join("\n").as_bytes(), statsd.as_str())
+            .await
+        {
+            log::debug!("could not send to StatsD: {:?}", err);
+        }
+    }
+}
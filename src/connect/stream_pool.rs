@@ -0,0 +1,105 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use super::{
+    tunnel::{ClientTunnel, TunnelStream},
+    CONNECT_CONFIG,
+};
+
+/// How long a warm stream waits to be used before it is closed. This is kept short, since the destination may give up on a connection that sends nothing.
+const WARM_STREAM_TTL: Duration = Duration::from_secs(5);
+
+/// A tunnel, by address, and a destination on it.
+type PoolKey = (usize, String);
+
+/// A warm stream, with when it was opened.
+type WarmStream = (Instant, TunnelStream);
+
+/// Warm streams, by tunnel and destination.
+static WARM_STREAMS: Lazy<Mutex<HashMap<PoolKey, Vec<WarmStream>>>> = Lazy::new(Default::default);
+
+/// When each destination, by tunnel, was last connected to.
+static LAST_CONNECTED: Lazy<Mutex<HashMap<PoolKey, Instant>>> = Lazy::new(Default::default);
+
+/// Opens a stream to the destination over the tunnel, using a warm stream if one was opened ahead of time. With --stream-pool, destinations connected to again within a few seconds get warm streams opened for their next connections.
+pub async fn pooled_stream(
+    tunnel: &'static ClientTunnel,
+    addr: &str,
+) -> anyhow::Result<TunnelStream> {
+    if CONNECT_CONFIG.stream_pool == 0 {
+        return tunnel.connect_stream(addr).await;
+    }
+    let key = (tunnel as *const ClientTunnel as usize, addr.to_string());
+    let now = Instant::now();
+    let warm = {
+        let mut warm_streams = WARM_STREAMS.lock();
+        let warm = warm_streams.get_mut(&key).and_then(|streams| {
+            streams.retain(|(opened, _)| now.saturating_duration_since(*opened) < WARM_STREAM_TTL);
+            streams.pop().map(|(_, stream)| stream)
+        });
+        if warm_streams
+            .get(&key)
+            .is_some_and(|streams| streams.is_empty())
+        {
+            warm_streams.remove(&key);
+        }
+        warm
+    };
+    let repeated = LAST_CONNECTED
+        .lock()
+        .insert(key.clone(), now)
+        .is_some_and(|last| now.saturating_duration_since(last) < WARM_STREAM_TTL);
+    if warm.is_some() || repeated {
+        smolscale::spawn(refill(tunnel, key)).detach();
+    }
+    match warm {
+        Some(stream) => {
+            log::debug!("using a warm stream to {addr}");
+            Ok(stream)
+        }
+        None => tunnel.connect_stream(addr).await,
+    }
+}
+
+/// Opens warm streams to the destination until there are --stream-pool of them, then closes those left unused once they get too old.
+async fn refill(tunnel: &'static ClientTunnel, key: PoolKey) {
+    let have = WARM_STREAMS
+        .lock()
+        .get(&key)
+        .map_or(0, |streams| streams.len());
+    for _ in have..CONNECT_CONFIG.stream_pool {
+        match tunnel.connect_stream(&key.1).await {
+            Ok(stream) => {
+                let mut warm_streams = WARM_STREAMS.lock();
+                let streams = warm_streams.entry(key.clone()).or_default();
+                // another refill may have got there first
+                if streams.len() >= CONNECT_CONFIG.stream_pool {
+                    break;
+                }
+                streams.push((Instant::now(), stream));
+            }
+            Err(err) => {
+                log::debug!("could not open a warm stream to {}: {:?}", key.1, err);
+                return;
+            }
+        }
+    }
+    smol::Timer::after(WARM_STREAM_TTL).await;
+    let now = Instant::now();
+    let mut warm_streams = WARM_STREAMS.lock();
+    if let Some(streams) = warm_streams.get_mut(&key) {
+        streams.retain(|(opened, _)| now.saturating_duration_since(*opened) < WARM_STREAM_TTL);
+        if streams.is_empty() {
+            warm_streams.remove(&key);
+        }
+    }
+    // forget destinations not connected to lately, so that this doesn't grow forever
+    LAST_CONNECTED
+        .lock()
+        .retain(|_, last| now.saturating_duration_since(*last) < WARM_STREAM_TTL);
+}
@@ -12,8 +12,11 @@ use smol::{
     future::FutureExt,
     Task,
 };
+use smol_timeout::TimeoutExt;
 use sosistab2::Pipe;
 
+use crate::connect::CONNECT_CONFIG;
+
 pub struct AutoconnectPipe<P: Pipe> {
     protocol: String,
     peer_metadata: String,
@@ -102,9 +105,37 @@ async fn autoconnect_loop<P: Pipe>(
         Down(Bytes),
         Replaced(P),
     }
+    let read_timeout = CONNECT_CONFIG
+        .socket_read_timeout
+        .map(|secs| Duration::from_secs_f64(secs.max(0.0)));
+    let write_timeout = CONNECT_CONFIG
+        .socket_write_timeout
+        .map(|secs| Duration::from_secs_f64(secs.max(0.0)));
     let mut current_pipe = init_pipe;
     let mut replace_task: Option<(Receiver<P>, Task<()>)> = None;
+    // when the oldest packet sent since anything was last received went out
+    let mut unanswered_since: Option<Instant> = None;
     let recreate = Arc::new(recreate);
+    let spawn_replace = |wait: Duration| {
+        let (send, recv) = smol::channel::bounded(1);
+        let protocol = protocol.clone();
+        let endpoint = endpoint.clone();
+        let recreate = recreate.clone();
+        (
+            recv,
+            smolscale::spawn(async move {
+                smol::Timer::after(wait).await;
+                let start = Instant::now();
+                log::debug!("reconnecting {protocol}/{endpoint}...");
+                let replacement = recreate().await;
+                log::debug!(
+                    "reconnected {protocol}/{endpoint} in {:?}!",
+                    start.elapsed()
+                );
+                let _ = send.try_send(replacement);
+            }),
+        )
+    };
     loop {
         let up_event = async {
             let up = recv_up.recv().await?;
@@ -127,39 +158,42 @@ async fn autoconnect_loop<P: Pipe>(
 
         match up_event.or(replace_event.or(dn_event)).await {
             Ok(Event::Up(up)) => {
-                current_pipe.send(up).await;
-                // on average, we need 5 packets to break through
-                if replace_task.is_none() && fastrand::f64() < 0.2 {
-                    let (send, recv) = smol::channel::bounded(1);
-                    let protocol = protocol.clone();
-                    let endpoint = endpoint.clone();
-                    let recreate = recreate.clone();
-                    replace_task = Some((
-                        recv,
-                        smolscale::spawn(async move {
-                            smol::Timer::after(Duration::from_secs(5)).await;
-                            let start = Instant::now();
-                            log::debug!("reconnecting {protocol}/{endpoint}...");
-                            let replacement = recreate().await;
-                            log::debug!(
-                                "reconnected {protocol}/{endpoint} in {:?}!",
-                                start.elapsed()
-                            );
-                            let _ = send.try_send(replacement);
-                        }),
-                    ));
+                let write_stalled = match write_timeout {
+                    Some(timeout) => current_pipe.send(up).timeout(timeout).await.is_none(),
+                    None => {
+                        current_pipe.send(up).await;
+                        false
+                    }
+                };
+                let waiting_since = *unanswered_since.get_or_insert_with(Instant::now);
+                let read_stalled =
+                    read_timeout.is_some_and(|timeout| waiting_since.elapsed() > timeout);
+                if write_stalled || read_stalled {
+                    // with --socket-read-timeout or --socket-write-timeout, a stalled pipe is replaced right away
+                    if replace_task.is_none() {
+                        log::debug!(
+                            "{protocol}/{endpoint} stalled on {}, replacing it",
+                            if write_stalled { "write" } else { "read" }
+                        );
+                        replace_task = Some(spawn_replace(Duration::ZERO));
+                    }
+                } else if replace_task.is_none() && fastrand::f64() < 0.2 {
+                    // on average, we need 5 packets to break through
+                    replace_task = Some(spawn_replace(Duration::from_secs(5)));
                 }
             }
             Ok(Event::Down(dn)) => {
                 replace_task = None;
+                unanswered_since = None;
                 let _ = send_down.try_send(dn);
             }
             Ok(Event::Replaced(p)) => {
                 current_pipe = p;
                 replace_task = None;
+                unanswered_since = None;
             }
             Err(err) => {
-                log::warn!("error: {:?}", err);
+                warn_limited!("error: {:?}", err);
                 smol::Timer::after(Duration::from_secs(1)).await;
             }
         }
@@ -0,0 +1,97 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use bytes::Bytes;
+use smol::{io::AsyncReadExt, io::AsyncWriteExt, lock::Mutex};
+use smol_timeout::TimeoutExt;
+use sosistab2::{Multiplex, MuxPublic, MuxSecret, MuxStream, Pipe};
+
+use crate::conninfo_store::ConnInfoStore;
+
+/// The port on which exits that support chaining accept chained sessions from other exits.
+const EXIT_CHAIN_PORT: u16 = 28080;
+
+/// Establishes a nested session to the exit `next_exit`, carried over a connection through an existing session to the exit `via_exit`.
+///
+/// This only works if the next exit cooperates by accepting chained sessions on [EXIT_CHAIN_PORT]; otherwise, authenticating over the returned session will fail.
+pub(crate) async fn chain_session(
+    outer: Arc<Multiplex>,
+    cstore: &ConnInfoStore,
+    via_exit: &str,
+    next_exit: &str,
+) -> anyhow::Result<Arc<Multiplex>> {
+    let summary = cstore.summary();
+    let next_descriptor = summary
+        .exits
+        .iter()
+        .find(|e| e.hostname.as_str() == next_exit)
+        .context(format!("no such exit {next_exit} to chain to"))?;
+    let stream = outer
+        .open_conn(&format!("{next_exit}:{EXIT_CHAIN_PORT}"))
+        .timeout(Duration::from_secs(60))
+        .await
+        .context("timed out opening chained connection")??;
+    let inner = Multiplex::new(
+        MuxSecret::generate(),
+        Some(MuxPublic::from_bytes(
+            *next_descriptor.sosistab_e2e_pk.as_bytes(),
+        )),
+    );
+    inner.add_pipe(ChainPipe {
+        write: stream.clone().into(),
+        read: stream.into(),
+        peer_addr: format!("{via_exit}->{next_exit}:{EXIT_CHAIN_PORT}"),
+    });
+    // the inner session is useless without the outer session
+    inner.add_drop_friend(outer);
+    Ok(Arc::new(inner))
+}
+
+/// A pipe that carries datagrams over a reliable stream through another exit, each framed with a big-endian u16 length.
+struct ChainPipe {
+    write: Mutex<MuxStream>,
+    read: Mutex<MuxStream>,
+    peer_addr: String,
+}
+
+#[async_trait]
+impl Pipe for ChainPipe {
+    async fn send(&self, to_send: Bytes) {
+        if to_send.len() > u16::MAX as usize {
+            log::warn!(
+                "dropping oversized chained datagram of {} bytes",
+                to_send.len()
+            );
+            return;
+        }
+        let mut write = self.write.lock().await;
+        let mut frame = Vec::with_capacity(to_send.len() + 2);
+        frame.extend_from_slice(&(to_send.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&to_send);
+        if let Err(err) = write.write_all(&frame).await {
+            log::debug!("could not send chained datagram: {:?}", err);
+        }
+    }
+
+    async fn recv(&self) -> std::io::Result<Bytes> {
+        let mut read = self.read.lock().await;
+        let mut len = [0u8; 2];
+        read.read_exact(&mut len).await?;
+        let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+        read.read_exact(&mut buf).await?;
+        Ok(buf.into())
+    }
+
+    fn protocol(&self) -> &str {
+        "sosistab2-chain"
+    }
+
+    fn peer_metadata(&self) -> &str {
+        ""
+    }
+
+    fn peer_addr(&self) -> String {
+        self.peer_addr.clone()
+    }
+}
@@ -2,7 +2,7 @@ use bytes::Bytes;
 use ed25519_dalek::ed25519::signature::Signature;
 use ed25519_dalek::{PublicKey, Verifier};
 use futures_util::{stream::FuturesUnordered, Future, StreamExt};
-use geph4_protocol::binder::protocol::BridgeDescriptor;
+use geph4_protocol::binder::protocol::{BridgeDescriptor, ExitDescriptor};
 
 use itertools::Itertools;
 use native_tls::TlsConnector;
@@ -13,11 +13,13 @@ use smol_str::SmolStr;
 use smol_timeout::TimeoutExt;
 use sosistab2::{Multiplex, MuxPublic, MuxSecret, ObfsTlsPipe, ObfsUdpPipe, ObfsUdpPublic, Pipe};
 
-use crate::{connect::CONNINFO_STORE, metrics::BridgeMetrics};
+use crate::{config::TransportFamily, connect::CONNINFO_STORE, metrics::BridgeMetrics};
 use crate::{
     connect::{
-        tunnel::{autoconnect::AutoconnectPipe, delay::DelayPipe, TunnelStatus},
-        METRIC_SESSION_ID,
+        tunnel::{
+            autoconnect::AutoconnectPipe, delay::DelayPipe, pacing::PacingPipe, TunnelStatus,
+        },
+        CONNECT_CONFIG, METRIC_SESSION_ID,
     },
     metrics::Metrics,
 };
@@ -78,10 +80,124 @@ fn verify_exit_signatures(
     Ok(())
 }
 
+/// Checks that the exit has the signing key pinned by --exit-pubkey.
+fn verify_exit_pubkey(exit: &ExitDescriptor, pubkey: [u8; 32]) -> anyhow::Result<()> {
+    if exit.signing_key.as_bytes() != &pubkey {
+        anyhow::bail!(
+            "exit {} has signing key {}, not the expected {}",
+            exit.hostname,
+            hex::encode(exit.signing_key.as_bytes()),
+            hex::encode(pubkey)
+        )
+    }
+    Ok(())
+}
+
+/// Finds the exit given by the parameters and its bridges, whose signatures are checked. Not every bridge is necessarily used; see [bridge_allowed].
+pub(crate) fn select_endpoints(
+    params: &BinderTunnelParams,
+) -> anyhow::Result<(ExitDescriptor, Vec<BridgeDescriptor>)> {
+    let summary = params.cstore.summary();
+    let exit_names = summary.exits.iter().map(|e| &e.hostname).collect_vec();
+    let selected_exit = summary
+        .exits
+        .iter()
+        .find(|s| Some(s.hostname.as_str()) == params.exit_server.as_deref())
+        .context(format!(
+            "no such exit found in the list; list is {:?}",
+            exit_names
+        ))?
+        .clone();
+    if let Some(pubkey) = params.exit_pubkey {
+        verify_exit_pubkey(&selected_exit, pubkey)?;
+    }
+    let bridges = params.cstore.bridges();
+    if bridges.is_empty() {
+        anyhow::bail!(
+            "no sosistab2 routes to {:?}, is this a valid exit?",
+            params.exit_server
+        )
+    }
+
+    log::debug!("{} routes", bridges.len());
+
+    verify_exit_signatures(&bridges, selected_exit.signing_key)?;
+    Ok((selected_exit, bridges))
+}
+
+/// With --sticky-bridges, restricts the bridges to the sticky set saved in the store, unless none of them are available or may be used.
+fn sticky_filter(
+    params: &BinderTunnelParams,
+    bridges: Vec<BridgeDescriptor>,
+) -> Vec<BridgeDescriptor> {
+    if !CONNECT_CONFIG.sticky_bridges {
+        return bridges;
+    }
+    let sticky = params.cstore.sticky_bridges();
+    let filtered = bridges
+        .iter()
+        .filter(|bridge| sticky.contains(&bridge.endpoint))
+        .cloned()
+        .collect_vec();
+    if !filtered.iter().any(|bridge| bridge_allowed(params, bridge)) {
+        warn_limited!("none of the sticky bridges are available, so using all bridges");
+        return bridges;
+    }
+    filtered
+}
+
+/// Keeps off the bridges in [BinderTunnelParams::avoid_bridges], unless none of the others may be used.
+fn avoid_filter(
+    params: &BinderTunnelParams,
+    bridges: Vec<BridgeDescriptor>,
+) -> Vec<BridgeDescriptor> {
+    let avoid = match &params.avoid_bridges {
+        Some(avoid) => avoid.read().clone(),
+        None => return bridges,
+    };
+    let filtered = bridges
+        .iter()
+        .filter(|bridge| !avoid.contains(&bridge.endpoint))
+        .cloned()
+        .collect_vec();
+    if !filtered.iter().any(|bridge| bridge_allowed(params, bridge)) {
+        warn_limited!(
+            "no bridges other than those of the other tunnel are usable, so sharing them"
+        );
+        return bridges;
+    }
+    filtered
+}
+
+/// Whether a bridge may be used under the bridge, protocol, and obfuscation restrictions in the parameters.
+pub(crate) fn bridge_allowed(params: &BinderTunnelParams, bridge: &BridgeDescriptor) -> bool {
+    if params.use_bridges && bridge.is_direct {
+        return false;
+    }
+    if let Some(obfs) = params.obfs {
+        if bridge.protocol != obfs.bridge_protocol() {
+            return false;
+        }
+    }
+    if let Some(regex) = &params.force_protocol {
+        let compiled = Regex::new(regex).expect("invalid protocol force");
+        if !compiled.is_match(&bridge.protocol) {
+            return false;
+        }
+    }
+    true
+}
+
 pub(crate) async fn get_session(ctx: TunnelCtx) -> anyhow::Result<Arc<sosistab2::Multiplex>> {
     match &ctx.endpoint {
         EndpointSource::Independent { endpoint } => {
             let (addr, raw_key) = parse_independent_endpoint(endpoint)?;
+            if !CONNECT_CONFIG.underlying_family.allows(&addr) {
+                anyhow::bail!(
+                    "endpoint {addr} is not allowed by --underlying-family {:?}",
+                    CONNECT_CONFIG.underlying_family
+                );
+            }
             let obfs_pk = ObfsUdpPublic::from_bytes(raw_key);
             let sessid = rand::thread_rng().gen::<u128>().to_string();
             let mplex = Multiplex::new(MuxSecret::generate(), None);
@@ -108,35 +224,20 @@ pub(crate) async fn get_session(ctx: TunnelCtx) -> anyhow::Result<Arc<sosistab2:
         }
         EndpointSource::Binder(binder_tunnel_params) => {
             let start = Instant::now();
-            let summary = binder_tunnel_params.cstore.summary();
-            let exit_names = summary.exits.iter().map(|e| &e.hostname).collect_vec();
-            let selected_exit = summary
-                .exits
-                .iter()
-                .find(|s| Some(s.hostname.as_str()) == binder_tunnel_params.exit_server.as_deref())
-                .context(format!(
-                    "no such exit found in the list; list is {:?}",
-                    exit_names
-                ))?
-                .clone();
-            let bridges = binder_tunnel_params.cstore.bridges();
-            if bridges.is_empty() {
-                anyhow::bail!(
-                    "no sosistab2 routes to {:?}, is this a valid exit?",
-                    binder_tunnel_params.exit_server
-                )
+            binder_tunnel_params.cstore.refresh_if_expired().await?;
+            let (selected_exit, bridges) = select_endpoints(binder_tunnel_params)?;
+            let bridges = sticky_filter(binder_tunnel_params, bridges);
+            let bridges = avoid_filter(binder_tunnel_params, bridges);
+            if let Some(in_use) = &binder_tunnel_params.bridges_in_use {
+                in_use.write().clear();
             }
 
-            log::debug!("{} routes", bridges.len());
-
             let e2e_key = MuxPublic::from_bytes(*selected_exit.sosistab_e2e_pk.as_bytes());
             let multiplex = Arc::new(sosistab2::Multiplex::new(
                 MuxSecret::generate(),
                 Some(e2e_key),
             ));
 
-            verify_exit_signatures(&bridges, selected_exit.signing_key)?;
-
             let (metrics_send, metrics_recv) = smol::channel::bounded(1000);
 
             // add *all* the bridges!
@@ -193,17 +294,76 @@ pub(crate) async fn get_session(ctx: TunnelCtx) -> anyhow::Result<Arc<sosistab2:
 
             Ok(multiplex)
         }
+        EndpointSource::File(endpoints) => {
+            if endpoints.bridges.is_empty() {
+                anyhow::bail!("no sosistab2 routes in the endpoints file")
+            }
+            if let Some(pubkey) = CONNECT_CONFIG.exit_pubkey {
+                verify_exit_pubkey(&endpoints.exit, pubkey)?;
+            }
+            verify_exit_signatures(&endpoints.bridges, endpoints.exit.signing_key)?;
+
+            let e2e_key = MuxPublic::from_bytes(*endpoints.exit.sosistab_e2e_pk.as_bytes());
+            let multiplex = Arc::new(sosistab2::Multiplex::new(
+                MuxSecret::generate(),
+                Some(e2e_key),
+            ));
+            // there's no binder to upload metrics to
+            let (metrics_send, _metrics_recv) = smol::channel::bounded(1);
+            let sess_id = format!("sess-{}", rand::thread_rng().gen::<u128>());
+            add_bridges(&ctx, &sess_id, &multiplex, &endpoints.bridges, metrics_send)
+                .timeout(Duration::from_secs(30))
+                .await
+                .context("timed out")?;
+            Ok(multiplex)
+        }
     }
 }
 
 const NUM_PIPES_PER_PROTOCOL: usize = 3;
 
+/// The wait before retrying a bridge whose handshake failed.
+const HANDSHAKE_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// The maximum delay imposed on connecting to a bridge with a bad track record, relative to the best bridge of the same protocol, so that historically-good bridges win the race.
+const BRIDGE_HEAD_START: Duration = Duration::from_secs(2);
+
+/// Adds pipes to the given bridges. With --prefer-transport, bridges of the other family are only tried if none of the preferred ones connect.
 async fn add_bridges(
     ctx: &TunnelCtx,
     sess_id: &str,
     mplex: &Multiplex,
     bridges: &[BridgeDescriptor],
     metrics_send: Sender<BridgeMetrics>,
+) {
+    let family = match CONNECT_CONFIG.prefer_transport {
+        Some(family) => family,
+        None => return add_bridges_inner(ctx, sess_id, mplex, bridges, metrics_send).await,
+    };
+    let (preferred, others): (Vec<_>, Vec<_>) = bridges
+        .iter()
+        .cloned()
+        .partition(|b| TransportFamily::of_protocol(&b.protocol) == Some(family));
+    add_bridges_inner(ctx, sess_id, mplex, &preferred, metrics_send.clone()).await;
+    let have_preferred = mplex
+        .iter_pipes()
+        .any(|pipe| TransportFamily::of_protocol(pipe.protocol()) == Some(family));
+    if have_preferred || others.is_empty() {
+        return;
+    }
+    log::warn!(
+        "no {:?} bridges connected, falling back to the other transports",
+        family
+    );
+    add_bridges_inner(ctx, sess_id, mplex, &others, metrics_send).await
+}
+
+async fn add_bridges_inner(
+    ctx: &TunnelCtx,
+    sess_id: &str,
+    mplex: &Multiplex,
+    bridges: &[BridgeDescriptor],
+    metrics_send: Sender<BridgeMetrics>,
 ) {
     // we pick only the 3 best out of every protocol
     let protocols: BTreeSet<SmolStr> = bridges.iter().map(|b| b.protocol.clone()).collect();
@@ -216,6 +376,19 @@ async fn add_bridges(
             .cloned()
             .collect_vec();
 
+        // the best-scoring bridge of this protocol starts right away
+        let best_quality = match &ctx.endpoint {
+            EndpointSource::Binder(params) => bridges
+                .iter()
+                .filter(|bridge| {
+                    CONNECT_CONFIG.underlying_family.allows(&bridge.endpoint)
+                        && bridge_allowed(params, bridge)
+                })
+                .map(|bridge| params.cstore.bridge_quality(bridge.endpoint))
+                .fold(0.0, f64::max),
+            _ => 0.0,
+        };
+
         let metrics_send = metrics_send.clone();
         outer.push(async move {
             let all_futures: Vec<_> = bridges
@@ -225,16 +398,15 @@ async fn add_bridges(
                     let ctx = ctx.clone();
                     let sess_id = sess_id.to_string();
                     let metrics_send = metrics_send.clone();
+                    let mut cstore = None;
+                    if !CONNECT_CONFIG.underlying_family.allows(&bridge.endpoint) {
+                        return None;
+                    }
                     if let EndpointSource::Binder(params) = &ctx.endpoint {
-                        if params.use_bridges && bridge.is_direct {
+                        if !bridge_allowed(params, &bridge) {
                             return None;
                         }
-                        if let Some(regex) = &params.force_protocol {
-                            let compiled = Regex::new(regex).expect("invalid protocol force");
-                            if !compiled.is_match(&bridge.protocol) {
-                                return None;
-                            }
-                        }
+                        cstore = Some(params.cstore.clone());
                     }
                     let protocol = protocol.clone();
                     Some(async move {
@@ -243,14 +415,44 @@ async fn add_bridges(
                             protocol: protocol.clone().into(),
                             pipe_latency: None,
                         };
-                        match connect_once(ctx.clone(), bridge.clone(), &sess_id).await {
+                        // give bridges that were good to us in the past a head start
+                        if let Some(cstore) = &cstore {
+                            let penalty =
+                                (best_quality - cstore.bridge_quality(bridge.endpoint)).max(0.0);
+                            smol::Timer::after(BRIDGE_HEAD_START.mul_f64(penalty)).await;
+                        }
+                        let mut attempt = 0;
+                        let result = loop {
+                            match connect_once(ctx.clone(), bridge.clone(), &sess_id).await {
+                                Err(err) if attempt < CONNECT_CONFIG.bridge_handshake_retries => {
+                                    log::debug!(
+                                        "handshake with {} failed, retrying: {:?}",
+                                        bridge.endpoint,
+                                        err
+                                    );
+                                    smol::Timer::after(HANDSHAKE_RETRY_DELAY).await;
+                                    attempt += 1;
+                                }
+                                result => break result,
+                            }
+                        };
+                        if let Some(cstore) = &cstore {
+                            cstore.record_bridge_result(
+                                bridge.endpoint,
+                                result
+                                    .as_ref()
+                                    .ok()
+                                    .map(|(_, latency)| Duration::from_secs_f64(*latency)),
+                            );
+                        }
+                        match result {
                             Ok((pipe, latency)) => {
                                 bridge_metrics.pipe_latency = Some(latency);
                                 let _ = metrics_send.send(bridge_metrics).await;
-                                Some(pipe)
+                                Some((pipe, bridge.endpoint))
                             }
                             Err(err) => {
-                                log::warn!(
+                                warn_limited!(
                                     "pipe creation failed for {} ({}): {:?}",
                                     bridge.endpoint,
                                     bridge.protocol,
@@ -272,9 +474,16 @@ async fn add_bridges(
                     break;
                 }
 
-                if let Some(pipe) = maybe_pipe {
+                if let Some((pipe, endpoint)) = maybe_pipe {
                     log::debug!("adding pipe {} @ {}", pipe.protocol(), pipe.peer_addr());
                     mplex.add_pipe(pipe);
+                    if let EndpointSource::Binder(BinderTunnelParams {
+                        bridges_in_use: Some(in_use),
+                        ..
+                    }) = &ctx.endpoint
+                    {
+                        in_use.write().insert(endpoint);
+                    }
                     count += 1;
                 }
             }
@@ -335,7 +544,7 @@ async fn autoconnect_with<P: Pipe, F: Future<Output = anyhow::Result<P>> + Send
             for wait in 0u64.. {
                 match f().await {
                     Ok(val) => return val,
-                    Err(err) => log::warn!(
+                    Err(err) => warn_limited!(
                         "problem reconnecting to {} / {}: {:?}",
                         protocol,
                         endpoint,
@@ -351,6 +560,22 @@ async fn autoconnect_with<P: Pipe, F: Future<Output = anyhow::Result<P>> + Send
 
 type ConnectLatency = f64;
 
+/// Connects a bare pipe to the bridge and returns how long it took, without adding it to any session.
+pub(crate) async fn probe_bridge(desc: BridgeDescriptor) -> anyhow::Result<Duration> {
+    let start = Instant::now();
+    let meta = format!("probe-{}", rand::thread_rng().gen::<u128>());
+    match desc.protocol.as_str() {
+        "sosistab2-obfsudp" => {
+            connect_udp(desc, meta).await?;
+        }
+        "sosistab2-obfstls" => {
+            connect_tls(desc, meta).await?;
+        }
+        other => anyhow::bail!("unknown protocol {other}"),
+    }
+    Ok(start.elapsed())
+}
+
 async fn connect_once(
     ctx: TunnelCtx,
     desc: BridgeDescriptor,
@@ -381,6 +606,12 @@ async fn connect_once(
             anyhow::bail!("unknown protocol {other}")
         }
     };
+    let inner: Box<dyn Pipe> = match CONNECT_CONFIG.obfs_pacing {
+        Some(max_jitter_ms) => {
+            Box::new(PacingPipe::new(inner, Duration::from_millis(max_jitter_ms)))
+        }
+        None => inner,
+    };
     let latency = start.elapsed().as_secs_f64();
     Ok((inner, latency))
 }
@@ -397,7 +628,8 @@ async fn replace_dead(
         smol::Timer::after(Duration::from_secs(120)).await;
         loop {
             let fallible_part = async {
-                let current_bridges = cstore.bridges();
+                cstore.refresh_if_expired().await?;
+                let current_bridges = sticky_filter(&binder_tunnel_params, cstore.bridges());
                 let multiplex = weak_multiplex.upgrade().context("multiplex is dead")?;
                 for (i, pipe) in multiplex.iter_pipes().enumerate() {
                     log::debug!("pipe {i}: [{}] {}", pipe.protocol(), pipe.peer_addr());
@@ -436,7 +668,7 @@ async fn replace_dead(
                 anyhow::Ok(())
             };
             if let Err(err) = fallible_part.await {
-                log::warn!("error replacing dead bridges: {:?}", err)
+                warn_limited!("error replacing dead bridges: {:?}", err)
             } else {
                 break;
             }
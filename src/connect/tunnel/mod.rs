@@ -1,54 +1,85 @@
 use bytes::Bytes;
 
 use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
 use smol::channel::{Receiver, Sender};
 use smol_str::SmolStr;
 use std::net::SocketAddr;
 
 use sosistab2::MuxStream;
 use std::{
+    collections::{HashSet, VecDeque},
     sync::{
         atomic::{AtomicU32, Ordering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tunnel_actor::tunnel_actor;
 pub mod activity;
 pub mod getsess;
 
 mod autoconnect;
+mod chain;
 mod delay;
+mod pacing;
+mod pause;
+mod stream;
+pub use stream::TunnelStream;
 pub mod tunnel_actor;
 
 use std::net::Ipv4Addr;
 
-use crate::conninfo_store::ConnInfoStore;
+use crate::{
+    config::ObfsLayer,
+    conninfo_store::{ConnInfoStore, EndpointsFile},
+};
 
-use self::activity::notify_activity;
+use self::{activity::notify_activity, pause::PauseSwitch, stream::StreamCounters};
 
 #[derive(Clone)]
 pub enum EndpointSource {
     Independent { endpoint: String },
     Binder(BinderTunnelParams),
+    File(Arc<EndpointsFile>),
 }
 
+/// A set of bridges, shared between tunnels so that they can keep off each other's.
+pub type SharedBridges = Arc<RwLock<HashSet<SocketAddr>>>;
+
 #[derive(Clone)]
 pub struct BinderTunnelParams {
     pub cstore: Arc<ConnInfoStore>,
     pub exit_server: Option<String>,
+    /// If given, the exit must have this signing key.
+    pub exit_pubkey: Option<[u8; 32]>,
+    /// If given, a second exit that the tunnel reaches through the first one.
+    pub chain_exit: Option<String>,
     pub use_bridges: bool,
+    // passed along, but not yet honored when picking bridges
+    #[allow(dead_code)]
     pub force_bridge: Option<Ipv4Addr>,
     pub force_protocol: Option<String>,
+    /// If given, only bridges with this obfuscation layer are used.
+    pub obfs: Option<ObfsLayer>,
+    /// If given, kept up to date with the bridges that the current session has pipes to.
+    pub bridges_in_use: Option<SharedBridges>,
+    /// If given, bridges that are avoided, unless there are no others, such as those another tunnel is using.
+    pub avoid_bridges: Option<SharedBridges>,
 }
 
+/// Where the tunnel actor sends a newly opened stream, along with the stream counters of the session it is on.
+pub(crate) type StreamReply = Sender<(MuxStream, Arc<StreamCounters>)>;
+
 #[derive(Clone)]
 pub(crate) struct TunnelCtx {
     pub endpoint: EndpointSource,
-    pub recv_socks5_conn: Receiver<(String, Sender<MuxStream>)>,
+    pub recv_socks5_conn: Receiver<(String, StreamReply)>,
     pub vpn_client_ip: Arc<AtomicU32>,
 
     pub connect_status: Arc<RwLock<ConnectionStatus>>,
+    pub history: Arc<RwLock<ConnectionHistory>>,
+    pub pause: Arc<PauseSwitch>,
     recv_vpn_outgoing: Receiver<Bytes>,
     send_vpn_incoming: Sender<Bytes>,
 
@@ -61,6 +92,8 @@ pub(crate) struct TunnelCtx {
 pub enum TunnelStatus {
     /// Just about to connect to a given address, with the given protocol
     PreConnect { addr: SocketAddr, protocol: SmolStr },
+    /// Could not connect because the binder is in maintenance, possibly until the given unix time
+    BinderMaintenance { until_unix: Option<u64> },
 }
 
 /// A ConnectionStatus shows the status of the tunnel.
@@ -76,6 +109,45 @@ impl ConnectionStatus {
     }
 }
 
+/// How many past reconnects are remembered.
+const MAX_RECONNECT_HISTORY: usize = 100;
+
+/// A record of the tunnel going down and having to reconnect.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReconnectEvent {
+    pub unix_timestamp: u64,
+    pub reason: SmolStr,
+}
+
+/// The connection history of a tunnel.
+#[derive(Default, Clone, Debug)]
+pub struct ConnectionHistory {
+    /// When the current session was established, if it's currently connected.
+    pub session_start: Option<SystemTime>,
+    /// The most recent reconnects, oldest first.
+    pub reconnects: VecDeque<ReconnectEvent>,
+}
+
+impl ConnectionHistory {
+    pub(crate) fn record_connected(&mut self) {
+        self.session_start = Some(SystemTime::now());
+    }
+
+    pub(crate) fn record_reconnect(&mut self, reason: &anyhow::Error) {
+        self.session_start = None;
+        self.reconnects.push_back(ReconnectEvent {
+            unix_timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            reason: reason.to_string().into(),
+        });
+        if self.reconnects.len() > MAX_RECONNECT_HISTORY {
+            self.reconnects.pop_front();
+        }
+    }
+}
+
 /// A tunnel starts and keeps alive the best sosistab session it can under given constraints.
 /// A sosistab Session is *a single end-to-end connection between a client and a server.*
 /// This can be thought of as analogous to TcpStream, except all reads and writes are datagram-based and unreliable.
@@ -83,19 +155,23 @@ pub struct ClientTunnel {
     endpoint: EndpointSource,
     client_ip_addr: Arc<AtomicU32>,
     connect_status: Arc<RwLock<ConnectionStatus>>,
+    history: Arc<RwLock<ConnectionHistory>>,
+    streams: Arc<StreamCounters>,
+    pause: Arc<PauseSwitch>,
 
     send_vpn_outgoing: Sender<Bytes>,
     recv_vpn_incoming: Receiver<Bytes>,
 
-    open_socks5_conn: Sender<(String, Sender<MuxStream>)>,
+    open_socks5_conn: Sender<(String, StreamReply)>,
 
     _task: Arc<smol::Task<anyhow::Result<()>>>,
 }
 
 impl ClientTunnel {
-    /// Creates a new ClientTunnel.
+    /// Creates a new ClientTunnel. If `paused`, it does not connect until [ClientTunnel::set_paused] resumes it.
     pub fn new(
         endpoint: EndpointSource,
+        paused: bool,
         status_callback: impl Fn(TunnelStatus) + Send + Sync + 'static,
     ) -> Self {
         let (send_socks5, recv_socks5) = smol::channel::unbounded();
@@ -106,12 +182,16 @@ impl ClientTunnel {
         let _last_ping_ms = Arc::new(AtomicU32::new(0));
 
         let connect_status = Arc::new(RwLock::new(ConnectionStatus::Connecting));
+        let history = Arc::new(RwLock::new(ConnectionHistory::default()));
+        let pause = Arc::new(PauseSwitch::new(paused));
         let ctx = TunnelCtx {
             endpoint: endpoint.clone(),
             recv_socks5_conn: recv_socks5,
             vpn_client_ip: current_state.clone(),
 
             connect_status: connect_status.clone(),
+            history: history.clone(),
+            pause: pause.clone(),
             send_vpn_incoming: send_incoming,
             recv_vpn_outgoing: recv_outgoing,
             status_callback: Arc::new(status_callback),
@@ -126,10 +206,18 @@ impl ClientTunnel {
             open_socks5_conn: send_socks5,
 
             connect_status,
+            history,
+            streams: Default::default(),
+            pause,
             _task: task,
         }
     }
 
+    /// Returns the connection history of the tunnel.
+    pub fn history(&self) -> ConnectionHistory {
+        self.history.read().clone()
+    }
+
     /// Returns the current connection status.
     pub fn status(&self) -> ConnectionStatus {
         if self.client_ip_addr.load(Ordering::Relaxed) == 0 {
@@ -139,13 +227,27 @@ impl ClientTunnel {
         }
     }
 
+    /// Pauses the tunnel, disconnecting it, or resumes it.
+    pub fn set_paused(&self, paused: bool) {
+        self.pause.set(paused)
+    }
+
+    /// Returns the number of streams currently open over the tunnel, and the number ever opened.
+    pub fn stream_counts(&self) -> (u64, u64) {
+        self.streams.get()
+    }
+
     /// Returns a sosistab stream to the given remote host.
-    pub async fn connect_stream(&self, remote: &str) -> anyhow::Result<MuxStream> {
+    pub async fn connect_stream(&self, remote: &str) -> anyhow::Result<TunnelStream> {
         let (send, recv) = smol::channel::bounded(1);
         self.open_socks5_conn
             .send((remote.to_string(), send))
             .await?;
-        Ok(recv.recv().await?)
+        let (stream, session_streams) = recv.recv().await?;
+        Ok(TunnelStream::new(
+            stream,
+            vec![self.streams.clone(), session_streams],
+        ))
     }
 
     pub async fn send_vpn(&self, msg: Bytes) -> anyhow::Result<()> {
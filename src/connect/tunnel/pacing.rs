@@ -0,0 +1,84 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use rand::Rng;
+use smol::{channel::Sender, Task};
+use sosistab2::Pipe;
+
+/// Probability of following an outgoing datagram with a padding datagram.
+const PADDING_PROBABILITY: f64 = 0.1;
+/// Maximum size of a padding datagram.
+const MAX_PADDING_LEN: usize = 512;
+/// How many packets can wait to be paced. Packets beyond this are dropped, like a full network queue would.
+const MAX_QUEUED: usize = 1000;
+
+pub struct PacingPipe<P: Pipe> {
+    inner: Arc<P>,
+    send_outgoing: Sender<(Bytes, Instant)>,
+    _task: Task<anyhow::Result<()>>,
+}
+
+impl<P: Pipe> PacingPipe<P> {
+    /// Creates a new Pipe that delays every outgoing packet by a random amount up to `max_jitter`, and occasionally sends a padding packet, so that traffic timing and sizes are less regular.
+    ///
+    /// Packets are never reordered. Each one is delayed from when it was sent, so the jitter of packets sent in quick succession overlaps rather than adding up, and the added latency is bounded by `max_jitter` plus the time the underlying pipe takes to send what is ahead of it. Padding packets consist entirely of 0xff bytes, which the other side's multiplex discards as unrecognizable.
+    pub fn new(pipe: P, max_jitter: Duration) -> Self {
+        let pipe = Arc::new(pipe);
+        let (send_outgoing, recv_outgoing) = smol::channel::bounded::<(Bytes, Instant)>(MAX_QUEUED);
+        let out_pipe = pipe.clone();
+        let _task = smolscale::spawn(async move {
+            let mut last_deadline = Instant::now();
+            loop {
+                let (pkt, enqueued) = recv_outgoing.recv().await?;
+                let jitter = max_jitter.mul_f64(rand::thread_rng().gen());
+                // never go before the previous packet, so that ordering is kept
+                let deadline = last_deadline.max(enqueued + jitter);
+                last_deadline = deadline;
+                smol::Timer::at(deadline).await;
+                out_pipe.send(pkt).await;
+                if rand::thread_rng().gen_bool(PADDING_PROBABILITY) {
+                    let len = rand::thread_rng().gen_range(1, MAX_PADDING_LEN + 1);
+                    out_pipe.send(vec![0xff; len].into()).await;
+                }
+            }
+        });
+        Self {
+            inner: pipe,
+            send_outgoing,
+            _task,
+        }
+    }
+}
+
+#[async_trait]
+impl<P: Pipe> Pipe for PacingPipe<P> {
+    async fn send(&self, to_send: Bytes) {
+        if self
+            .send_outgoing
+            .try_send((to_send, Instant::now()))
+            .is_err()
+        {
+            log::trace!("pacing queue full, dropping packet");
+        }
+    }
+
+    async fn recv(&self) -> std::io::Result<Bytes> {
+        self.inner.recv().await
+    }
+
+    fn protocol(&self) -> &str {
+        self.inner.protocol()
+    }
+
+    fn peer_metadata(&self) -> &str {
+        self.inner.peer_metadata()
+    }
+
+    fn peer_addr(&self) -> String {
+        self.inner.peer_addr()
+    }
+}
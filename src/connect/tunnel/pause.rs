@@ -0,0 +1,38 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use event_listener::Event;
+
+/// A switch that pauses a tunnel, tearing down its session and keeping it down until it is resumed.
+pub struct PauseSwitch {
+    paused: AtomicBool,
+    changed: Event,
+}
+
+impl PauseSwitch {
+    pub fn new(paused: bool) -> Self {
+        Self {
+            paused: AtomicBool::new(paused),
+            changed: Event::new(),
+        }
+    }
+
+    pub fn set(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+        self.changed.notify(usize::MAX);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Waits until the switch is in the given position.
+    pub async fn wait_for(&self, paused: bool) {
+        loop {
+            let listener = self.changed.listen();
+            if self.is_paused() == paused {
+                return;
+            }
+            listener.await;
+        }
+    }
+}
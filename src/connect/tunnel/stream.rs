@@ -0,0 +1,88 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use futures_util::{AsyncRead, AsyncWrite};
+use sosistab2::MuxStream;
+
+/// Counts the streams opened over a tunnel, or over one of its sessions.
+#[derive(Default)]
+pub(crate) struct StreamCounters {
+    active: AtomicU64,
+    total: AtomicU64,
+}
+
+impl StreamCounters {
+    /// Returns the number of streams currently open, and the number ever opened.
+    pub fn get(&self) -> (u64, u64) {
+        (
+            self.active.load(Ordering::Relaxed),
+            self.total.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Decrements the active stream counts once every clone of a stream is gone.
+struct ActiveGuard(Vec<Arc<StreamCounters>>);
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        for counters in self.0.iter() {
+            counters.active.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A stream multiplexed over a tunnel's session, counted as active until it and all its clones are dropped.
+#[derive(Clone)]
+pub struct TunnelStream {
+    inner: MuxStream,
+    _guard: Arc<ActiveGuard>,
+}
+
+impl TunnelStream {
+    /// Wraps a stream, counting it in each of the given counters.
+    pub(crate) fn new(inner: MuxStream, counters: Vec<Arc<StreamCounters>>) -> Self {
+        for counters in counters.iter() {
+            counters.active.fetch_add(1, Ordering::Relaxed);
+            counters.total.fetch_add(1, Ordering::Relaxed);
+        }
+        Self {
+            inner,
+            _guard: Arc::new(ActiveGuard(counters)),
+        }
+    }
+}
+
+impl AsyncRead for TunnelStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TunnelStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
@@ -1,13 +1,17 @@
 use crate::{
     connect::{
         stats::{StatItem, STATS_GATHERER, STATS_RECV_BYTES, STATS_SEND_BYTES},
-        tunnel::{ConnectionStatus, EndpointSource},
+        tunnel::{ConnectionStatus, EndpointSource, TunnelStatus},
+        CONNECT_CONFIG,
     },
+    fronts::BinderMaintenance,
 };
 
 use super::{
     activity::{notify_activity, wait_activity},
+    chain::chain_session,
     getsess::get_session,
+    stream::StreamCounters,
     TunnelCtx,
 };
 use anyhow::Context;
@@ -38,9 +42,30 @@ use std::{
 /// Background task of a TunnelManager
 pub(crate) async fn tunnel_actor(ctx: TunnelCtx) -> anyhow::Result<()> {
     loop {
+        ctx.pause.wait_for(false).await;
         // Run until a failure happens, log the error, then restart
-        if let Err(err) = tunnel_actor_once(ctx.clone()).await {
-            log::warn!("tunnel_actor restarting: {:?}", err);
+        let result = tunnel_actor_once(ctx.clone())
+            .or(async {
+                ctx.pause.wait_for(true).await;
+                log::info!("tunnel paused");
+                Ok(())
+            })
+            .await;
+        if let Err(err) = result {
+            if let Some(maintenance) = err.downcast_ref::<BinderMaintenance>() {
+                (ctx.status_callback)(TunnelStatus::BinderMaintenance {
+                    until_unix: maintenance.until_unix,
+                });
+            }
+            warn_limited!("tunnel_actor restarting: {:?}", err);
+            ctx.history.write().record_reconnect(&err);
+            // the client IP is only set once connected, so this tells a dropped connection apart from a failed attempt
+            if ctx.vpn_client_ip.load(Ordering::SeqCst) != 0 {
+                smol::Timer::after(Duration::from_secs_f64(
+                    CONNECT_CONFIG.reconnect_initial_delay,
+                ))
+                .await;
+            }
             smol::Timer::after(Duration::from_secs(1)).await;
         }
     }
@@ -55,16 +80,106 @@ async fn print_stats_loop(mux: Arc<Multiplex>) {
     }
 }
 
-async fn tunnel_actor_once(ctx: TunnelCtx) -> anyhow::Result<()> {
-    let _start = Instant::now();
+/// How often a session replaced by --max-session-secs rotation is checked for connections still on it.
+const ROTATED_SESSION_POLL: Duration = Duration::from_secs(5);
+
+/// A session to the exit, authenticated and ready to carry traffic.
+struct ReadySession {
+    mux: Arc<Multiplex>,
+    client_ip: u32,
+    protocol: &'static str,
+    address: String,
+    /// Counts the streams opened over this session.
+    streams: Arc<StreamCounters>,
+}
 
-    let ctx1 = ctx.clone();
+async fn tunnel_actor_once(ctx: TunnelCtx) -> anyhow::Result<()> {
     ctx.vpn_client_ip.store(0, Ordering::SeqCst);
     notify_activity();
 
-    let tunnel_mux = get_session(ctx.clone()).await?;
+    let mut session = connect_session(&ctx).await?;
+
+    let ctx2 = ctx.clone();
+    scopeguard::defer!({
+        *ctx2.connect_status.write() = ConnectionStatus::Connecting;
+    });
+
+    loop {
+        ctx.vpn_client_ip.store(session.client_ip, Ordering::SeqCst);
+        log::info!("TUNNEL_ACTOR MAIN LOOP!");
+        *ctx.connect_status.write() = ConnectionStatus::Connected {
+            protocol: session.protocol.into(),
+            address: session.address.as_str().into(),
+        };
+        ctx.history.write().record_connected();
+
+        let serve = serve_session(ctx.clone(), session.mux.clone(), session.streams.clone());
+        let max_age = match CONNECT_CONFIG.max_session_secs {
+            // a fresh session may be assigned a different VPN address, which would break every VPN flow
+            Some(_) if CONNECT_CONFIG.vpn_mode.is_some() => {
+                warn_limited!("--max-session-secs has no effect in VPN mode");
+                return serve.await;
+            }
+            Some(secs) => Duration::from_secs(secs),
+            None => return serve.await,
+        };
+        // keep serving on the old session until its replacement is up
+        let next = async {
+            serve.await?;
+            anyhow::bail!("session ended")
+        }
+        .or(rotate_session(&ctx, max_age))
+        .await?;
+        let old = std::mem::replace(&mut session, next);
+        log::info!(
+            "rotated to a fresh session, closing the old one once its {} connections finish",
+            old.streams.get().0
+        );
+        // the old session's streams cannot move, so it stays up until the last one closes
+        smolscale::spawn(async move {
+            while old.streams.get().0 > 0 {
+                smol::Timer::after(ROTATED_SESSION_POLL).await;
+            }
+            log::debug!("closing the rotated-out session");
+            drop(old);
+        })
+        .detach();
+    }
+}
 
-    if let EndpointSource::Binder(binder_tunnel_params) = ctx.endpoint.clone() {
+/// Waits until the current session is `max_age` old, then connects its replacement, retrying until one is up.
+async fn rotate_session(ctx: &TunnelCtx, max_age: Duration) -> anyhow::Result<ReadySession> {
+    smol::Timer::after(max_age).await;
+    log::info!("session reached --max-session-secs, rotating");
+    loop {
+        match connect_session(ctx).await {
+            Ok(session) => return Ok(session),
+            Err(err) => {
+                warn_limited!("cannot rotate session, keeping the old one: {:?}", err);
+                smol::Timer::after(Duration::from_secs(30)).await;
+            }
+        }
+    }
+}
+
+/// Establishes and authenticates a new session to the exit.
+async fn connect_session(ctx: &TunnelCtx) -> anyhow::Result<ReadySession> {
+    let mut tunnel_mux = get_session(ctx.clone()).await?;
+    let mut status_protocol = "sosistab2";
+    let mut status_address = String::from("dynamic");
+
+    let client_ip = if let EndpointSource::File(endpoints) = ctx.endpoint.clone() {
+        if let Some(token) = endpoints.token.as_ref() {
+            let ipv4 = authenticate_session(&tunnel_mux, token)
+                .timeout(Duration::from_secs(60))
+                .await
+                .ok_or_else(|| anyhow::anyhow!("authentication timed out"))??;
+            log::info!("VPN private IP assigned: {ipv4}");
+            ipv4.into()
+        } else {
+            12345
+        }
+    } else if let EndpointSource::Binder(binder_tunnel_params) = ctx.endpoint.clone() {
         let auth_start = Instant::now();
         // authenticate
         let token = binder_tunnel_params.cstore.blind_token();
@@ -74,36 +189,65 @@ async fn tunnel_actor_once(ctx: TunnelCtx) -> anyhow::Result<()> {
             .ok_or_else(|| anyhow::anyhow!("authentication timed out"))??;
         let auth_time = auth_start.elapsed().as_secs_f64();
         log::debug!("auth time: {}s", auth_time);
+        let ipv4 = if let Some(chain_exit) = binder_tunnel_params.chain_exit.as_deref() {
+            let via_exit = binder_tunnel_params.exit_server.unwrap_or_default();
+            tunnel_mux = chain_session(
+                tunnel_mux,
+                &binder_tunnel_params.cstore,
+                &via_exit,
+                chain_exit,
+            )
+            .await
+            .with_context(|| format!("could not chain from {via_exit} to {chain_exit}"))?;
+            let ipv4 = authenticate_session(&tunnel_mux, &token)
+                .timeout(Duration::from_secs(60))
+                .await
+                .ok_or_else(|| anyhow::anyhow!("authentication timed out"))
+                .and_then(|r| r)
+                .with_context(|| {
+                    format!("could not chain to {chain_exit}; it may not support exit chaining")
+                })?;
+            log::info!("two-hop path established: {via_exit} -> {chain_exit}");
+            status_protocol = "sosistab2-chain";
+            status_address = format!("{via_exit} -> {chain_exit}");
+            ipv4
+        } else {
+            ipv4
+        };
         log::info!("VPN private IP assigned: {ipv4}");
-        ctx.vpn_client_ip.store(ipv4.into(), Ordering::SeqCst);
+        ipv4.into()
     } else {
-        ctx.vpn_client_ip.store(12345, Ordering::SeqCst);
-    }
-
-    log::info!("TUNNEL_ACTOR MAIN LOOP!");
-    *ctx.connect_status.write() = ConnectionStatus::Connected {
-        protocol: "sosistab2".into(),
-        address: "dynamic".into(),
+        12345
     };
 
-    let ctx2 = ctx.clone();
-    scopeguard::defer!({
-        *ctx2.connect_status.write() = ConnectionStatus::Connecting;
-    });
+    Ok(ReadySession {
+        mux: tunnel_mux,
+        client_ip,
+        protocol: status_protocol,
+        address: status_address,
+        streams: Default::default(),
+    })
+}
 
+/// Carries connections and VPN packets over a session until it fails.
+async fn serve_session(
+    ctx: TunnelCtx,
+    tunnel_mux: Arc<Multiplex>,
+    streams: Arc<StreamCounters>,
+) -> anyhow::Result<()> {
     let (send_death, recv_death) = smol::channel::unbounded();
     let _lala = smolscale::spawn(print_stats_loop(tunnel_mux.clone()));
-    connection_handler_loop(ctx1.clone(), tunnel_mux.clone(), send_death)
+    connection_handler_loop(ctx.clone(), tunnel_mux.clone(), streams, send_death)
         .or(async {
             // kill the whole session if any one connection fails
             let e = recv_death.recv().await.context("death received")?;
             anyhow::bail!(e)
         })
-        .or(watchdog_loop(ctx1.clone(), tunnel_mux.clone()))
+        .or(watchdog_loop(ctx.clone(), tunnel_mux.clone()))
         .or(vpn_loop(
             tunnel_mux.clone(),
-            ctx.send_vpn_incoming,
-            ctx.recv_vpn_outgoing,
+            ctx.send_vpn_incoming.clone(),
+            ctx.recv_vpn_outgoing.clone(),
         ))
         .await
 }
@@ -184,6 +328,7 @@ async fn vpn_loop(
 async fn connection_handler_loop(
     ctx: TunnelCtx,
     mux: Arc<sosistab2::Multiplex>,
+    streams: Arc<StreamCounters>,
     send_death: Sender<anyhow::Error>,
 ) -> anyhow::Result<()> {
     loop {
@@ -193,6 +338,7 @@ async fn connection_handler_loop(
             .await
             .context("cannot get socks5 connect request")?;
         let mux = mux.clone();
+        let streams = streams.clone();
         let send_death = send_death.clone();
         notify_activity();
         smolscale::spawn(async move {
@@ -209,7 +355,10 @@ async fn connection_handler_loop(
                         start.elapsed().as_millis(),
                     );
 
-                    conn_reply.send(remote).await.context("conn_reply failed")?;
+                    conn_reply
+                        .send((remote, streams))
+                        .await
+                        .context("conn_reply failed")?;
                     Ok::<(), anyhow::Error>(())
                 }
                 Some(Err(err)) => {
@@ -267,7 +416,7 @@ async fn watchdog_loop(
             log::debug!("** watchdog completed in {:?} **", ping);
         }
 
-        let timer = smol::Timer::after(Duration::from_secs(10));
+        let timer = smol::Timer::after(Duration::from_millis(CONNECT_CONFIG.stats_interval_ms));
         wait_activity(Duration::from_secs(600)).await;
         timer.await;
     }
@@ -0,0 +1,71 @@
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::Context;
+use futures_util::TryFutureExt;
+use smol::prelude::*;
+use smol_timeout::TimeoutExt;
+
+use super::{
+    keepalive::apply_tcp_keepalive,
+    listen::BoundListener,
+    relay::copy_with_stats,
+    socket_owner::{client_uid_tunneled, forward_decision},
+    socks5::socks5_allowed,
+};
+
+/// How long a client gets to send its first byte.
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The version byte that every SOCKS5 greeting starts with. HTTP requests start with a method name instead.
+const SOCKS5_VERSION: u8 = 5;
+
+/// With --unified-proxy, accepts connections on `listen` and forwards each one to the SOCKS5 proxy at `socks5` or the HTTP proxy at `http`, depending on the first byte it sends.
+pub async fn unified_front(
+    listen: &'static BoundListener,
+    socks5: SocketAddr,
+    http: SocketAddr,
+) -> anyhow::Result<()> {
+    let listener = smol::net::TcpListener::try_from(listen.take()?)
+        .context("cannot set up unified proxy listener")?;
+    log::debug!("unified SOCKS5 and HTTP proxy started on {}", listen.addr());
+    loop {
+        let (client, client_addr) = listener
+            .accept()
+            .await
+            .context("cannot accept unified proxy")?;
+        if !socks5_allowed(client_addr.ip()) {
+            log::debug!("rejecting proxy client {client_addr} not in --socks5-allow-from");
+            continue;
+        }
+        apply_tcp_keepalive(&client);
+        let tunneled = client_uid_tunneled(&client);
+        smolscale::spawn(
+            async move {
+                let mut first = [0u8; 1];
+                let n = client
+                    .peek(&mut first)
+                    .timeout(SNIFF_TIMEOUT)
+                    .await
+                    .context("client sent nothing")??;
+                if n == 0 {
+                    return Ok(());
+                }
+                let inner = if first[0] == SOCKS5_VERSION {
+                    socks5
+                } else {
+                    http
+                };
+                // peeking leaves the first byte in place, so the inner proxy sees the whole stream
+                let upstream = smol::net::TcpStream::connect(inner).await?;
+                upstream.set_nodelay(true)?;
+                forward_decision(upstream.local_addr()?, tunneled);
+                copy_with_stats(client.clone(), upstream.clone(), |_| ())
+                    .race(copy_with_stats(upstream, client, |_| ()))
+                    .await?;
+                anyhow::Ok(())
+            }
+            .map_err(move |err| log::debug!("unified proxy client {client_addr} died: {:?}", err)),
+        )
+        .detach();
+    }
+}
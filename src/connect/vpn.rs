@@ -1,9 +1,14 @@
 #[cfg(target_os = "linux")]
 mod linux_routing;
 
+#[cfg(target_os = "linux")]
+mod linux_tproxy;
+
 #[cfg(target_os = "macos")]
 mod macos_routing;
 
+mod mtu;
+
 #[cfg(windows)]
 mod windows_routing;
 
@@ -25,192 +30,317 @@ use anyhow::Context;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use bytes::Bytes;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddrV4};
 
 use geph_nat::GephNat;
 use governor::{Quota, RateLimiter};
 use once_cell::sync::Lazy;
+use parking_lot::RwLock;
 use pnet_packet::ip::IpNextHeaderProtocols;
 use pnet_packet::MutablePacket;
 use pnet_packet::{
-    ipv4::Ipv4Packet,
+    ipv4::{Ipv4Packet, MutableIpv4Packet},
     tcp::{TcpFlags, TcpPacket},
+    udp::{MutableUdpPacket, UdpPacket},
     Packet,
 };
 use smol::prelude::*;
 
-use crate::{config::VpnMode, connect::stats::STATS_RECV_BYTES};
+use self::mtu::MTU_WATCH;
+use crate::{
+    config::{VpnIpv6, VpnMode, VpnQueueOverflow},
+    connect::{
+        dns::{local_request, probe_remote_ipv6},
+        stats::STATS_RECV_BYTES,
+    },
+};
 
-use super::{stats::STATS_SEND_BYTES, CONNECT_CONFIG, TUNNEL};
+use super::{active_tunnel, stats::STATS_SEND_BYTES, tunnel::ClientTunnel, CONNECT_CONFIG, TUNNEL};
 
 /// The VPN shuffling task
 pub static VPN_SHUFFLE_TASK: Lazy<JoinHandle<Infallible>> = Lazy::new(|| {
-    #[cfg(unix)]
-    /// Runs the VPN on a particular file-descriptor number.
-    unsafe fn fd_vpn_loop(fd_num: i32) -> Infallible {
-        let mut up_file = std::fs::File::from_raw_fd(fd_num);
-        let mut down_file = std::fs::File::from_raw_fd(fd_num);
-        let up_thread = std::thread::Builder::new()
-            .name("vpn-up".into())
-            .spawn(move || {
-                let mut bts = [0u8; 65536];
-                loop {
-                    let n = up_file.read(&mut bts).expect("vpn up thread failed");
-
-                    let to_send = Bytes::copy_from_slice(&bts[..n]);
-                    #[cfg(target_os = "macos")]
-                    let to_send = if to_send.len() >= 4 {
-                        to_send.slice(4..)
-                    } else {
-                        continue;
-                    };
-                    log::trace!("vpn up {}", to_send.len());
-                    vpn_upload(to_send);
-                }
-            })
-            .unwrap();
-        let dn_thread = std::thread::Builder::new()
-            .name("vpn-dn".into())
-            .spawn(move || loop {
-                let bts = vpn_download_blocking();
-                log::trace!("vpn dn {}", bts.len());
-                #[cfg(target_os = "macos")]
-                {
-                    let mut buf = [0u8; 65536];
-                    buf[4..][..bts.len()].copy_from_slice(&bts);
-                    buf[3] = 0x02;
-                    let _ = down_file.write(&buf[..bts.len() + 4]);
-                }
-                #[cfg(not(target_os = "macos"))]
-                let _ = down_file.write(&bts).unwrap();
-            })
-            .unwrap();
-        up_thread.join().unwrap();
-        dn_thread.join().unwrap()
-    }
-
     std::thread::Builder::new()
         .name("vpn".into())
         .spawn(|| {
-            match CONNECT_CONFIG.vpn_mode {
-                Some(VpnMode::Stdio) => {
-                    // every packet is prepended with u16le length
-                    std::thread::spawn(|| {
-                        let mut stdin = BufReader::new(std::io::stdin().lock());
-                        // upload
-                        loop {
-                            let len = stdin.read_u16::<LittleEndian>().unwrap() as usize;
-                            let mut buffer = vec![0u8; len];
-                            stdin.read_exact(&mut buffer).unwrap();
-                            vpn_upload(buffer.into())
-                        }
-                    });
-                    // download
-                    let mut stdout = BufWriter::new(std::io::stdout().lock());
-                    loop {
-                        let down_pkt = vpn_download_blocking();
-                        stdout
-                            .write_u16::<LittleEndian>(down_pkt.len() as u16)
-                            .unwrap();
-                        stdout.write_all(&down_pkt).unwrap();
-                        stdout.flush().unwrap();
-                    }
+            let modes = CONNECT_CONFIG
+                .vpn_mode
+                .as_ref()
+                .map(|modes| modes.0.as_slice())
+                .unwrap_or_default();
+            for mode in modes.iter().map_while(|mode| *mode) {
+                log::info!("starting VPN mode {:?}", mode);
+                *ACTIVE_VPN_MODE.write() = Some(mode);
+                let Err(err) = run_vpn_mode(mode);
+                log::warn!("VPN mode {:?} failed: {:?}", mode, err);
+            }
+            *ACTIVE_VPN_MODE.write() = None;
+            log::info!("not starting VPN mode");
+            Lazy::force(&TUNNEL);
+            loop {
+                std::thread::park()
+            }
+        })
+        .unwrap()
+});
+
+/// The VPN mode that is running, which is the first of --vpn-mode that could be started.
+pub static ACTIVE_VPN_MODE: RwLock<Option<VpnMode>> = RwLock::new(None);
+
+/// Runs the VPN in the given mode, returning only if it cannot be started or dies.
+fn run_vpn_mode(mode: VpnMode) -> anyhow::Result<Infallible> {
+    match mode {
+        VpnMode::Stdio => {
+            // every packet is prepended with u16le length
+            std::thread::spawn(|| {
+                let mut stdin = BufReader::new(std::io::stdin().lock());
+                // upload
+                loop {
+                    let len = stdin.read_u16::<LittleEndian>().unwrap() as usize;
+                    let mut buffer = vec![0u8; len];
+                    stdin.read_exact(&mut buffer).unwrap();
+                    vpn_upload(buffer.into())
                 }
-                Some(VpnMode::InheritedFd) => {
-                    #[cfg(unix)]
-                    {
-                        // Read the file-descriptor number from an environment variable
-                        let fd_num: i32 = std::env::var("GEPH_VPN_FD")
-                    .ok()
-                     .and_then(|e| e.parse().ok())
-                    .expect(
-                    "must set GEPH_VPN_FD to a file descriptor in order to use inherited-fd mode",
-                    );
-                        unsafe { fd_vpn_loop(fd_num) }
-                    }
-                    #[cfg(not(unix))]
-                    {
-                        panic!("cannot use inherited-fd mode on non-Unix systems")
-                    }
+            });
+            // download
+            let mut stdout = BufWriter::new(std::io::stdout().lock());
+            loop {
+                let down_pkt = vpn_download_blocking();
+                stdout
+                    .write_u16::<LittleEndian>(down_pkt.len() as u16)
+                    .unwrap();
+                stdout.write_all(&down_pkt).unwrap();
+                stdout.flush().unwrap();
+            }
+        }
+        VpnMode::InheritedFd => {
+            #[cfg(unix)]
+            {
+                // Read the file-descriptor number from --vpn-fd, or else an environment variable
+                let fd_num: i32 = CONNECT_CONFIG
+                    .vpn_fd
+                    .or_else(|| {
+                        std::env::var("GEPH_VPN_FD")
+                            .ok()
+                            .and_then(|e| e.parse().ok())
+                    })
+                    .context(
+                        "must give --vpn-fd or set GEPH_VPN_FD to a file descriptor in order to use inherited-fd mode",
+                    )?;
+                if unsafe { libc::fcntl(fd_num, libc::F_GETFD) } == -1 {
+                    anyhow::bail!("file descriptor {fd_num} is not open")
                 }
-                Some(VpnMode::TunNoRoute | VpnMode::TunRoute) => {
-                    #[cfg(unix)]
+                unsafe { fd_vpn_loop(fd_num) }
+            }
+            #[cfg(not(unix))]
+            {
+                anyhow::bail!("cannot use inherited-fd mode on non-Unix systems")
+            }
+        }
+        VpnMode::TunNoRoute | VpnMode::TunRoute => {
+            #[cfg(unix)]
+            {
+                #[cfg(target_os = "macos")]
+                let device = {
+                    use tun::Device;
+                    let device = ::tun::platform::Device::new(
+                        ::tun::Configuration::default()
+                            .mtu(CONNECT_CONFIG.vpn_mtu as i32)
+                            .up(),
+                    )
+                    .context("could not initialize TUN device")?;
+                    std::process::Command::new("ifconfig")
+                        .arg(device.name())
+                        .arg("100.64.89.64")
+                        .arg("100.64.0.1")
+                        .spawn()
+                        .context("cannot ifconfig")?
+                        .wait()
+                        .context("cannot wait")?;
+                    setup_static_routes(device.name());
+                    device
+                };
+
+                #[cfg(not(target_os = "macos"))]
+                let device = ::tun::platform::Device::new(
+                    ::tun::Configuration::default()
+                        .name(&CONNECT_CONFIG.vpn_tun_name)
+                        .address("100.64.89.64")
+                        .netmask("255.255.255.0")
+                        .destination("100.64.0.1")
+                        .mtu(CONNECT_CONFIG.vpn_mtu as i32)
+                        .up(),
+                )
+                .context("could not initialize TUN device")?;
+                #[cfg(target_os = "linux")]
+                std::thread::spawn(setup_ipv6_address);
+                #[cfg(not(target_os = "macos"))]
+                setup_static_routes(&CONNECT_CONFIG.vpn_tun_name);
+                if mode == VpnMode::TunRoute {
+                    #[cfg(target_os = "linux")]
                     {
-                        #[cfg(target_os = "macos")]
-                        let device = {
-                            use tun::Device;
-                            let device = ::tun::platform::Device::new(
-                                ::tun::Configuration::default().mtu(16384).up(),
-                            )
-                            .expect("could not initialize TUN device");
-                            std::process::Command::new("ifconfig")
-                                .arg(device.name())
-                                .arg("100.64.89.64")
-                                .arg("100.64.0.1")
-                                .spawn()
-                                .expect("cannot ifconfig")
-                                .wait()
-                                .expect("cannot wait");
-                            device
-                        };
-
-                        #[cfg(not(target_os = "macos"))]
-                        let device = ::tun::platform::Device::new(
-                            ::tun::Configuration::default()
-                                .name("tun-geph")
-                                .address("100.64.89.64")
-                                .netmask("255.255.255.0")
-                                .destination("100.64.0.1")
-                                .mtu(16384)
-                                .up(),
-                        )
-                        .expect("could not initialize TUN device");
-                        if CONNECT_CONFIG.vpn_mode == Some(VpnMode::TunRoute) {
-                            #[cfg(target_os = "linux")]
-                            {
-                                linux_routing::setup_routing();
-                            }
-                            #[cfg(target_os = "macos")]
-                            {
-                                use tun::Device;
-                                macos_routing::setup_routing(device.name());
-                            }
-                        }
-                        unsafe { fd_vpn_loop(device.as_raw_fd()) }
+                        linux_routing::setup_routing();
                     }
-                    #[cfg(not(unix))]
+                    #[cfg(target_os = "macos")]
                     {
-                        panic!("cannot use tun modes on non-Unix systems")
+                        use tun::Device;
+                        macos_routing::setup_routing(device.name());
                     }
                 }
-                Some(VpnMode::WinDivert) => {
-                    #[cfg(windows)]
-                    {
-                        windows_routing::start_routing()
-                    }
+                unsafe { fd_vpn_loop(device.as_raw_fd()) }
+            }
+            #[cfg(not(unix))]
+            {
+                anyhow::bail!("cannot use tun modes on non-Unix systems")
+            }
+        }
+        VpnMode::WinDivert => {
+            #[cfg(windows)]
+            {
+                windows_routing::start_routing()
+            }
 
-                    #[cfg(not(windows))]
-                    {
-                        panic!("cannot use windivert mode outside windows")
-                    }
-                }
-                None => {
-                    log::info!("not starting VPN mode");
-                    Lazy::force(&TUNNEL);
-                    loop {
-                        std::thread::park()
-                    }
-                }
+            #[cfg(not(windows))]
+            {
+                anyhow::bail!("cannot use windivert mode outside windows")
+            }
+        }
+        VpnMode::Tproxy => {
+            #[cfg(target_os = "linux")]
+            {
+                linux_tproxy::start_tproxy()
+            }
+
+            #[cfg(not(target_os = "linux"))]
+            {
+                anyhow::bail!("cannot use tproxy mode outside Linux")
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+/// Runs the VPN on a particular file-descriptor number, returning only if one of its threads dies.
+unsafe fn fd_vpn_loop(fd_num: i32) -> anyhow::Result<Infallible> {
+    let mut up_file = std::fs::File::from_raw_fd(fd_num);
+    let mut down_file = std::fs::File::from_raw_fd(fd_num);
+    let up_thread = std::thread::Builder::new()
+        .name("vpn-up".into())
+        .spawn(move || {
+            let mut bts = [0u8; 65536];
+            loop {
+                let n = up_file.read(&mut bts).expect("vpn up thread failed");
+
+                let to_send = Bytes::copy_from_slice(&bts[..n]);
+                #[cfg(target_os = "macos")]
+                let to_send = if to_send.len() >= 4 {
+                    to_send.slice(4..)
+                } else {
+                    continue;
+                };
+                log::trace!("vpn up {}", to_send.len());
+                vpn_upload(to_send);
             }
         })
-        .unwrap()
+        .unwrap();
+    let dn_thread = std::thread::Builder::new()
+        .name("vpn-dn".into())
+        .spawn(move || loop {
+            let bts = vpn_download_blocking();
+            log::trace!("vpn dn {}", bts.len());
+            #[cfg(target_os = "macos")]
+            {
+                let mut buf = [0u8; 65536];
+                buf[4..][..bts.len()].copy_from_slice(&bts);
+                buf[3] = 0x02;
+                let _ = down_file.write(&buf[..bts.len() + 4]);
+            }
+            #[cfg(not(target_os = "macos"))]
+            let _ = down_file.write(&bts).unwrap();
+        })
+        .unwrap();
+    up_thread
+        .join()
+        .map_err(|_| anyhow::anyhow!("VPN upload thread died"))?;
+    dn_thread
+        .join()
+        .map_err(|_| anyhow::anyhow!("VPN download thread died"))
+}
+
+/// Routes the subnets given by --vpn-route-cidr through the TUN device.
+#[cfg(unix)]
+fn setup_static_routes(tun_name: &str) {
+    for cidr in CONNECT_CONFIG.vpn_route_cidr.iter() {
+        log::info!("routing {} through {}", cidr, tun_name);
+        #[cfg(target_os = "macos")]
+        let status = std::process::Command::new("route")
+            .args([
+                "-n",
+                "add",
+                "-net",
+                &cidr.to_string(),
+                "-interface",
+                tun_name,
+            ])
+            .status();
+        #[cfg(not(target_os = "macos"))]
+        let status = std::process::Command::new("ip")
+            .args(["route", "replace", &cidr.to_string(), "dev", tun_name])
+            .status();
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => log::warn!("adding route to {} failed: {}", cidr, status),
+            Err(err) => log::warn!("cannot add route to {}: {:?}", cidr, err),
+        }
+    }
+}
+
+/// The private IPv6 address given to the TUN device when IPv6 is carried through the VPN.
+#[cfg(target_os = "linux")]
+const VPN_IPV6_ADDR: &str = "fd64:6765:7068::2/64";
+
+/// Whether or not IPv6 is carried through the VPN. With "auto", this blocks until the exit's IPv6 connectivity is probed.
+pub(crate) static VPN_IPV6: Lazy<bool> = Lazy::new(|| match CONNECT_CONFIG.vpn_ipv6 {
+    VpnIpv6::On => true,
+    VpnIpv6::Off => false,
+    VpnIpv6::Auto => {
+        let supported = smol::future::block_on(probe_remote_ipv6());
+        if supported {
+            log::info!("exit has IPv6 connectivity, carrying IPv6 through the VPN");
+        } else {
+            log::info!("exit is IPv4-only, not carrying IPv6 through the VPN");
+        }
+        supported
+    }
 });
 
+#[cfg(target_os = "linux")]
+fn setup_ipv6_address() {
+    if *VPN_IPV6 {
+        std::process::Command::new("ip")
+            .args([
+                "-6",
+                "addr",
+                "add",
+                VPN_IPV6_ADDR,
+                "dev",
+                &CONNECT_CONFIG.vpn_tun_name,
+            ])
+            .status()
+            .expect("could not assign IPv6 address to TUN device");
+    }
+}
+
+fn is_ipv6(pkt: &[u8]) -> bool {
+    pkt.first().map(|b| b >> 4) == Some(6)
+}
+
 /// Uploads a packet through the global VPN
 pub fn vpn_upload(pkt: Bytes) {
     Lazy::force(&VPN_TASK);
     STATS_SEND_BYTES.fetch_add(pkt.len() as u64, Ordering::Relaxed);
-    let _ = UP_CHANNEL.0.try_send(pkt);
+    if let Err(pkt) = try_enqueue(&UP_CHANNEL, pkt) {
+        let _ = UP_CHANNEL.0.send(pkt);
+    }
 }
 
 /// Downloads a packet through the global VPN
@@ -231,10 +361,34 @@ pub fn vpn_download_blocking() -> Bytes {
 }
 
 // Up and down channels
-static UP_CHANNEL: Lazy<(flume::Sender<Bytes>, flume::Receiver<Bytes>)> =
-    Lazy::new(|| flume::bounded(10000));
-static DOWN_CHANNEL: Lazy<(flume::Sender<Bytes>, flume::Receiver<Bytes>)> =
-    Lazy::new(|| flume::bounded(10000));
+type PacketQueue = (flume::Sender<Bytes>, flume::Receiver<Bytes>);
+static UP_CHANNEL: Lazy<PacketQueue> =
+    Lazy::new(|| flume::bounded(CONNECT_CONFIG.vpn_queue_depth.max(1)));
+static DOWN_CHANNEL: Lazy<PacketQueue> =
+    Lazy::new(|| flume::bounded(CONNECT_CONFIG.vpn_queue_depth.max(1)));
+
+/// Queues a packet without waiting, dropping a packet if the queue is full, as --vpn-queue-overflow says. With "block", a packet that doesn't fit is handed back, for the caller to wait for room.
+fn try_enqueue(queue: &PacketQueue, pkt: Bytes) -> Result<(), Bytes> {
+    match queue.0.try_send(pkt) {
+        Err(flume::TrySendError::Full(pkt)) => match CONNECT_CONFIG.vpn_queue_overflow {
+            VpnQueueOverflow::DropNewest => Ok(()),
+            VpnQueueOverflow::DropOldest => {
+                let _ = queue.1.try_recv();
+                let _ = queue.0.try_send(pkt);
+                Ok(())
+            }
+            VpnQueueOverflow::Block => Err(pkt),
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Queues a packet coming down from the tunnel.
+async fn enqueue_down(pkt: Bytes) {
+    if let Err(pkt) = try_enqueue(&DOWN_CHANNEL, pkt) {
+        let _ = DOWN_CHANNEL.0.send_async(pkt).await;
+    }
+}
 
 static VPN_TASK: Lazy<std::thread::JoinHandle<()>> = Lazy::new(|| {
     std::thread::spawn(|| {
@@ -242,21 +396,31 @@ static VPN_TASK: Lazy<std::thread::JoinHandle<()>> = Lazy::new(|| {
             smol::future::block_on(async {
                 loop {
                     log::info!("VPN task about to get client IP...");
-                    let init_ip = TUNNEL.get_vpn_client_ip().await;
+                    let tunnel = active_tunnel();
+                    let init_ip = tunnel.get_vpn_client_ip().await;
                     log::info!("VPN task initializing IP to {init_ip}");
                     let nat = Arc::new(GephNat::new(NAT_TABLE_SIZE, init_ip));
                     let ip_change_fut = async move {
                         loop {
-                            let i = TUNNEL.get_vpn_client_ip().await;
+                            let i = tunnel.get_vpn_client_ip().await;
                             if i != init_ip {
                                 anyhow::bail!("new IP: {i}")
                             }
                             smol::Timer::after(Duration::from_secs(5)).await;
                         }
                     };
-                    let res = vpn_up_loop(nat.clone())
-                        .or(vpn_down_loop(nat))
+                    let failover_fut = async move {
+                        loop {
+                            if !std::ptr::eq(active_tunnel(), tunnel) {
+                                anyhow::bail!("switching to another tunnel")
+                            }
+                            smol::Timer::after(Duration::from_millis(200)).await;
+                        }
+                    };
+                    let res = vpn_up_loop(tunnel, nat.clone())
+                        .or(vpn_down_loop(tunnel, nat))
                         .or(ip_change_fut)
+                        .or(failover_fut)
                         .await;
                     log::warn!("vpn loops somehow died: {:?}", res);
                 }
@@ -271,14 +435,28 @@ static VPN_TASK: Lazy<std::thread::JoinHandle<()>> = Lazy::new(|| {
 const NAT_TABLE_SIZE: usize = 10000; // max size of the NAT table
 
 /// Up loop for vpn
-async fn vpn_up_loop(nat: Arc<GephNat>) -> anyhow::Result<()> {
+async fn vpn_up_loop(tunnel: &ClientTunnel, nat: Arc<GephNat>) -> anyhow::Result<()> {
     let limiter = RateLimiter::direct(
         Quota::per_second(NonZeroU32::new(500u32).unwrap())
             .allow_burst(NonZeroU32::new(100u32).unwrap()),
     );
     loop {
         let mut bts = UP_CHANNEL.1.recv_async().await.unwrap().to_vec();
+        if is_ipv6(&bts) {
+            // IPv6 packets bypass the (IPv4-only) NAT and are dropped outright if the VPN doesn't carry IPv6
+            if *VPN_IPV6 {
+                tunnel.send_vpn(bts.into()).await?
+            }
+            continue;
+        }
+        if CONNECT_CONFIG.vpn_dns_proxy && intercept_dns(&bts) {
+            continue;
+        }
         mangle_dns_up(&mut bts);
+        if mtu::clamp_mss(&mut bts, mtu::max_mss()) {
+            fix_all_checksums(&mut bts);
+        }
+        MTU_WATCH.observe_upload(&bts);
         // ACK decimation
         if ack_decimate(&bts).is_some() && limiter.check().is_err() {
             log::trace!("doing ack decimation!");
@@ -286,12 +464,64 @@ async fn vpn_up_loop(nat: Arc<GephNat>) -> anyhow::Result<()> {
             let mangled_msg = nat.mangle_upstream_pkt(&bts);
 
             if let Some(body) = mangled_msg {
-                TUNNEL.send_vpn(body).await?
+                tunnel.send_vpn(body).await?
             };
         }
     }
 }
 
+/// If the packet is a UDP DNS query, answers it in the background through the local DNS proxy and returns true.
+fn intercept_dns(pkt: &[u8]) -> bool {
+    let ip_pkt = match Ipv4Packet::new(pkt) {
+        Some(ip_pkt) if ip_pkt.get_next_level_protocol() == IpNextHeaderProtocols::Udp => ip_pkt,
+        _ => return false,
+    };
+    let udp_pkt = match UdpPacket::new(ip_pkt.payload()) {
+        Some(udp_pkt) if udp_pkt.get_destination() == 53 => udp_pkt,
+        _ => return false,
+    };
+    let client = SocketAddrV4::new(ip_pkt.get_source(), udp_pkt.get_source());
+    let server = SocketAddrV4::new(ip_pkt.get_destination(), 53);
+    let query = udp_pkt.payload().to_vec();
+    smolscale::spawn(async move {
+        match local_request(&query).await {
+            Ok(response) => match udp_packet(server, client, &response) {
+                Some(pkt) => {
+                    enqueue_down(pkt.into()).await;
+                }
+                None => log::debug!("DNS response too large for {client}"),
+            },
+            Err(err) => log::debug!("DNS query from {client} failed: {:?}", err),
+        }
+    })
+    .detach();
+    true
+}
+
+/// Builds an IPv4 UDP packet with the given payload.
+fn udp_packet(source: SocketAddrV4, destination: SocketAddrV4, payload: &[u8]) -> Option<Vec<u8>> {
+    const HEADERS_LEN: usize = 20 + 8;
+    let total_len = u16::try_from(HEADERS_LEN + payload.len()).ok()?;
+    let mut bts = vec![0u8; total_len as usize];
+    {
+        let mut ip_pkt = MutableIpv4Packet::new(&mut bts)?;
+        ip_pkt.set_version(4);
+        ip_pkt.set_header_length(5);
+        ip_pkt.set_total_length(total_len);
+        ip_pkt.set_ttl(64);
+        ip_pkt.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        ip_pkt.set_source(*source.ip());
+        ip_pkt.set_destination(*destination.ip());
+        let mut udp_pkt = MutableUdpPacket::new(ip_pkt.payload_mut())?;
+        udp_pkt.set_source(source.port());
+        udp_pkt.set_destination(destination.port());
+        udp_pkt.set_length(total_len - 20);
+        udp_pkt.set_payload(payload);
+    }
+    fix_all_checksums(&mut bts)?;
+    Some(bts)
+}
+
 static FAKE_DNS_SERVER: AtomicU32 = AtomicU32::new(0);
 static REAL_DNS_SERVER: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
 
@@ -362,14 +592,23 @@ fn fix_all_checksums(bts: &mut [u8]) -> Option<()> {
 }
 
 /// Down loop for vpn
-async fn vpn_down_loop(nat: Arc<GephNat>) -> anyhow::Result<()> {
+async fn vpn_down_loop(tunnel: &ClientTunnel, nat: Arc<GephNat>) -> anyhow::Result<()> {
     loop {
-        let incoming = TUNNEL.recv_vpn().await.context("downstream failed")?;
+        let incoming = tunnel.recv_vpn().await.context("downstream failed")?;
+        if is_ipv6(&incoming) {
+            if *VPN_IPV6 {
+                enqueue_down(incoming).await;
+            }
+            continue;
+        }
         let mangled_incoming = nat.mangle_downstream_pkt(&incoming);
         if let Some(mangled_bts) = mangled_incoming {
             let mut mangled_bts = mangled_bts.to_vec();
             mangle_dns_dn(&mut mangled_bts);
-            let _ = DOWN_CHANNEL.0.try_send(mangled_bts.into());
+            if mtu::clamp_mss(&mut mangled_bts, mtu::max_mss()) {
+                fix_all_checksums(&mut mangled_bts);
+            }
+            enqueue_down(mangled_bts.into()).await;
         }
     }
 }
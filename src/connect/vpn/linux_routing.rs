@@ -7,7 +7,9 @@ use once_cell::sync::Lazy;
 use signal_hook::iterator::Signals;
 use std::net::{IpAddr, Ipv4Addr};
 
-use crate::connect::{CONNECT_CONFIG, TUNNEL, TUNNEL_STATUS_CALLBACK};
+use crate::connect::{listen::DNS_LISTEN, CONNECT_CONFIG, TUNNEL, TUNNEL_STATUS_CALLBACK};
+
+use super::VPN_IPV6;
 
 struct SingleWhitelister {
     dest: IpAddr,
@@ -60,10 +62,11 @@ pub fn setup_routing() {
         }
 
         // set the DNS server
-        let mut dns_listen = CONNECT_CONFIG.dns_listen;
+        let mut dns_listen = *DNS_LISTEN;
         dns_listen.set_ip(Ipv4Addr::new(127, 0, 0, 1).into());
         std::env::set_var("GEPH_DNS", dns_listen.to_string());
-        let cmd = include_str!("linux_routing_setup.sh");
+        std::env::set_var("GEPH_TUN", &CONNECT_CONFIG.vpn_tun_name);
+        let cmd = routing_script();
         let mut child = Command::new("sh").arg("-c").arg(cmd).spawn().unwrap();
         child.wait().expect("iptables was not set up properly");
         unsafe {
@@ -72,17 +75,51 @@ pub fn setup_routing() {
         // teardown process
         let mut signals = Signals::new([libc::SIGABRT, libc::SIGTERM, libc::SIGINT])
             .expect("did not register signal handler properly");
-        for _ in signals.forever() {
+        if signals.forever().next().is_some() {
             teardown_routing();
             std::process::exit(-1)
         }
     });
 }
 
+/// The routing setup script, which either routes or blocks IPv6 depending on whether the VPN carries it.
+fn routing_script() -> String {
+    let ipv6_part = if *VPN_IPV6 {
+        include_str!("linux_routing_setup_ipv6.sh")
+    } else {
+        include_str!("linux_routing_block_ipv6.sh")
+    };
+    let script = format!("{}{}", include_str!("linux_routing_setup.sh"), ipv6_part);
+    restrict_to_uids(&script, &CONNECT_CONFIG.tunnel_uid)
+}
+
+/// With --tunnel-uid, rewrites the routing script so that only the given users' traffic is routed into the tunnel, has its DNS redirected, and has its IPv6 blocked.
+fn restrict_to_uids(script: &str, uids: &[u32]) -> String {
+    if uids.is_empty() {
+        return script.to_string();
+    }
+    script
+        .lines()
+        .flat_map(|line| {
+            if line.contains("to all lookup 8964") {
+                uids.iter()
+                    .map(|uid| line.replace("to all", &format!("uidrange {uid}-{uid}")))
+                    .collect()
+            } else if line.contains("-j DNAT") || line.contains("-j REJECT") {
+                uids.iter()
+                    .map(|uid| line.replace("-j ", &format!("-m owner --uid-owner {uid} -j ")))
+                    .collect()
+            } else {
+                vec![line.to_string()]
+            }
+        })
+        .join("\n")
+}
+
 extern "C" fn teardown_routing() {
     log::debug!("teardown_routing starting!");
     WHITELIST.clear();
-    let cmd = include_str!("linux_routing_setup.sh")
+    let cmd = routing_script()
         .lines()
         .filter(|l| l.contains("-D") || l.contains("del") || l.contains("flush"))
         .join("\n");
@@ -0,0 +1,5 @@
+# block ipv6 completely
+ip6tables -D OUTPUT -o lo -j ACCEPT
+ip6tables -A OUTPUT -o lo -j ACCEPT
+ip6tables -D OUTPUT  -j REJECT
+ip6tables -A OUTPUT  -j REJECT
@@ -1,6 +1,6 @@
 export PATH=$PATH:/usr/sbin/:/sbin/
 ip route flush table 8964
-ip route add default dev tun-geph table 8964
+ip route add default dev $GEPH_TUN table 8964
 # ip rule del not fwmark 8964 table 8964
 # ip rule add not fwmark 8964 table 8964
 ip rule del table main suppress_prefixlength 0
@@ -18,19 +18,14 @@ iptables -t nat -A OUTPUT -p tcp --dport 53 -j DNAT --to $GEPH_DNS
 # iptables -A OUTPUT -t mangle -d 10.0.0.0/8,172.16.0.0/12,192.168.0.0/16 -j MARK --set-mark 11111
 # # set up routing tables
 # ip route flush table 8964
-# ip route add default dev tun-geph table 8964
+# ip route add default dev $GEPH_TUN table 8964
 # ip rule del fwmark 8964 table 8964
 # ip rule add fwmark 8964 table 8964
 # # mangle
-# iptables -t nat -D POSTROUTING -o tun-geph -j MASQUERADE
-# iptables -t nat -A POSTROUTING -o tun-geph -j MASQUERADE
+# iptables -t nat -D POSTROUTING -o $GEPH_TUN -j MASQUERADE
+# iptables -t nat -A POSTROUTING -o $GEPH_TUN -j MASQUERADE
 # # redirect DNS
 
 # # clamp MTU
 # iptables -t mangle -D OUTPUT -p tcp --tcp-flags SYN,RST SYN -j TCPMSS --set-mss 1240
 # iptables -t mangle -A OUTPUT -p tcp --tcp-flags SYN,RST SYN -j TCPMSS --set-mss 1240
-# block ipv6 completely
-ip6tables -D OUTPUT -o lo -j ACCEPT
-ip6tables -A OUTPUT -o lo -j ACCEPT
-ip6tables -D OUTPUT  -j REJECT
-ip6tables -A OUTPUT  -j REJECT
\ No newline at end of file
@@ -0,0 +1,7 @@
+# route ipv6 through the tunnel too
+ip -6 route flush table 8964
+ip -6 route add default dev $GEPH_TUN table 8964
+ip -6 rule del table main suppress_prefixlength 0
+ip -6 rule add table main suppress_prefixlength 0
+ip -6 rule del to all lookup 8964 pref 2
+ip -6 rule add to all lookup 8964 pref 2
@@ -0,0 +1,108 @@
+use std::{
+    convert::Infallible, os::unix::prelude::AsRawFd, sync::atomic::Ordering, time::Duration,
+};
+
+use anyhow::Context;
+use smol_timeout::TimeoutExt;
+
+use crate::connect::{
+    active_tunnel,
+    keepalive::apply_tcp_keepalive,
+    relay::relay_proxied,
+    stats::{STATS_RECV_BYTES, STATS_SEND_BYTES},
+    tunnel::activity::notify_activity,
+    CONNECT_CONFIG,
+};
+
+/// Runs the TPROXY mode, accepting TCP connections redirected by iptables TPROXY rules and feeding them into the tunnel.
+///
+/// The iptables rules themselves are up to the user. For example, with the default listening address:
+///
+/// ```text
+/// ip rule add fwmark 1 lookup 100
+/// ip route add local 0.0.0.0/0 dev lo table 100
+/// iptables -t mangle -A PREROUTING -p tcp -j TPROXY --on-port 9950 --tproxy-mark 1
+/// ```
+pub fn start_tproxy() -> anyhow::Result<Infallible> {
+    smol::future::block_on(async {
+        let listener = transparent_listener().context("could not listen for TPROXY connections")?;
+        log::info!("TPROXY listening on {}", CONNECT_CONFIG.tproxy_listen);
+        loop {
+            let conn = match listener.accept().await {
+                Ok((conn, _)) => conn,
+                Err(err) => {
+                    // e.g. running out of file descriptors, which passes once connections close
+                    warn_limited!("could not accept TPROXY connection: {:?}", err);
+                    smol::Timer::after(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            smolscale::spawn(async move {
+                if let Err(err) = handle_tproxy(conn).await {
+                    log::debug!("TPROXY connection failed: {:?}", err);
+                }
+            })
+            .detach();
+        }
+    })
+}
+
+/// Creates a listener with IP_TRANSPARENT set, so that it can accept connections addressed to anywhere.
+fn transparent_listener() -> anyhow::Result<smol::net::TcpListener> {
+    let listen = CONNECT_CONFIG.tproxy_listen;
+    let socket = socket2::Socket::new(
+        if listen.is_ipv4() {
+            socket2::Domain::ipv4()
+        } else {
+            socket2::Domain::ipv6()
+        },
+        socket2::Type::stream(),
+        Some(socket2::Protocol::tcp()),
+    )?;
+    let one: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_IP,
+            libc::IP_TRANSPARENT,
+            &one as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of_val(&one) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error())
+            .context("could not set IP_TRANSPARENT (are we running as root?)");
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&listen.into())?;
+    socket.listen(1024)?;
+    let listener = socket.into_tcp_listener();
+    listener.set_nonblocking(true)?;
+    Ok(smol::net::TcpListener::try_from(listener)?)
+}
+
+async fn handle_tproxy(conn: smol::net::TcpStream) -> anyhow::Result<()> {
+    apply_tcp_keepalive(&conn);
+    // with TPROXY, the local address of the accepted connection is the original destination
+    let dest = conn.local_addr()?;
+    log::debug!("TPROXY connection to {}", dest);
+    let remote = active_tunnel()
+        .connect_stream(&dest.to_string())
+        .timeout(Duration::from_secs(120))
+        .await
+        .context("open connection timeout")??;
+    relay_proxied(
+        conn,
+        remote,
+        |n| {
+            STATS_SEND_BYTES.fetch_add(n as u64, Ordering::Relaxed);
+            notify_activity();
+        },
+        |n| {
+            STATS_RECV_BYTES.fetch_add(n as u64, Ordering::Relaxed);
+            notify_activity();
+        },
+    )
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,166 @@
+use std::time::{Duration, Instant};
+
+use moka::sync::{Cache, CacheBuilder};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use pnet_packet::{
+    ip::IpNextHeaderProtocols,
+    ipv4::{Ipv4Packet, MutableIpv4Packet},
+    tcp::{MutableTcpPacket, TcpFlags, TcpPacket},
+    MutablePacket, Packet,
+};
+
+use crate::connect::CONNECT_CONFIG;
+
+/// Packets larger than this are the ones that get lost on paths with a PMTU blackhole.
+const LARGE_PACKET: usize = 1280;
+
+/// How long an uploaded segment is remembered, for noticing that it was retransmitted.
+const SEGMENT_MEMORY: Duration = Duration::from_secs(10);
+
+/// How often retransmission rates are evaluated.
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// The minimum number of retransmitted large segments in a window to suspect a blackhole.
+const MIN_LARGE_RETRANSMITS: u64 = 20;
+
+/// Watches uploaded TCP segments for the signature of a PMTU blackhole: large segments being retransmitted much more often than small ones.
+pub struct MtuWatch {
+    seen: Cache<(u32, u16, u16, u32, usize), ()>,
+    window: Mutex<RetransmitWindow>,
+}
+
+struct RetransmitWindow {
+    start: Instant,
+    large_sent: u64,
+    large_retransmitted: u64,
+    small_sent: u64,
+    small_retransmitted: u64,
+}
+
+impl RetransmitWindow {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            large_sent: 0,
+            large_retransmitted: 0,
+            small_sent: 0,
+            small_retransmitted: 0,
+        }
+    }
+
+    /// Whether this window looks like large packets are being dropped.
+    fn looks_like_blackhole(&self) -> bool {
+        let large_loss = self.large_retransmitted as f64 / self.large_sent.max(1) as f64;
+        let small_loss = self.small_retransmitted as f64 / self.small_sent.max(1) as f64;
+        self.large_retransmitted >= MIN_LARGE_RETRANSMITS
+            && large_loss > 0.2
+            && large_loss > small_loss * 5.0
+    }
+}
+
+pub static MTU_WATCH: Lazy<MtuWatch> = Lazy::new(|| MtuWatch {
+    seen: CacheBuilder::new(10000)
+        .time_to_live(SEGMENT_MEMORY)
+        .build(),
+    window: Mutex::new(RetransmitWindow::new()),
+});
+
+impl MtuWatch {
+    /// Looks at an IPv4 packet going up the VPN.
+    pub fn observe_upload(&self, pkt: &[u8]) {
+        let segment = match parse_data_segment(pkt) {
+            Some(segment) => segment,
+            None => return,
+        };
+        let retransmitted = self.seen.contains_key(&segment);
+        self.seen.insert(segment, ());
+
+        let mut window = self.window.lock();
+        if pkt.len() > LARGE_PACKET {
+            window.large_sent += 1;
+            window.large_retransmitted += retransmitted as u64;
+        } else {
+            window.small_sent += 1;
+            window.small_retransmitted += retransmitted as u64;
+        }
+        if window.start.elapsed() >= WINDOW {
+            if window.looks_like_blackhole() {
+                warn_limited!(
+                    "{} of {} large packets needed retransmission, compared to {} of {} small packets. The path to the bridge probably drops large packets; try a lower --vpn-mtu, such as 1280",
+                    window.large_retransmitted,
+                    window.large_sent,
+                    window.small_retransmitted,
+                    window.small_sent
+                );
+            }
+            *window = RetransmitWindow::new();
+        }
+    }
+}
+
+/// Identifies a TCP segment carrying data by its destination, ports, sequence number, and length.
+fn parse_data_segment(pkt: &[u8]) -> Option<(u32, u16, u16, u32, usize)> {
+    let ip = Ipv4Packet::new(pkt)?;
+    if ip.get_next_level_protocol() != IpNextHeaderProtocols::Tcp {
+        return None;
+    }
+    let tcp = TcpPacket::new(ip.payload())?;
+    if tcp.payload().is_empty() {
+        return None;
+    }
+    Some((
+        ip.get_destination().into(),
+        tcp.get_source(),
+        tcp.get_destination(),
+        tcp.get_sequence(),
+        tcp.payload().len(),
+    ))
+}
+
+/// The largest TCP MSS that fits in the VPN MTU.
+pub fn max_mss() -> u16 {
+    CONNECT_CONFIG.vpn_mtu.saturating_sub(40)
+}
+
+/// Lowers the MSS option of an IPv4 TCP SYN packet to at most the given value, so that neither side sends segments larger than the VPN MTU. Returns whether the packet was changed, in which case its checksums must be fixed.
+pub fn clamp_mss(pkt: &mut [u8], max_mss: u16) -> bool {
+    let mut ip = match MutableIpv4Packet::new(pkt) {
+        Some(ip) if ip.get_next_level_protocol() == IpNextHeaderProtocols::Tcp => ip,
+        _ => return false,
+    };
+    let mut tcp = match MutableTcpPacket::new(ip.payload_mut()) {
+        Some(tcp) if tcp.get_flags() & TcpFlags::SYN != 0 => tcp,
+        _ => return false,
+    };
+    let header_len = (tcp.get_data_offset() as usize * 4).min(tcp.packet().len());
+    let options = match tcp.packet_mut().get_mut(20..header_len) {
+        Some(options) => options,
+        None => return false,
+    };
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            // end of options
+            0 => break,
+            // no-op
+            1 => i += 1,
+            kind => {
+                let len = match options.get(i + 1) {
+                    Some(&len) if len >= 2 => len as usize,
+                    _ => break,
+                };
+                if kind == 2 && len == 4 && i + 4 <= options.len() {
+                    let mss = u16::from_be_bytes([options[i + 2], options[i + 3]]);
+                    if mss > max_mss {
+                        options[i + 2..i + 4].copy_from_slice(&max_mss.to_be_bytes());
+                        return true;
+                    }
+                    return false;
+                }
+                i += len;
+            }
+        }
+    }
+    false
+}
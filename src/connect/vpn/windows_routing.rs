@@ -17,7 +17,10 @@ mod windivert;
 
 static GEPH_OWN_ADDRS: Lazy<DashSet<IpAddr>> = Lazy::new(DashSet::new);
 
-pub fn start_routing() -> Infallible {
+pub fn start_routing() -> anyhow::Result<Infallible> {
+    // opening a handle up front tells us whether WinDivert is usable at all
+    let download_handle = windivert::PacketHandle::open("false", -200)
+        .map_err(|err| anyhow::anyhow!("cannot open WinDivert: {:?}", err))?;
     *TUNNEL_STATUS_CALLBACK.write() = Box::new(move |addr| {
         if let TunnelStatus::PreConnect { addr, protocol: _ } = addr {
             let addr = addr.ip();
@@ -32,14 +35,13 @@ pub fn start_routing() -> Infallible {
     }
 
     std::thread::spawn(upload_loop);
-    download_loop()
+    Ok(download_loop(download_handle))
 }
 
 static FAKE_DNS_SERVER: AtomicU32 = AtomicU32::new(0);
 static REAL_DNS_SERVER: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
 
-fn download_loop() -> Infallible {
-    let handle = windivert::PacketHandle::open("false", -200).unwrap();
+fn download_loop(handle: windivert::PacketHandle) -> Infallible {
     loop {
         let mut pkt = vpn_download_blocking().to_vec();
         let mut mangled = false;
@@ -1,7 +1,9 @@
 use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
     path::Path,
     sync::Arc,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use acidjson::AcidJson;
@@ -11,21 +13,57 @@ use bytes::Bytes;
 use futures_util::join;
 use geph4_protocol::binder::protocol::{
     AuthError, AuthRequestV2, AuthResponseV2, BinderClient, BlindToken, BridgeDescriptor,
-    Credentials, Level, MasterSummary, UserInfoV2,
+    Credentials, ExitDescriptor, Level, MasterSummary, UserInfoV2,
 };
 use melprot::NodeRpcClient;
 use moka::sync::{Cache, CacheBuilder};
 use nanorpc::{JrpcRequest, JrpcResponse, RpcTransport};
+use parking_lot::Mutex;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 
 use stdcode::StdcodeSerializeExt;
 use tmelcrypt::{HashVal, Hashable};
 
+use crate::fronts::BinderMaintenance;
+
 const TOKEN_STALE_SECS: u64 = 86400;
 const SUMMARY_STALE_SECS: u64 = 3600;
 const BRIDGE_STALE_SECS: u64 = 600;
 
+/// How quickly a bridge score "forgets" old observations and drifts back to neutral.
+const BRIDGE_SCORE_HALF_LIFE_SECS: f64 = 3.0 * 86400.0;
+/// Scores that haven't been updated in this long are dropped entirely.
+const BRIDGE_SCORE_EXPIRE_SECS: u64 = 30 * 86400;
+/// Weight given to each new observation in the moving averages.
+const BRIDGE_SCORE_ALPHA: f64 = 0.3;
+/// Quality of a bridge we know nothing about.
+const BRIDGE_SCORE_NEUTRAL: f64 = 0.5;
+
+/// What went wrong opening or refreshing a [ConnInfoStore], which decides whether it is worth trying again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StoreErrorKind {
+    /// The binder rejected the credentials. Retrying won't help.
+    Auth,
+    /// The binder is in maintenance, and should be left alone for a while.
+    Maintenance(BinderMaintenance),
+    /// Anything else, like the binder being unreachable, which may well go away by itself.
+    Transient,
+}
+
+impl StoreErrorKind {
+    /// Classifies an error from [ConnInfoStore::connect] or [ConnInfoStore::refresh].
+    pub fn of(err: &anyhow::Error) -> Self {
+        if let Some(maintenance) = err.downcast_ref::<BinderMaintenance>() {
+            Self::Maintenance(*maintenance)
+        } else if let Some(AuthError::InvalidCredentials) = err.downcast_ref::<AuthError>() {
+            Self::Auth
+        } else {
+            Self::Transient
+        }
+    }
+}
+
 /// Persistent storage for connection info, asynchronously refreshed.
 pub struct ConnInfoStore {
     inner: AcidJson<ConnInfoInner>,
@@ -36,6 +74,12 @@ pub struct ConnInfoStore {
     exit_host: String,
 
     get_creds: Box<dyn Fn() -> Credentials + Send + Sync + 'static>,
+
+    /// Cached data older than this many seconds is refreshed before use, regardless of the usual staleness thresholds.
+    max_age_secs: Option<u64>,
+
+    /// The maintenance the binder last reported, and until when it is left alone because of it.
+    maintenance: Mutex<Option<(BinderMaintenance, Instant)>>,
 }
 
 impl ConnInfoStore {
@@ -46,6 +90,7 @@ impl ConnInfoStore {
         mizaru_free: mizaru::PublicKey,
         mizaru_plus: mizaru::PublicKey,
         exit_host: &str,
+        max_age_secs: Option<u64>,
         get_creds: impl Fn() -> Credentials + Send + Sync + 'static,
     ) -> anyhow::Result<Self> {
         log::debug!("constructing a conninfo store!");
@@ -69,6 +114,8 @@ impl ConnInfoStore {
                 bad_countries: vec![],
             },
             summary_refresh_unix: 0,
+            bridge_scores: BTreeMap::new(),
+            sticky_bridges: vec![],
         })?;
         let cached_exit = inner.read().cached_exit.clone();
         let toret = Self {
@@ -82,6 +129,8 @@ impl ConnInfoStore {
                 exit_host.to_owned()
             },
             get_creds: Box::new(get_creds),
+            max_age_secs,
+            maintenance: Mutex::new(None),
         };
 
         // only force a refresh here if the *token* is stale, because that is a hard error. other things being stale are totally fine.
@@ -91,7 +140,8 @@ impl ConnInfoStore {
             .as_secs();
         let must_refresh = (current_unix + 100
             > toret.inner.read().token_refresh_unix + TOKEN_STALE_SECS)
-            || toret.inner.read().cached_exit.as_str() != exit_host;
+            || toret.inner.read().cached_exit.as_str() != exit_host
+            || toret.is_expired(current_unix);
         if must_refresh {
             log::debug!("blocking on construct because token is stale");
             toret.refresh().await?;
@@ -100,7 +150,36 @@ impl ConnInfoStore {
     }
 
     /// Refreshes the whole store. This should generally be called in a background task.
+    ///
+    /// While the binder is in maintenance, this fails right away with [BinderMaintenance] instead of contacting it.
     pub async fn refresh(&self) -> anyhow::Result<()> {
+        if let Some(maintenance) = self.maintenance() {
+            return Err(maintenance.into());
+        }
+        let result = self.refresh_inner().await;
+        if let Err(err) = &result {
+            if let Some(maintenance) = err.downcast_ref::<BinderMaintenance>() {
+                let delay = maintenance.retry_delay();
+                log::warn!("{maintenance}, so not contacting it for {:?}", delay);
+                *self.maintenance.lock() = Some((*maintenance, Instant::now() + delay));
+            }
+        }
+        result
+    }
+
+    /// The maintenance the binder is in, if it reported one that is not yet over.
+    pub fn maintenance(&self) -> Option<BinderMaintenance> {
+        let mut maintenance = self.maintenance.lock();
+        match *maintenance {
+            Some((info, retry_at)) if Instant::now() < retry_at => Some(info),
+            _ => {
+                *maintenance = None;
+                None
+            }
+        }
+    }
+
+    async fn refresh_inner(&self) -> anyhow::Result<()> {
         let current_unix = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
@@ -108,7 +187,7 @@ impl ConnInfoStore {
         // refresh master summary
         let summary_refresh_unix = self.inner.read().summary_refresh_unix;
         let summary_fut = async {
-            if current_unix > summary_refresh_unix + SUMMARY_STALE_SECS {
+            if current_unix > summary_refresh_unix + self.stale_secs(SUMMARY_STALE_SECS) {
                 log::debug!("summary stale so refreshing summary");
                 let summary = self.get_verified_summary().await?;
                 let mut inner = self.inner.write();
@@ -127,7 +206,7 @@ impl ConnInfoStore {
                 "current user info == remote user info?: {}",
                 current_user_info == remote_user_info
             );
-            if current_unix > token_refresh_unix + TOKEN_STALE_SECS * 2 / 3
+            if current_unix > token_refresh_unix + self.stale_secs(TOKEN_STALE_SECS * 2 / 3)
                 || current_user_info != remote_user_info
             {
                 log::debug!("token stale so refreshing token");
@@ -145,7 +224,7 @@ impl ConnInfoStore {
         let cached_exit = self.inner.read().cached_exit.clone();
 
         let bridge_fut = async {
-            if current_unix > bridge_refresh_unix + BRIDGE_STALE_SECS
+            if current_unix > bridge_refresh_unix + self.stale_secs(BRIDGE_STALE_SECS)
                 || cached_exit != self.exit_host
             {
                 log::debug!("bridges stale so refreshing bridges");
@@ -173,11 +252,100 @@ impl ConnInfoStore {
         Ok(())
     }
 
+    /// Refreshes the store right away if any of the cached data is older than the configured maximum age. Call this before using cached data to guarantee its freshness.
+    pub async fn refresh_if_expired(&self) -> anyhow::Result<()> {
+        let current_unix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if self.is_expired(current_unix) {
+            log::debug!("cached conninfo older than the maximum age, so refreshing");
+            self.refresh().await?;
+        }
+        Ok(())
+    }
+
+    /// Whether any of the cached data is older than the configured maximum age.
+    fn is_expired(&self, current_unix: u64) -> bool {
+        let Some(max_age) = self.max_age_secs else {
+            return false;
+        };
+        let inner = self.inner.read();
+        [
+            inner.summary_refresh_unix,
+            inner.token_refresh_unix,
+            inner.bridges_refresh_unix,
+        ]
+        .into_iter()
+        .any(|refresh_unix| current_unix > refresh_unix + max_age)
+    }
+
+    /// The staleness threshold to use in place of `default`, taking the maximum age into account.
+    fn stale_secs(&self, default: u64) -> u64 {
+        self.max_age_secs.map_or(default, |max| max.min(default))
+    }
+
     /// Gets the current list of bridges
     pub fn bridges(&self) -> Vec<BridgeDescriptor> {
         self.inner.read().bridges.clone()
     }
 
+    /// Records the outcome of connecting to a bridge, updating its persistent quality score. `latency` is the time taken to establish the pipe, or `None` if it failed.
+    pub fn record_bridge_result(&self, endpoint: SocketAddr, latency: Option<Duration>) {
+        let current_unix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut inner = self.inner.write();
+        inner
+            .bridge_scores
+            .retain(|_, score| score.updated_unix + BRIDGE_SCORE_EXPIRE_SECS > current_unix);
+        let score = inner
+            .bridge_scores
+            .entry(endpoint.to_string())
+            .or_insert(BridgeScore {
+                reliability: 1.0,
+                latency: latency.map(|l| l.as_secs_f64()).unwrap_or(1.0),
+                updated_unix: current_unix,
+            });
+        let success = if latency.is_some() { 1.0 } else { 0.0 };
+        score.reliability += BRIDGE_SCORE_ALPHA * (success - score.reliability);
+        if let Some(latency) = latency {
+            score.latency += BRIDGE_SCORE_ALPHA * (latency.as_secs_f64() - score.latency);
+        }
+        score.updated_unix = current_unix;
+        log::debug!("updated bridge score for {endpoint}: {:?}", score);
+    }
+
+    /// Gets the historical quality of a bridge, between 0.0 (terrible) and 1.0 (perfect). Bridges without history, or whose history is old, score close to neutral.
+    pub fn bridge_quality(&self, endpoint: SocketAddr) -> f64 {
+        let current_unix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.inner
+            .read()
+            .bridge_scores
+            .get(&endpoint.to_string())
+            .map(|score| score.quality(current_unix))
+            .unwrap_or(BRIDGE_SCORE_NEUTRAL)
+    }
+
+    /// Gets the exit whose bridges are currently cached.
+    pub fn cached_exit(&self) -> String {
+        self.inner.read().cached_exit.clone()
+    }
+
+    /// Gets the sticky set of bridges that connections with --sticky-bridges are restricted to.
+    pub fn sticky_bridges(&self) -> Vec<SocketAddr> {
+        self.inner.read().sticky_bridges.clone()
+    }
+
+    /// Replaces the sticky set of bridges.
+    pub fn set_sticky_bridges(&self, endpoints: Vec<SocketAddr>) {
+        self.inner.write().sticky_bridges = endpoints;
+    }
+
     /// Gets the current master summary
     pub fn summary(&self) -> MasterSummary {
         self.inner.read().summary.clone()
@@ -193,6 +361,23 @@ impl ConnInfoStore {
         self.inner.read().blind_token.clone()
     }
 
+    /// Exports the currently cached exit, bridges, and authentication token, for connecting later without contacting the binder.
+    pub fn export_endpoints(&self) -> anyhow::Result<EndpointsFile> {
+        let inner = self.inner.read();
+        let exit = inner
+            .summary
+            .exits
+            .iter()
+            .find(|e| e.hostname == inner.cached_exit)
+            .context(format!("no such exit {}", inner.cached_exit))?
+            .clone();
+        Ok(EndpointsFile {
+            exit,
+            bridges: inner.bridges.clone(),
+            token: Some(inner.blind_token.clone()),
+        })
+    }
+
     /// Gets the underlying RPC.
     pub fn rpc(&self) -> &BinderClient {
         &self.rpc
@@ -267,6 +452,8 @@ impl ConnInfoStore {
     /// We check from newest to oldest until we find a match, or we run out of bindings.
     /// Old domain names being used by other people is not a threat because
     /// we also hash the sosistab2 public key of the servers, which other people can't get.
+    // unused while the check in get_verified_summary is disabled
+    #[allow(dead_code)]
     async fn verify_summary(&self, summary: &MasterSummary) -> anyhow::Result<bool> {
         struct CustomRpcTransport {
             binder_client: Arc<BinderClient>,
@@ -336,4 +523,47 @@ struct ConnInfoInner {
 
     summary: MasterSummary,
     summary_refresh_unix: u64,
+
+    #[serde(default)]
+    bridge_scores: BTreeMap<String, BridgeScore>,
+
+    #[serde(default)]
+    sticky_bridges: Vec<SocketAddr>,
+}
+
+/// A pre-fetched set of endpoints for one exit, distributed as a JSON file so that clients can connect without ever contacting the binder.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EndpointsFile {
+    pub exit: ExitDescriptor,
+    pub bridges: Vec<BridgeDescriptor>,
+    /// Token used to authenticate to the exit. If absent, no authentication is attempted. Tokens eventually expire, so files containing them must be re-exported periodically.
+    pub token: Option<BlindToken>,
+}
+
+impl EndpointsFile {
+    /// Reads an endpoints file from disk.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read(path).context("cannot read endpoints file")?;
+        serde_json::from_slice(&contents).context("cannot parse endpoints file")
+    }
+}
+
+/// Persistent quality record of a single bridge, learned from past connection attempts.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+struct BridgeScore {
+    /// Moving average of connection success (1.0) versus failure (0.0).
+    reliability: f64,
+    /// Moving average of pipe establishment latency, in seconds.
+    latency: f64,
+    updated_unix: u64,
+}
+
+impl BridgeScore {
+    /// Overall quality of the bridge, decayed towards neutral depending on how old the observations are.
+    fn quality(&self, current_unix: u64) -> f64 {
+        let raw = self.reliability / (1.0 + self.latency);
+        let age = current_unix.saturating_sub(self.updated_unix) as f64;
+        let weight = 0.5f64.powf(age / BRIDGE_SCORE_HALF_LIFE_SECS);
+        weight * raw + (1.0 - weight) * BRIDGE_SCORE_NEUTRAL
+    }
 }
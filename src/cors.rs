@@ -0,0 +1,47 @@
+//! Cross-origin resource sharing for the local HTTP servers, so that browser front-ends on other origins can call them.
+
+use std::io::Read;
+
+use tiny_http::{Header, Request, Response};
+
+/// Responding to requests with CORS headers.
+pub trait CorsExt {
+    /// Responds, allowing the given origin, if any, to read the response.
+    fn respond_cors<R: Read>(
+        self,
+        origin: Option<&str>,
+        response: Response<R>,
+    ) -> std::io::Result<()>;
+}
+
+impl CorsExt for Request {
+    fn respond_cors<R: Read>(
+        self,
+        origin: Option<&str>,
+        response: Response<R>,
+    ) -> std::io::Result<()> {
+        match origin.and_then(|origin| header("Access-Control-Allow-Origin", origin)) {
+            Some(allow_origin) => self.respond(response.with_header(allow_origin)),
+            None => self.respond(response),
+        }
+    }
+}
+
+/// The answer to a CORS preflight request, allowing the given origin to make any request the servers understand.
+pub fn preflight_response(origin: &str) -> Response<std::io::Empty> {
+    [
+        ("Access-Control-Allow-Origin", origin),
+        ("Access-Control-Allow-Methods", "GET, POST, OPTIONS"),
+        ("Access-Control-Allow-Headers", "Content-Type"),
+        ("Access-Control-Max-Age", "86400"),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| header(name, value))
+    .fold(Response::empty(204), |response, header| {
+        response.with_header(header)
+    })
+}
+
+fn header(name: &str, value: &str) -> Option<Header> {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).ok()
+}
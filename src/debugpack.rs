@@ -1,18 +1,22 @@
 use std::{
+    collections::{BTreeMap, VecDeque},
+    net::Ipv6Addr,
     ops::Deref,
     sync::Arc,
     time::{Duration, Instant, SystemTime},
 };
 
+use anyhow::Context;
 use once_cell::sync::Lazy;
 use parking_lot::Mutex;
-use rusqlite::{backup, params, Connection};
+use regex::{Captures, Regex};
+use rusqlite::{backup, params, Connection, OpenFlags, OptionalExtension};
 use serde::{Deserialize, Serialize};
 use smol::{channel::Sender, Task};
 use structopt::StructOpt;
 
 use crate::{
-    config::{CommonOpt, CONFIG},
+    config::{write_private, CommonOpt, CONFIG},
     ALLOCATOR,
 };
 
@@ -22,7 +26,16 @@ pub struct DebugPackOpt {
     pub common: CommonOpt,
 
     #[structopt(long)]
-    pub export_to: String, // path of file to backup DB to
+    pub export_to: Option<String>, // path of file to backup DB to
+
+    #[structopt(subcommand)]
+    pub command: Option<DebugPackCommand>,
+}
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub enum DebugPackCommand {
+    /// Prints a human-readable summary of an exported debugpack.
+    Inspect { path: String },
 }
 
 pub struct DebugPack {
@@ -48,6 +61,25 @@ pub static DEBUGPACK: Lazy<Arc<DebugPack>> = Lazy::new(|| {
         crate::config::Opt::Debugpack(dp_opt) => {
             DebugPack::new(&dp_opt.common.debugpack_path).unwrap()
         }
+        crate::config::Opt::Logout(logout_opt) => {
+            DebugPack::new(&logout_opt.common.debugpack_path).unwrap()
+        }
+        crate::config::Opt::Resolve(resolve_opt) => {
+            DebugPack::new(&resolve_opt.common.debugpack_path).unwrap()
+        }
+        crate::config::Opt::RouteTest(route_test_opt) => {
+            DebugPack::new(&route_test_opt.common.debugpack_path).unwrap()
+        }
+        crate::config::Opt::RouteDump(route_dump_opt) => {
+            DebugPack::new(&route_dump_opt.common.debugpack_path).unwrap()
+        }
+        crate::config::Opt::Geo(geo_opt) => DebugPack::new(&geo_opt.common.debugpack_path).unwrap(),
+        crate::config::Opt::Version(version_opt) => {
+            DebugPack::new(&version_opt.common.debugpack_path).unwrap()
+        }
+        crate::config::Opt::Bridges(bridges_opt) => {
+            DebugPack::new(&bridges_opt.common().debugpack_path).unwrap()
+        }
     };
 
     Arc::new(dp)
@@ -83,6 +115,12 @@ impl DebugPack {
                 line text)",
             [],
         )?;
+        conn.execute(
+            "create table if not exists config (
+                timestamp timestamp,
+                json text)",
+            [],
+        )?;
 
         conn.execute(
             "delete from loglines where datetime(timestamp, '+1 day') < datetime()",
@@ -135,6 +173,16 @@ impl DebugPack {
         let _ = self.send_timeseries.try_send((key.to_string(), value));
     }
 
+    /// Records the configuration the daemon was started with, so that it shows up when inspecting the debugpack.
+    pub fn add_config(&self, json: &str) {
+        if let Err(err) = self.conn.lock().execute(
+            "insert into config (timestamp, json) values (datetime(), ?1)",
+            params![json],
+        ) {
+            log::error!("cannot write config: {}", err)
+        }
+    }
+
     pub fn backup(&self, dest: &str) -> anyhow::Result<()> {
         let mut dst = Connection::open(dest)?;
         let src = self.conn.lock();
@@ -173,3 +221,248 @@ impl DebugPack {
 pub(crate) fn export_debugpak(dest: &str) -> anyhow::Result<()> {
     DEBUGPACK.backup(dest)
 }
+
+/// Exports the debugpack as the bytes of an SQLite database. With `redact`, IP addresses and the RPC key are scrubbed from the log lines and the recorded config, and so are the identifying fields of the config.
+pub(crate) fn export_debugpak_bytes(redact: bool) -> anyhow::Result<Vec<u8>> {
+    let path = std::env::temp_dir().join(format!("geph4-debugpack-{:x}.db", rand::random::<u64>()));
+    let path_str = path.to_str().context("temporary path is not UTF-8")?;
+    let result = (|| {
+        // SQLite gives its journal the permissions of the database
+        write_private(&path, &[])?;
+        DEBUGPACK.backup(path_str)?;
+        if redact {
+            redact_debugpak(path_str)?;
+        }
+        Ok(std::fs::read(&path)?)
+    })();
+    let _ = std::fs::remove_file(&path);
+    result
+}
+
+/// The fields of the recorded config, as paths into its JSON, that identify the user or what they connect to.
+const IDENTIFYING_CONFIG_FIELDS: &[&[&str]] = &[
+    &["auth", "auth_kind", "AuthPassword", "username"],
+    &["auth", "auth_kind", "AuthKeypair", "sk_path"],
+    &["auth", "profile"],
+    &["exit_server"],
+    &["exit_balance"],
+    &["socks5_exit"],
+    &["forward_ports"],
+    &["override_connect"],
+];
+
+/// Scrubs the log lines and the recorded config of an exported debugpack in place.
+fn redact_debugpak(path: &str) -> anyhow::Result<()> {
+    let rpc_key = std::env::var("GEPH_RPC_KEY")
+        .ok()
+        .filter(|key| !key.is_empty());
+    let mut conn = Connection::open(path)?;
+    let tx = conn.transaction()?;
+    let lines = tx
+        .prepare("select rowid, line from loglines")?
+        .query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    for (rowid, line) in lines {
+        let redacted = redact_text(&line, rpc_key.as_deref());
+        if redacted != line {
+            tx.execute(
+                "update loglines set line = ?1 where rowid = ?2",
+                params![redacted, rowid],
+            )?;
+        }
+    }
+    let configs = tx
+        .prepare("select rowid, json from config")?
+        .query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    for (rowid, json) in configs {
+        // a config that doesn't parse is dropped, rather than risk leaving fields in it
+        let redacted = serde_json::from_str::<serde_json::Value>(&json)
+            .map(|mut config| {
+                for field in IDENTIFYING_CONFIG_FIELDS {
+                    if let Some(value) = field
+                        .iter()
+                        .try_fold(&mut config, |value, key| value.get_mut(key))
+                    {
+                        *value = "<redacted>".into();
+                    }
+                }
+                redact_text(&config.to_string(), rpc_key.as_deref())
+            })
+            .unwrap_or_else(|_| "<redacted>".into());
+        tx.execute(
+            "update config set json = ?1 where rowid = ?2",
+            params![redacted, rowid],
+        )?;
+    }
+    tx.commit()?;
+    // otherwise, the original lines may linger in free pages
+    conn.execute("vacuum", [])?;
+    Ok(())
+}
+
+/// Replaces IPv4 and IPv6 addresses, and the RPC key if given, in a piece of text.
+fn redact_text(text: &str, rpc_key: Option<&str>) -> String {
+    static IPV4_ADDR: Lazy<Regex> = Lazy::new(|| Regex::new(r"\b\d{1,3}(\.\d{1,3}){3}\b").unwrap());
+    // candidates only; see below for what actually counts as an address
+    static IPV6_ADDR: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"[0-9A-Fa-f]{0,4}(:[0-9A-Fa-f]{0,4}){2,7}").unwrap());
+    let redacted = IPV6_ADDR.replace_all(text, |caps: &Captures| {
+        let m = caps.get(0).unwrap();
+        // not part of a longer word, such as the "::" in a Rust path or the start of an IPv4-mapped address
+        let joined = text[..m.start()]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_' || c == ':')
+            || {
+                let mut after = text[m.end()..].chars();
+                match after.next() {
+                    Some('.') => after.next().is_some_and(|c| c.is_ascii_digit()),
+                    Some(c) => c.is_alphanumeric() || c == '_',
+                    None => false,
+                }
+            };
+        if !joined && m.as_str().parse::<Ipv6Addr>().is_ok() {
+            "<ip>".to_string()
+        } else {
+            m.as_str().to_string()
+        }
+    });
+    let mut redacted = IPV4_ADDR.replace_all(&redacted, "<ip>").into_owned();
+    if let Some(key) = rpc_key {
+        redacted = redacted.replace(key, "<rpc-key>");
+    }
+    redacted
+}
+
+pub(crate) fn main_debugpack(opt: &DebugPackOpt) -> anyhow::Result<()> {
+    match (&opt.command, &opt.export_to) {
+        (Some(DebugPackCommand::Inspect { path }), _) => inspect_debugpak(path),
+        (None, Some(export_to)) => export_debugpak(export_to),
+        (None, None) => anyhow::bail!("either --export-to or a subcommand must be given"),
+    }
+}
+
+/// Prints a human-readable summary of the debugpack at the given path: the recorded config, log counts by level, and captured stats.
+fn inspect_debugpak(path: &str) -> anyhow::Result<()> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let has_table = |name: &str| -> anyhow::Result<bool> {
+        Ok(conn
+            .prepare("select 1 from sqlite_master where type = 'table' and name = ?1")?
+            .exists(params![name])?)
+    };
+
+    println!("== config ==");
+    let config: Option<(String, String)> = if has_table("config")? {
+        conn.query_row(
+            "select timestamp, json from config order by timestamp desc limit 1",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?
+    } else {
+        None
+    };
+    match config {
+        Some((timestamp, json)) => {
+            println!("recorded at {timestamp}:");
+            match serde_json::from_str::<serde_json::Value>(&json) {
+                Ok(serde_json::Value::Object(fields)) => {
+                    for (key, value) in fields {
+                        println!("  {key}: {value}");
+                    }
+                }
+                _ => println!("  {json}"),
+            }
+        }
+        None => println!("(none recorded)"),
+    }
+
+    println!();
+    println!("== logs ==");
+    let mut level_counts: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut problems = VecDeque::new();
+    let mut first_last: Option<(String, String)> = None;
+    let mut stmt = conn.prepare("select timestamp, line from loglines order by timestamp")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let timestamp: String = row.get(0)?;
+        let line: String = row.get(1)?;
+        let level = log_level(&line);
+        *level_counts.entry(level).or_default() += 1;
+        if level == "ERROR" || level == "WARN" {
+            problems.push_back(format!(
+                "{timestamp} {}",
+                line.lines().next().unwrap_or_default().trim()
+            ));
+            if problems.len() > 10 {
+                problems.pop_front();
+            }
+        }
+        first_last = Some(match first_last {
+            Some((first, _)) => (first, timestamp),
+            None => (timestamp.clone(), timestamp),
+        });
+    }
+    match first_last {
+        Some((first, last)) => {
+            println!(
+                "{} lines from {first} to {last}",
+                level_counts.values().sum::<usize>()
+            );
+            for (level, count) in level_counts.iter() {
+                println!("  {level}: {count}");
+            }
+        }
+        None => println!("(no log lines)"),
+    }
+    if !problems.is_empty() {
+        println!("last {} warnings and errors:", problems.len());
+        for line in problems {
+            println!("  {line}");
+        }
+    }
+
+    println!();
+    println!("== stats ==");
+    let mut stmt = conn.prepare(
+        "select key, count(*), min(value), max(value),
+            (select value from timeseries t2 where t2.key = t1.key order by timestamp desc limit 1)
+        from timeseries t1 group by key order by key",
+    )?;
+    let mut rows = stmt.query([])?;
+    let mut any = false;
+    while let Some(row) = rows.next()? {
+        any = true;
+        let key: String = row.get(0)?;
+        let count: i64 = row.get(1)?;
+        let min: f64 = row.get(2)?;
+        let max: f64 = row.get(3)?;
+        let last: f64 = row.get(4)?;
+        println!("{key}: {count} samples, last {last:.2}, min {min:.2}, max {max:.2}");
+    }
+    if !any {
+        println!("(no stats)");
+    }
+    Ok(())
+}
+
+/// Extracts the level from a log line, which starts with a preamble like "[module WARN]:" or "[WARN module]:".
+fn log_level(line: &str) -> &'static str {
+    let preamble = line.split("]:").next().unwrap_or_default();
+    for word in preamble.split(|c: char| c == '[' || c.is_whitespace()) {
+        match word {
+            "ERRO" | "ERROR" => return "ERROR",
+            "WARN" => return "WARN",
+            "INFO" => return "INFO",
+            "DEBG" | "DEBUG" => return "DEBUG",
+            "TRAC" | "TRACE" => return "TRACE",
+            _ => {}
+        }
+    }
+    "OTHER"
+}
@@ -1,6 +1,8 @@
 use std::{
+    collections::HashMap,
+    fmt::Display,
     sync::atomic::{AtomicUsize, Ordering},
-    time::Duration,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::Context;
@@ -8,32 +10,142 @@ use async_trait::async_trait;
 use backoff::{backoff::Backoff, ExponentialBackoffBuilder};
 use geph4_protocol::binder::client::E2eeHttpTransport;
 
-use itertools::Itertools;
-use nanorpc::{DynRpcTransport, RpcTransport};
+use nanorpc::{DynRpcTransport, JrpcResponse, RpcTransport};
 use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 use smol_timeout::TimeoutExt;
 
-/// Parses a list of front/host pairs and produces a DynRpcTransport.
+/// Parses a list of front/host pairs and produces a DynRpcTransport, whose every call fails after the given timeout.
 pub fn parse_fronts(
     binder_lpk: [u8; 32],
     fronts: impl IntoIterator<Item = (String, String)>,
+    timeout: Duration,
 ) -> DynRpcTransport {
     // make a list of the different alternatives, then select between them at random while increasing the timeout every time
-    let alternatives = fronts
+    let (endpoints, alternatives): (Vec<_>, Vec<_>) = fronts
         .into_iter()
         .map(|(endpoint, real_host)| {
-            DynRpcTransport::new(E2eeHttpTransport::new(
+            let transport = DynRpcTransport::new(E2eeHttpTransport::new(
                 binder_lpk,
-                endpoint,
+                endpoint.clone(),
                 vec![("host".to_string(), real_host)],
-            ))
+            ));
+            (endpoint, transport)
         })
-        .collect_vec();
-    let unified = MultiRpcTransport(alternatives);
+        .unzip();
+    let unified = MultiRpcTransport {
+        endpoints,
+        alternatives,
+        timeout,
+    };
     DynRpcTransport::new(unified)
 }
 
-struct MultiRpcTransport(Vec<DynRpcTransport>);
+/// How long to leave the binder alone when it is in maintenance without saying for how long.
+const MAINTENANCE_DEFAULT_BACKOFF: Duration = Duration::from_secs(300);
+
+/// The longest the binder is left alone for maintenance, however far off the end of the window it gives, in case its clock or ours is off.
+const MAINTENANCE_MAX_BACKOFF: Duration = Duration::from_secs(1800);
+
+/// The binder reported that it is down for maintenance. It does so with an error response whose message mentions maintenance, and whose data may give the end of the maintenance window as `{"until_unix": ...}`. The binder protocol has no error code for maintenance, so the message is all there is to go on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BinderMaintenance {
+    pub until_unix: Option<u64>,
+}
+
+impl BinderMaintenance {
+    fn from_response(resp: &JrpcResponse) -> Option<Self> {
+        let error = resp.error.as_ref()?;
+        if !error.message.to_lowercase().contains("maintenance") {
+            return None;
+        }
+        Some(Self {
+            until_unix: error.data.get("until_unix").and_then(|v| v.as_u64()),
+        })
+    }
+
+    /// How long to wait before contacting the binder again.
+    pub fn retry_delay(&self) -> Duration {
+        let current_unix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        match self.until_unix {
+            Some(until) if until > current_unix => {
+                Duration::from_secs(until - current_unix).min(MAINTENANCE_MAX_BACKOFF)
+            }
+            _ => MAINTENANCE_DEFAULT_BACKOFF,
+        }
+    }
+}
+
+impl Display for BinderMaintenance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.until_unix {
+            Some(until) => write!(f, "binder in maintenance until unix time {until}"),
+            None => write!(f, "binder in maintenance"),
+        }
+    }
+}
+
+impl std::error::Error for BinderMaintenance {}
+
+/// Failures in a row after which a front is sidelined.
+const FRONT_DISABLE_AFTER: u32 = 3;
+/// How long a front is first sidelined for. This doubles every time it fails again when re-probed.
+const FRONT_DISABLE_BASE: Duration = Duration::from_secs(60);
+const FRONT_DISABLE_MAX: Duration = Duration::from_secs(1800);
+
+/// How well a binder front has been doing, by its URL.
+static FRONT_HEALTH: Lazy<Mutex<HashMap<String, FrontHealth>>> = Lazy::new(Default::default);
+
+#[derive(Default)]
+struct FrontHealth {
+    successes: u64,
+    failures: u64,
+    consecutive_failures: u32,
+    /// While set, the front is skipped, unless every front is. Once it passes, the front is tried again as a re-probe.
+    disabled_until: Option<Instant>,
+    /// How long the front was last sidelined for.
+    disabled_for: Duration,
+}
+
+impl FrontHealth {
+    fn is_disabled(&self) -> bool {
+        self.disabled_until
+            .is_some_and(|until| until > Instant::now())
+    }
+
+    fn record_success(&mut self) {
+        self.successes += 1;
+        self.consecutive_failures = 0;
+        self.disabled_until = None;
+        self.disabled_for = Duration::ZERO;
+    }
+
+    /// Records a failure, returning how long the front is now sidelined for, if it just was.
+    fn record_failure(&mut self) -> Option<Duration> {
+        self.failures += 1;
+        self.consecutive_failures += 1;
+        // a front that was sidelined before is sidelined again as soon as its re-probe fails
+        if self.consecutive_failures < FRONT_DISABLE_AFTER && self.disabled_until.is_none() {
+            return None;
+        }
+        self.disabled_for = if self.disabled_for.is_zero() {
+            FRONT_DISABLE_BASE
+        } else {
+            (self.disabled_for * 2).min(FRONT_DISABLE_MAX)
+        };
+        self.disabled_until = Some(Instant::now() + self.disabled_for);
+        Some(self.disabled_for)
+    }
+}
+
+struct MultiRpcTransport {
+    endpoints: Vec<String>,
+    alternatives: Vec<DynRpcTransport>,
+    timeout: Duration,
+}
 
 #[async_trait]
 impl RpcTransport for MultiRpcTransport {
@@ -43,13 +155,46 @@ impl RpcTransport for MultiRpcTransport {
         &self,
         req: nanorpc::JrpcRequest,
     ) -> Result<nanorpc::JrpcResponse, Self::Error> {
+        let method = req.method.clone();
+        self.call_with_retries(req)
+            .timeout(self.timeout)
+            .await
+            .with_context(|| {
+                format!(
+                    "binder request {:?} timed out after {:?}",
+                    method, self.timeout
+                )
+            })?
+    }
+}
+
+impl MultiRpcTransport {
+    /// The first front, starting from the given index, that isn't sidelined for failing. If every front is, they are all tried anyway.
+    fn next_healthy(&self, start: usize) -> usize {
+        let health = FRONT_HEALTH.lock();
+        (0..self.alternatives.len())
+            .map(|offset| (start + offset) % self.alternatives.len())
+            .find(|&idx| {
+                health
+                    .get(&self.endpoints[idx])
+                    .is_none_or(|health| !health.is_disabled())
+            })
+            .unwrap_or(start % self.alternatives.len())
+    }
+
+    async fn call_with_retries(
+        &self,
+        req: nanorpc::JrpcRequest,
+    ) -> anyhow::Result<nanorpc::JrpcResponse> {
         let mut backoff = ExponentialBackoffBuilder::new()
             .with_max_elapsed_time(Duration::from_secs(30).into())
             .build();
+        let mut consecutive_failures = 0;
         loop {
             static IDX: Lazy<AtomicUsize> = Lazy::new(|| AtomicUsize::new(fastrand::usize(..)));
-            let idx = IDX.load(Ordering::Relaxed) % self.0.len();
-            let random_element = &self.0[idx];
+            let idx = self.next_healthy(IDX.load(Ordering::Relaxed));
+            IDX.store(idx, Ordering::Relaxed);
+            let random_element = &self.alternatives[idx];
             log::debug!("selecting binder front {idx} for method {:?}", req.method);
             let req = req.clone();
             let vv = async {
@@ -62,10 +207,39 @@ impl RpcTransport for MultiRpcTransport {
                 )
             };
             match vv.await {
-                Ok(v) => return Ok(v),
+                Ok(v) => {
+                    FRONT_HEALTH
+                        .lock()
+                        .entry(self.endpoints[idx].clone())
+                        .or_default()
+                        .record_success();
+                    // maintenance is the binder's doing rather than the front's, so trying other fronts would only hammer it
+                    if let Some(maintenance) = BinderMaintenance::from_response(&v) {
+                        return Err(maintenance.into());
+                    }
+                    return Ok(v);
+                }
                 Err(err) => {
-                    log::warn!("binder front {idx} failed: {:?}", err);
+                    // this includes CDN-level errors like 5xx responses, which are the front's fault, so we move on to another front
+                    warn_limited!("binder front {idx} failed: {:?}", err);
+                    {
+                        let mut health = FRONT_HEALTH.lock();
+                        let health = health.entry(self.endpoints[idx].clone()).or_default();
+                        if let Some(disabled_for) = health.record_failure() {
+                            log::warn!(
+                                "binder front {idx} failed {} of {} times, skipping it for {:?}",
+                                health.failures,
+                                health.failures + health.successes,
+                                disabled_for
+                            );
+                        }
+                    }
                     IDX.fetch_add(1, Ordering::Relaxed);
+                    consecutive_failures += 1;
+                    // only back off once every front has failed in a row; until then, alternate fronts are tried immediately
+                    if consecutive_failures % self.alternatives.len() != 0 {
+                        continue;
+                    }
                     if let Some(next) = backoff.next_backoff() {
                         log::debug!("backing off for {:?}", next);
                         smol::Timer::after(next).await;
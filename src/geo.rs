@@ -0,0 +1,83 @@
+use std::{net::IpAddr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use smol_timeout::TimeoutExt;
+use structopt::StructOpt;
+
+use crate::{china, config::CommonOpt};
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub struct GeoOpt {
+    #[structopt(flatten)]
+    pub common: CommonOpt,
+
+    /// Decides on bridges as if "connect --use-bridges" were given.
+    #[structopt(long)]
+    pub use_bridges: bool,
+
+    /// Decides on bridges as if "connect --no-china-test" were given.
+    #[structopt(long)]
+    pub no_china_test: bool,
+
+    /// Prints the result as JSON.
+    #[structopt(long)]
+    pub json: bool,
+}
+
+/// Where we seem to be, and what "connect" would make of it.
+#[derive(Serialize)]
+struct GeoInfo {
+    public_ip: Option<IpAddr>,
+    /// "China", "outside China", or "unknown" if the probe failed or only an IPv6 address was seen.
+    region: &'static str,
+    use_bridges: bool,
+    reason: &'static str,
+}
+
+/// Probes our public IP, like the China test of "connect" does, and prints which region it is in and whether bridges would be used, without connecting.
+pub async fn main_geo(opt: GeoOpt) -> anyhow::Result<()> {
+    // the same timeout as the China test of "connect", so that a slow probe gives the same answer
+    let public_ip = match china::public_ip().timeout(Duration::from_secs(2)).await {
+        Some(Ok(ip)) => Some(ip),
+        Some(Err(err)) => {
+            log::warn!("cannot find our public IP: {err}");
+            None
+        }
+        None => {
+            log::warn!("timed out finding our public IP");
+            None
+        }
+    };
+    let in_china = match public_ip {
+        Some(IpAddr::V4(ip)) => Some(china::is_chinese_ip(ip)),
+        _ => None,
+    };
+    // the same decision that "connect" makes
+    let skip_test = opt.no_china_test || !cfg!(feature = "china-test");
+    let (use_bridges, reason) = china::bridge_decision(opt.use_bridges, skip_test, in_china);
+    let info = GeoInfo {
+        public_ip,
+        region: match in_china {
+            Some(true) => "China",
+            Some(false) => "outside China",
+            None => "unknown",
+        },
+        use_bridges,
+        reason,
+    };
+    if opt.json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+    } else {
+        match info.public_ip {
+            Some(ip) => println!("public IP: {ip}"),
+            None => println!("public IP: unknown"),
+        }
+        println!("region: {}", info.region);
+        println!(
+            "bridges: {} ({})",
+            if info.use_bridges { "yes" } else { "no" },
+            info.reason
+        );
+    }
+    Ok(())
+}
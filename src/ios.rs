@@ -7,6 +7,7 @@ use std::{
     time::Duration,
 };
 
+use anyhow::Context;
 use bytes::Bytes;
 use once_cell::sync::Lazy;
 
@@ -84,7 +85,7 @@ fn dispatch_ios(func: String, args: Vec<String>) -> anyhow::Result<String> {
                 let opt = Opt::from_iter_safe(
                     vec![String::from("geph4-client"), String::from("connect")]
                         .into_iter()
-                        .chain(args.into_iter()),
+                        .chain(args),
                 )
                 .map_err(|e| {
                     log::error!("OH NO WEIRD FAIL: {:?}", e);
@@ -97,7 +98,7 @@ fn dispatch_ios(func: String, args: Vec<String>) -> anyhow::Result<String> {
                 config_logging_ios();
                 Lazy::force(&TIMESERIES_LOOP); // must be called *after* CONFIG is set
 
-                start_main_connect();
+                start_main_connect()?;
                 log::info!("called the start_main_connect");
                 Ok("".into())
             }
@@ -105,20 +106,20 @@ fn dispatch_ios(func: String, args: Vec<String>) -> anyhow::Result<String> {
                 let opt = Opt::from_iter_safe(
                     vec![String::from("geph4-client"), String::from("sync")]
                         .into_iter()
-                        .chain(args.clone().into_iter()),
+                        .chain(args.clone()),
                 )?;
                 override_config(opt);
 
-                let sync_opt = SyncOpt::from_iter(
-                    std::iter::once(String::from("sync")).chain(args.into_iter()),
-                );
+                let sync_opt =
+                    SyncOpt::from_iter(std::iter::once(String::from("sync")).chain(args));
                 let ret = sync_json(sync_opt).await?;
                 anyhow::Ok(ret)
             }
             "binder_rpc" => {
-                let opt = Opt::from_iter_safe(
-                    vec![String::from("geph4-client"), String::from("binder-proxy")].into_iter(),
-                )?;
+                let opt = Opt::from_iter_safe(vec![
+                    String::from("geph4-client"),
+                    String::from("binder-proxy"),
+                ])?;
                 override_config(opt);
                 let binder_client = Arc::new(CommonOpt::from_iter(vec![""]).get_binder_client());
                 let line = args[0].clone();
@@ -130,15 +131,15 @@ fn dispatch_ios(func: String, args: Vec<String>) -> anyhow::Result<String> {
                 let opt = Opt::from_iter_safe(
                     vec![String::from("geph4-client"), String::from("debugpack")]
                         .into_iter()
-                        .chain(args.clone().into_iter()),
+                        .chain(args.clone()),
                 )?;
                 override_config(opt);
 
-                let dp_opt = DebugPackOpt::from_iter(
-                    std::iter::once(String::from("debugpak")).chain(args.into_iter()),
-                );
-                debugpack::export_debugpak(&dp_opt.export_to)?;
-                anyhow::Ok(dp_opt.export_to)
+                let dp_opt =
+                    DebugPackOpt::from_iter(std::iter::once(String::from("debugpak")).chain(args));
+                let export_to = dp_opt.export_to.context("must give --export-to")?;
+                debugpack::export_debugpak(&export_to)?;
+                anyhow::Ok(export_to)
             }
             "version" => anyhow::Ok(String::from(version)),
             _ => anyhow::bail!("function {func} does not exist"),
@@ -218,13 +219,15 @@ pub extern "C" fn upload_packet(pkt: *const c_uchar, len: c_int) {
 }
 
 #[no_mangle]
-pub extern "C" fn download_packet(buffer: *mut c_uchar, buflen: c_int) -> c_int {
+/// writes the next packet from the VPN into buffer, returning its length
+/// # Safety
+/// The buffer must be valid for buflen bytes.
+pub unsafe extern "C" fn download_packet(buffer: *mut c_uchar, buflen: c_int) -> c_int {
     // Lazy::force(&VPN_SHUFFLE_TASK);
     let pkt = smol::future::block_on(vpn_download());
     let pkt_ref = pkt.as_ref();
     unsafe {
-        let mut slice: &mut [u8] =
-            std::slice::from_raw_parts_mut(buffer as *mut u8, buflen as usize);
+        let mut slice: &mut [u8] = std::slice::from_raw_parts_mut(buffer, buflen as usize);
         if pkt.len() < slice.len() {
             if slice.write_all(pkt_ref).is_err() {
                 log::debug!("from geph: error writing to buffer!");
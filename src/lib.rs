@@ -1,11 +1,16 @@
 use std::{io::Write, sync::atomic::AtomicUsize};
 use std::{ops::Deref, sync::atomic::Ordering};
 
+#[macro_use]
+mod log_limit;
+
 mod config;
+mod cors;
 mod fronts;
 
 mod melprot_cache;
 mod socks2http;
+mod syslog;
 
 use cap::Cap;
 use colored::Colorize;
@@ -14,10 +19,11 @@ use once_cell::sync::Lazy;
 use pad::{Alignment, PadStr};
 
 use crate::{
-    config::{Opt, CONFIG},
+    config::{LogTarget, Opt, CONFIG},
     debugpack::{DEBUGPACK, TIMESERIES_LOOP},
 };
 mod binderproxy;
+mod bridges;
 mod china;
 mod connect;
 mod conninfo_store;
@@ -27,15 +33,25 @@ mod metrics;
 pub mod ios;
 
 mod debugpack;
+mod geo;
+mod logout;
 mod main_bridgetest;
+mod resolve;
+mod route_dump;
+mod route_test;
 mod sync;
+mod version;
 
 #[global_allocator]
-pub static ALLOCATOR: Cap<std::alloc::System> = Cap::new(std::alloc::System, usize::max_value());
+pub static ALLOCATOR: Cap<std::alloc::System> = Cap::new(std::alloc::System, usize::MAX);
 
 pub fn dispatch() -> anyhow::Result<()> {
     std::env::remove_var("http_proxy");
     std::env::remove_var("https_proxy");
+    // forking is only safe before any threads are started
+    if let Opt::Connect(opt) = CONFIG.deref() {
+        connect::daemon::setup(opt)?;
+    }
     Lazy::force(&TIMESERIES_LOOP);
     config_logging();
     let version = env!("CARGO_PKG_VERSION");
@@ -45,13 +61,20 @@ pub fn dispatch() -> anyhow::Result<()> {
     smolscale::block_on(async move {
         match CONFIG.deref() {
             Opt::Connect(_) => {
-                connect::start_main_connect();
+                connect::start_main_connect()?;
                 smol::future::pending().await
             }
             Opt::Sync(opt) => sync::main_sync(opt.clone()).await,
             Opt::BinderProxy(opt) => binderproxy::main_binderproxy(opt.clone()).await,
             Opt::BridgeTest(opt) => main_bridgetest::main_bridgetest(opt.clone()).await,
-            Opt::Debugpack(opt) => debugpack::export_debugpak(&opt.export_to),
+            Opt::Debugpack(opt) => debugpack::main_debugpack(opt),
+            Opt::Logout(opt) => logout::main_logout(opt.clone()).await,
+            Opt::Resolve(opt) => resolve::main_resolve(opt.clone()).await,
+            Opt::RouteTest(opt) => route_test::main_route_test(opt.clone()).await,
+            Opt::RouteDump(opt) => route_dump::main_route_dump(opt),
+            Opt::Geo(opt) => geo::main_geo(opt.clone()).await,
+            Opt::Version(opt) => version::main_version(opt),
+            Opt::Bridges(opt) => bridges::main_bridges(opt.clone()).await,
         }
     })
 }
@@ -59,12 +82,32 @@ pub fn dispatch() -> anyhow::Result<()> {
 static LONGEST_LINE_EVER: AtomicUsize = AtomicUsize::new(0);
 
 fn config_logging() {
+    let to_syslog = match CONFIG.deref() {
+        Opt::Connect(opt) if opt.log_target == LogTarget::Syslog => match syslog::open() {
+            Ok(()) => true,
+            Err(err) => {
+                eprintln!("cannot log to syslog, so logging to stderr: {:?}", err);
+                false
+            }
+        },
+        _ => false,
+    };
     if let Err(e) = env_logger::Builder::from_env(
         env_logger::Env::default()
             .default_filter_or("geph4client=debug,geph4_protocol=debug,melprot=debug,warn"),
     )
     .format_timestamp_millis()
     .format(move |buf, record| {
+        if to_syslog {
+            let line = format!(
+                "[{}]: {}",
+                record.module_path().unwrap_or("none"),
+                record.args()
+            );
+            syslog::send(record.level(), &line);
+            DEBUGPACK.add_logline(&line);
+            return Ok(());
+        }
         let preamble = format!(
             "[{} {}]:",
             record.module_path().unwrap_or("none").dimmed(),
@@ -102,6 +145,13 @@ fn config_melprot_cache() -> anyhow::Result<()> {
         Opt::Sync(opt) => Some(&opt.auth.credential_cache),
         Opt::BinderProxy(_) => None,
         Opt::Debugpack(_) => None,
+        Opt::Logout(_) => None,
+        Opt::Resolve(opt) => Some(&opt.auth.credential_cache),
+        Opt::RouteTest(_) => None,
+        Opt::RouteDump(_) => None,
+        Opt::Geo(_) => None,
+        Opt::Version(_) => None,
+        Opt::Bridges(opt) => Some(&opt.auth().credential_cache),
     };
     if let Some(mut path) = path.cloned() {
         path.push("melprot");
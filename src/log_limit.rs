@@ -0,0 +1,85 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use smol::Task;
+
+/// Identical messages from the same place are shown at most once in this long.
+const REPEAT_WINDOW: Duration = Duration::from_secs(60);
+
+/// How often suppressed counts are checked for messages that stopped repeating.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Recent {
+    target: &'static str,
+    last_shown: Instant,
+    suppressed: usize,
+}
+
+static RECENT: Lazy<Mutex<HashMap<(&'static str, String), Recent>>> = Lazy::new(Default::default);
+
+/// Logs the suppressed counts of messages that haven't been shown for a whole [REPEAT_WINDOW], so that they aren't lost when a message stops repeating.
+static FLUSH_LOOP: Lazy<Task<()>> = Lazy::new(|| {
+    smolscale::spawn(async {
+        loop {
+            smol::Timer::after(FLUSH_INTERVAL).await;
+            let mut recent = RECENT.lock();
+            let now = Instant::now();
+            for ((_, message), r) in recent.iter_mut() {
+                if r.suppressed > 0 && now.duration_since(r.last_shown) >= REPEAT_WINDOW {
+                    log::warn!(target: r.target, "{message} (repeated {} times)", r.suppressed);
+                    r.suppressed = 0;
+                    r.last_shown = now;
+                }
+            }
+        }
+    })
+});
+
+/// Decides whether a message from the given call site should be logged. Returns the line to log, which mentions how many identical messages were suppressed since it was last shown, or `None` if it should be suppressed. Suppressed counts of messages that stop repeating are logged under `target` once the window is up.
+pub fn limit(target: &'static str, callsite: &'static str, message: String) -> Option<String> {
+    Lazy::force(&FLUSH_LOOP);
+    let mut recent = RECENT.lock();
+    if recent.len() > 1000 {
+        recent.retain(|_, r| r.suppressed > 0 || r.last_shown.elapsed() < REPEAT_WINDOW);
+    }
+    let now = Instant::now();
+    match recent.get_mut(&(callsite, message.clone())) {
+        Some(r) if r.last_shown.elapsed() < REPEAT_WINDOW => {
+            r.suppressed += 1;
+            None
+        }
+        Some(r) => {
+            let suppressed = std::mem::take(&mut r.suppressed);
+            r.last_shown = now;
+            if suppressed > 0 {
+                Some(format!("{message} (repeated {suppressed} times)"))
+            } else {
+                Some(message)
+            }
+        }
+        None => {
+            recent.insert(
+                (callsite, message.clone()),
+                Recent {
+                    target,
+                    last_shown: now,
+                    suppressed: 0,
+                },
+            );
+            Some(message)
+        }
+    }
+}
+
+/// Like `log::warn!`, but collapses identical messages repeated within a minute, for noisy paths like reconnect loops.
+macro_rules! warn_limited {
+    ($($arg:tt)+) => {
+        if let Some(line) = $crate::log_limit::limit(module_path!(), concat!(file!(), ":", line!()), format!($($arg)+)) {
+            log::warn!("{}", line)
+        }
+    };
+}
@@ -0,0 +1,45 @@
+use std::io::{BufRead, Write};
+
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::config::{AuthOpt, CommonOpt};
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub struct LogoutOpt {
+    #[structopt(flatten)]
+    pub common: CommonOpt,
+
+    #[structopt(flatten)]
+    pub auth: AuthOpt,
+
+    /// Deletes without asking for confirmation.
+    #[structopt(long)]
+    pub yes: bool,
+}
+
+/// Deletes the cached connection info and credentials belonging to the given auth config.
+pub async fn main_logout(opt: LogoutOpt) -> anyhow::Result<()> {
+    let cache_dir = opt.auth.user_cache_dir()?;
+    if !cache_dir.exists() {
+        println!("nothing cached at {:?}", cache_dir);
+        return Ok(());
+    }
+    println!("this will delete the credential cache at {:?}:", cache_dir);
+    for entry in std::fs::read_dir(&cache_dir)? {
+        println!("  {:?}", entry?.file_name());
+    }
+    if !opt.yes {
+        print!("continue? [y/N] ");
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().lock().read_line(&mut answer)?;
+        if !answer.trim().eq_ignore_ascii_case("y") {
+            println!("aborted");
+            return Ok(());
+        }
+    }
+    std::fs::remove_dir_all(&cache_dir)?;
+    println!("deleted {:?}", cache_dir);
+    Ok(())
+}
@@ -0,0 +1,68 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::{
+    config::{get_conninfo_store, AuthOpt, CommonOpt, ObfsLayer},
+    connect::tunnel::{
+        getsess::{bridge_allowed, select_endpoints},
+        BinderTunnelParams,
+    },
+    conninfo_store::EndpointsFile,
+};
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub struct ResolveOpt {
+    #[structopt(flatten)]
+    pub common: CommonOpt,
+
+    #[structopt(flatten)]
+    pub auth: AuthOpt,
+
+    /// The exit to resolve.
+    #[structopt(long)]
+    pub exit: String,
+
+    /// Only lists bridges that are not direct connections to the exit, as "connect --use-bridges" would use.
+    #[structopt(long)]
+    pub use_bridges: bool,
+
+    /// Only lists bridges whose protocol matches the given regex, as with "connect --force-protocol".
+    #[structopt(long)]
+    pub force_protocol: Option<String>,
+
+    /// Only lists bridges using the given obfuscation layer, as with "connect --obfs".
+    #[structopt(long)]
+    pub obfs: Option<ObfsLayer>,
+}
+
+/// Fetches the exit and bridges that "connect" would use, and prints them as JSON without connecting. The output has the format of an endpoints file, but without an authentication token.
+pub async fn main_resolve(opt: ResolveOpt) -> anyhow::Result<()> {
+    let cstore = get_conninfo_store(&opt.common, &opt.auth, &opt.exit).await?;
+    cstore.refresh().await?;
+    let params = BinderTunnelParams {
+        cstore: Arc::new(cstore),
+        exit_server: Some(opt.exit.clone()),
+        exit_pubkey: None,
+        chain_exit: None,
+        use_bridges: opt.use_bridges,
+        force_bridge: None,
+        force_protocol: opt.force_protocol.clone(),
+        obfs: opt.obfs,
+        bridges_in_use: None,
+        avoid_bridges: None,
+    };
+    let (exit, bridges) = select_endpoints(&params)?;
+    let bridges = bridges
+        .into_iter()
+        .filter(|bridge| bridge_allowed(&params, bridge))
+        .collect();
+    let resolved = EndpointsFile {
+        exit,
+        bridges,
+        token: None,
+    };
+    println!("{}", serde_json::to_string_pretty(&resolved)?);
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use std::path::PathBuf;
+
+use ipnet::Ipv4Net;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::config::{CommonOpt, DnsRoute};
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub struct RouteDumpOpt {
+    #[structopt(flatten)]
+    pub common: CommonOpt,
+
+    /// Dumps the policy as if "connect --exclude-prc" were given.
+    #[structopt(long)]
+    pub exclude_prc: bool,
+
+    /// Dumps the policy as if "connect --proxy-local" were given.
+    #[structopt(long)]
+    pub proxy_local: bool,
+
+    /// Dumps the policy as if "connect --route-script" were given with this script.
+    #[structopt(long)]
+    pub route_script: Option<PathBuf>,
+
+    /// Dumps the policy as if "connect --dns-route" were given.
+    #[structopt(long, default_value = "split")]
+    pub dns_route: DnsRoute,
+
+    /// Dumps the policy as if "connect --vpn-route-cidr" were given. Can be given multiple times.
+    #[structopt(long)]
+    pub vpn_route_cidr: Vec<Ipv4Net>,
+
+    /// Dumps the policy as if "connect --tunnel-uid" were given. Can be given multiple times.
+    #[structopt(long)]
+    pub tunnel_uid: Vec<u32>,
+
+    /// Dumps the policy as if "connect --dedicated-session" were given.
+    #[structopt(long)]
+    pub dedicated_session: Option<String>,
+}
+
+/// The effective routing policy of "connect" under some flags.
+#[derive(Serialize)]
+struct RoutePolicy {
+    /// The checks made, in order, on each SOCKS5 or HTTP proxy connection. The first that matches decides.
+    proxy_rules: Vec<String>,
+    exclude_prc: bool,
+    route_script: Option<PathBuf>,
+    private_addresses: PrivateAddresses,
+    tunnel_uid: Vec<u32>,
+    dedicated_session: Option<String>,
+    dns_route: DnsRoute,
+    vpn_route_cidr: Vec<Ipv4Net>,
+}
+
+/// How connections to local destinations are handled.
+#[derive(Serialize)]
+struct PrivateAddresses {
+    route: &'static str,
+    ranges: Vec<&'static str>,
+    hostnames: &'static str,
+}
+
+/// Prints, as JSON, how "connect" would split traffic under the given flags, without connecting.
+pub fn main_route_dump(opt: &RouteDumpOpt) -> anyhow::Result<()> {
+    let mut proxy_rules = vec![];
+    if !opt.tunnel_uid.is_empty() {
+        proxy_rules.push("direct if the client is run by another local user".into());
+    }
+    if !opt.proxy_local {
+        proxy_rules.push("direct if the destination is a private address".into());
+    }
+    match (&opt.route_script, opt.exclude_prc) {
+        (Some(script), _) => proxy_rules.push(format!("direct if {:?} prints \"direct\"", script)),
+        (None, true) => proxy_rules.push("direct if the destination is in China".into()),
+        (None, false) => {}
+    }
+    proxy_rules.push("tunnel".into());
+
+    let policy = RoutePolicy {
+        proxy_rules,
+        exclude_prc: opt.exclude_prc,
+        route_script: opt.route_script.clone(),
+        private_addresses: PrivateAddresses {
+            route: if opt.proxy_local { "tunnel" } else { "direct" },
+            ranges: vec![
+                "10.0.0.0/8",
+                "172.16.0.0/12",
+                "192.168.0.0/16",
+                "127.0.0.0/8",
+                "169.254.0.0/16",
+            ],
+            hostnames: "without a public suffix",
+        },
+        tunnel_uid: opt.tunnel_uid.clone(),
+        dedicated_session: opt.dedicated_session.clone(),
+        dns_route: opt.dns_route,
+        vpn_route_cidr: opt.vpn_route_cidr.clone(),
+    };
+    println!("{}", serde_json::to_string_pretty(&policy)?);
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::{
+    config::CommonOpt,
+    connect::routing::{classify, RoutingRules},
+};
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub struct RouteTestOpt {
+    #[structopt(flatten)]
+    pub common: CommonOpt,
+
+    /// The destination to test, as "host:port".
+    pub destination: String,
+
+    /// Tests as if "connect --exclude-prc" were given.
+    #[structopt(long)]
+    pub exclude_prc: bool,
+
+    /// Tests as if "connect --proxy-local" were given.
+    #[structopt(long)]
+    pub proxy_local: bool,
+
+    /// Tests as if "connect --route-script" were given with this script.
+    #[structopt(long)]
+    pub route_script: Option<PathBuf>,
+}
+
+/// Prints how a SOCKS5 or HTTP proxy connection to the destination would be routed under the given flags, and why, without connecting.
+pub async fn main_route_test(opt: RouteTestOpt) -> anyhow::Result<()> {
+    if !opt.destination.contains(':') {
+        anyhow::bail!("destination must be given as host:port")
+    }
+    let rules = RoutingRules {
+        exclude_prc: opt.exclude_prc,
+        proxy_local: opt.proxy_local,
+        route_script: opt.route_script.as_deref(),
+    };
+    let reason = classify(&opt.destination, &rules).await;
+    println!("{}: {}", opt.destination, reason);
+    Ok(())
+}
@@ -154,7 +154,7 @@ fn get_addr_len(atyp: &Address) -> usize {
     }
 }
 fn write_domain_name_address<B: BufMut>(dnaddr: &str, port: u16, buf: &mut B) {
-    assert!(dnaddr.len() <= u8::max_value() as usize);
+    assert!(dnaddr.len() <= u8::MAX as usize);
 
     buf.put_u8(consts::SOCKS5_ADDR_TYPE_DOMAIN_NAME);
     buf.put_u8(dnaddr.len() as u8);
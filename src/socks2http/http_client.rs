@@ -10,10 +10,15 @@ use tokio::net::TcpStream;
 #[derive(Clone)]
 pub struct SocksConnector {
     proxy: SocketAddr,
+    /// Whether the clients whose requests go over these connections are tunneled under --tunnel-uid.
+    tunneled: bool,
 }
 impl SocksConnector {
-    pub fn new(addr: SocketAddr) -> SocksConnector {
-        SocksConnector { proxy: addr }
+    pub fn new(addr: SocketAddr, tunneled: bool) -> SocksConnector {
+        SocksConnector {
+            proxy: addr,
+            tunneled,
+        }
     }
 }
 impl hyper::service::Service<Uri> for SocksConnector {
@@ -25,15 +30,15 @@ impl hyper::service::Service<Uri> for SocksConnector {
     }
     fn call(&mut self, dst: Uri) -> Self::Future {
         let proxy = self.proxy;
+        let tunneled = self.tunneled;
         SocksConnecting {
             fut: async move {
                 match crate::socks2http::address::host_addr(&dst) {
                     None => {
-                        use std::io::{Error, ErrorKind};
-                        let err = Error::new(ErrorKind::Other, "URI must be a valid Address");
+                        let err = std::io::Error::other("URI must be a valid Address");
                         Err(err)
                     }
-                    Some(addr) => socks5::connect(&addr, &proxy).await,
+                    Some(addr) => socks5::connect(&addr, &proxy, tunneled).await,
                 }
             }
             .boxed(),
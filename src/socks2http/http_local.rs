@@ -1,3 +1,4 @@
+use crate::connect::socket_owner::client_tunneled;
 use crate::socks2http::address::{host_addr, Address};
 use crate::socks2http::http_client;
 use crate::socks2http::socks5;
@@ -14,24 +15,30 @@ use hyper::{
 };
 use log::trace;
 use std::convert::Infallible;
-use std::net::SocketAddr;
-pub async fn run(listen_addr: SocketAddr, proxy_address: SocketAddr) -> std::io::Result<()> {
-    let shared_server: SharedProxyServer = ProxyServer::new_shared(proxy_address);
+use std::net::{SocketAddr, TcpListener};
+pub async fn run(
+    listener: TcpListener,
+    proxy_address: SocketAddr,
+    relay_buffer_size: usize,
+) -> std::io::Result<()> {
+    let shared_server: SharedProxyServer =
+        ProxyServer::new_shared(proxy_address, relay_buffer_size);
     let make_service = make_service_fn(|socket: &AddrStream| {
         let client_addr = socket.remote_addr();
+        let tunneled = client_tunneled(socket.local_addr(), client_addr);
         let cloned_server = shared_server.clone();
         async move {
             Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                server_dispatch(req, client_addr, cloned_server.clone())
+                server_dispatch(req, client_addr, tunneled, cloned_server.clone())
             }))
         }
     });
-    let server = hyper::Server::bind(&listen_addr)
+    let server = hyper::Server::from_tcp(listener)
+        .map_err(std::io::Error::other)?
         .http1_only(true)
         .serve(make_service);
     if let Err(err) = server.await {
-        use std::io::Error;
-        return Err(Error::new(std::io::ErrorKind::Other, err));
+        return Err(std::io::Error::other(err));
     }
     Ok(())
 }
@@ -40,6 +47,7 @@ use std::str::FromStr;
 async fn server_dispatch(
     mut req: Request<Body>,
     client_addr: SocketAddr,
+    tunneled: bool,
     proxy_server: SharedProxyServer,
 ) -> std::io::Result<Response<Body>> {
     let host = match host_addr(req.uri()) {
@@ -125,7 +133,8 @@ async fn server_dispatch(
     };
     if Method::CONNECT == req.method() {
         let addr: SocketAddr = proxy_server.addr;
-        let stream = socks5::connect(&host, &addr).await?;
+        let relay_buffer_size = proxy_server.relay_buffer_size;
+        let stream = socks5::connect(&host, &addr, tunneled).await?;
         trace!(
             "CONNECT relay connected {} <-> {} ({})",
             client_addr,
@@ -141,7 +150,15 @@ async fn server_dispatch(
                         addr,
                         host
                     );
-                    establish_connect_tunnel(upgraded, stream, &addr, client_addr, host).await
+                    establish_connect_tunnel(
+                        upgraded,
+                        stream,
+                        &addr,
+                        client_addr,
+                        host,
+                        relay_buffer_size,
+                    )
+                    .await
                 }
                 Err(e) => {
                     trace!(
@@ -162,7 +179,13 @@ async fn server_dispatch(
         let conn_keep_alive = check_keep_alive(req.version(), req.headers(), true);
         clear_hop_headers(req.headers_mut());
         set_conn_keep_alive(req.version(), req.headers_mut(), conn_keep_alive);
-        let mut res: Response<Body> = match proxy_server.client.request(req).await {
+        // pooled connections are only shared between clients with the same --tunnel-uid decision
+        let client = if tunneled {
+            &proxy_server.client
+        } else {
+            &proxy_server.direct_client
+        };
+        let mut res: Response<Body> = match client.request(req).await {
             Ok(res) => res,
             Err(err) => {
                 trace!(
@@ -195,14 +218,17 @@ async fn establish_connect_tunnel(
     svr_addr: &SocketAddr,
     client_addr: SocketAddr,
     addr: Address,
+    relay_buffer_size: usize,
 ) {
-    use tokio::io::{copy, split};
+    use tokio::io::{copy_buf, split, BufReader};
 
-    let (mut r, mut w) = split(upgraded);
-    let (mut svr_r, mut svr_w) = stream.split();
+    let (r, mut w) = split(upgraded);
+    let (svr_r, mut svr_w) = stream.split();
+    let mut r = BufReader::with_capacity(relay_buffer_size, r);
+    let mut svr_r = BufReader::with_capacity(relay_buffer_size, svr_r);
 
-    let rhalf = copy(&mut r, &mut svr_w);
-    let whalf = copy(&mut svr_r, &mut w);
+    let rhalf = copy_buf(&mut r, &mut svr_w);
+    let whalf = copy_buf(&mut svr_r, &mut w);
 
     trace!(
         "CONNECT relay established {} <-> {} ({})",
@@ -405,7 +431,7 @@ fn clear_hop_headers(headers: &mut HeaderMap<HeaderValue>) {
     }
 
     for header in extra_headers {
-        while let Some(..) = headers.remove(&header) {}
+        while headers.remove(&header).is_some() {}
     }
 
     // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Connection
@@ -422,7 +448,7 @@ fn clear_hop_headers(headers: &mut HeaderMap<HeaderValue>) {
     ];
 
     for header in &HOP_BY_HOP_HEADERS {
-        while let Some(..) = headers.remove(*header) {}
+        while headers.remove(*header).is_some() {}
     }
 }
 
@@ -446,19 +472,26 @@ fn set_conn_keep_alive(version: Version, headers: &mut HeaderMap<HeaderValue>, k
 #[derive(Clone)]
 pub struct ProxyServer {
     client: http_client::SocksClient,
+    direct_client: http_client::SocksClient,
     addr: SocketAddr,
+    relay_buffer_size: usize,
 }
 pub type SharedProxyServer = std::sync::Arc<ProxyServer>;
 impl ProxyServer {
-    fn new(addr: SocketAddr) -> ProxyServer {
-        let connector = http_client::SocksConnector::new(addr);
+    fn new(addr: SocketAddr, relay_buffer_size: usize) -> ProxyServer {
+        let connector = http_client::SocksConnector::new(addr, true);
         let proxy_client: http_client::SocksClient = hyper::Client::builder().build(connector);
+        let direct_connector = http_client::SocksConnector::new(addr, false);
+        let direct_client: http_client::SocksClient =
+            hyper::Client::builder().build(direct_connector);
         ProxyServer {
             addr,
             client: proxy_client,
+            direct_client,
+            relay_buffer_size,
         }
     }
-    fn new_shared(addr: SocketAddr) -> SharedProxyServer {
-        std::sync::Arc::new(ProxyServer::new(addr))
+    fn new_shared(addr: SocketAddr, relay_buffer_size: usize) -> SharedProxyServer {
+        std::sync::Arc::new(ProxyServer::new(addr, relay_buffer_size))
     }
 }
@@ -3,10 +3,14 @@ mod consts;
 mod http_client;
 mod http_local;
 mod socks5;
-use std::net::SocketAddr;
+use std::net::{SocketAddr, TcpListener};
 
-pub async fn run_tokio(local_listen_addr: SocketAddr, proxy_address: SocketAddr) {
-    http_local::run(local_listen_addr, proxy_address)
+pub async fn run_tokio(
+    local_listener: TcpListener,
+    proxy_address: SocketAddr,
+    relay_buffer_size: usize,
+) {
+    http_local::run(local_listener, proxy_address, relay_buffer_size)
         .await
         .unwrap()
 }
@@ -1,3 +1,4 @@
+use crate::connect::socket_owner::forward_decision;
 use crate::socks2http::address::Address;
 use crate::socks2http::consts;
 use bytes::{BufMut, BytesMut};
@@ -43,8 +44,11 @@ pub struct TcpRequestHeader {
 pub async fn connect<S: tokio::net::ToSocketAddrs>(
     addr: &Address,
     proxy: &S,
+    tunneled: bool,
 ) -> io::Result<TcpStream> {
     let mut client_stream = TcpStream::connect(proxy).await?;
+    // the SOCKS5 proxy would otherwise see us, rather than our client, as the owner of this connection
+    forward_decision(client_stream.local_addr()?, tunneled);
     // handshake
     let handshake_request = HandshakeRequest::new(vec![consts::SOCKS5_AUTH_METHOD_NONE]);
     handshake_request.write_to(&mut client_stream).await?;
@@ -64,7 +68,7 @@ pub async fn connect<S: tokio::net::ToSocketAddrs>(
     match tcp_res_header.reply {
         Reply::Succeeded => {}
         r => {
-            let err = io::Error::new(io::ErrorKind::Other, format!("{}", r));
+            let err = io::Error::other(format!("{}", r));
             return Err(err);
         }
     }
@@ -137,7 +141,7 @@ pub struct HandshakeResponse {
     pub chosen_method: u8,
 }
 impl HandshakeResponse {
-    /// Creates a handshake response
+    // Creates a handshake response
     // pub fn new(cm: u8) -> HandshakeResponse {
     //     HandshakeResponse { chosen_method: cm }
     // }
@@ -169,8 +173,6 @@ impl HandshakeResponse {
 pub struct TcpResponseHeader {
     /// SOCKS5 reply
     pub reply: Reply,
-    /// Reply address
-    pub address: Address,
 }
 impl TcpResponseHeader {
     /// Read from a reader
@@ -189,10 +191,10 @@ impl TcpResponseHeader {
                 format!("unsupported socks version {:#x}", ver),
             ));
         }
-        let address = Address::read_from(r).await?;
+        // the bound address must be read off the stream, but nothing needs it
+        Address::read_from(r).await?;
         Ok(TcpResponseHeader {
             reply: Reply::from_u8(reply_code),
-            address,
         })
     }
 }
@@ -285,6 +287,6 @@ impl From<io::Error> for Error {
 }
 impl From<Error> for io::Error {
     fn from(err: Error) -> io::Error {
-        io::Error::new(io::ErrorKind::Other, err.message)
+        io::Error::other(err.message)
     }
 }
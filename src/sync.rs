@@ -1,11 +1,13 @@
-use geph4_protocol::binder::protocol::Level;
+use geph4_protocol::binder::protocol::{ExitDescriptor, Level};
 
+use anyhow::Context;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
+use std::path::PathBuf;
 use structopt::StructOpt;
 
-use crate::config::{get_conninfo_store, AuthOpt, CommonOpt};
+use crate::config::{get_conninfo_store, write_private, AuthOpt, CommonOpt};
 
 #[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
 pub struct SyncOpt {
@@ -18,10 +20,39 @@ pub struct SyncOpt {
     /// Forces synchronization of fresh data.
     #[structopt(long)]
     pub force: bool,
+
+    /// Also writes the bridges, keys, and authentication token of the exit given by --export-exit to this file, for use with "connect --endpoints-file" where the binder is unreachable.
+    #[structopt(long)]
+    pub export_endpoints: Option<PathBuf>,
+
+    /// The exit whose endpoints are written by --export-endpoints.
+    #[structopt(long)]
+    pub export_exit: Option<String>,
+
+    /// Prints the account tier and expiry, the available exits, and the cached endpoints as a single JSON object, in a stable format meant for scripts.
+    #[structopt(long)]
+    pub json: bool,
 }
 
 pub async fn main_sync(opt: SyncOpt) -> anyhow::Result<()> {
-    println!("{}", sync_json(opt).await?);
+    if let Some(path) = opt.export_endpoints.as_ref() {
+        let exit = opt
+            .export_exit
+            .as_deref()
+            .context("--export-endpoints requires --export-exit")?;
+        let store = get_conninfo_store(&opt.common, &opt.auth, exit).await?;
+        store.refresh().await?;
+        // the endpoints include the authentication token
+        write_private(
+            path,
+            &serde_json::to_vec_pretty(&store.export_endpoints()?)?,
+        )?;
+    }
+    if opt.json {
+        println!("{}", sync_stats_json(opt).await?);
+    } else {
+        println!("{}", sync_json(opt).await?);
+    }
     Ok(())
 }
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -32,8 +63,65 @@ pub async fn sync_json(opt: SyncOpt) -> anyhow::Result<String> {
 
     let master = binder_client.summary();
     let user = binder_client.user_info();
-    let exits = master
-        .exits
+    let exits = dumb_down_exits(master.exits);
+    Ok(format!(
+        "{{\"exits\": {}, \"user\": {}, \"version\": {:?}}}",
+        serde_json::to_string(&exits)?,
+        serde_json::to_string(&user)?,
+        VERSION
+    ))
+}
+
+/// Like [sync_json], but with the account and cached endpoint state laid out for scripts, for `sync --json`.
+async fn sync_stats_json(opt: SyncOpt) -> anyhow::Result<String> {
+    let store = get_conninfo_store(&opt.common, &opt.auth, "").await?;
+    store.refresh().await?;
+
+    let user = store.user_info();
+    let cached_exit = store.cached_exit();
+    let stats = SyncStats {
+        version: VERSION,
+        account: AccountStats {
+            userid: user.userid,
+            tier: level_name(
+                user.subscription
+                    .as_ref()
+                    .map(|sub| sub.level)
+                    .unwrap_or(Level::Free),
+            ),
+            expires_unix: user.subscription.as_ref().map(|sub| sub.expires_unix),
+        },
+        exits: dumb_down_exits(store.summary().exits),
+        endpoints: EndpointStats {
+            exit: (!cached_exit.is_empty()).then_some(cached_exit),
+            bridges: store
+                .bridges()
+                .into_iter()
+                .map(|bridge| BridgeStats {
+                    endpoint: bridge.endpoint.to_string(),
+                    protocol: bridge.protocol.into(),
+                    is_direct: bridge.is_direct,
+                })
+                .collect(),
+            sticky_bridges: store
+                .sticky_bridges()
+                .iter()
+                .map(|endpoint| endpoint.to_string())
+                .collect(),
+        },
+    };
+    Ok(serde_json::to_string_pretty(&stats)?)
+}
+
+fn level_name(level: Level) -> String {
+    match level {
+        Level::Free => "free".to_string(),
+        Level::Plus => "plus".to_string(),
+    }
+}
+
+fn dumb_down_exits(exits: Vec<ExitDescriptor>) -> Vec<DumbedDownExitDescriptor> {
+    exits
         .into_iter()
         .map(|exit| DumbedDownExitDescriptor {
             hostname: exit.hostname.into(),
@@ -43,20 +131,42 @@ pub async fn sync_json(opt: SyncOpt) -> anyhow::Result<String> {
             allowed_levels: exit
                 .allowed_levels
                 .into_iter()
-                .map(|l| match l {
-                    Level::Free => "free".to_string(),
-                    Level::Plus => "plus".to_string(),
-                })
+                .map(level_name)
                 .collect_vec(),
             load: exit.load,
         })
-        .collect_vec();
-    Ok(format!(
-        "{{\"exits\": {}, \"user\": {}, \"version\": {:?}}}",
-        serde_json::to_string(&exits)?,
-        serde_json::to_string(&user)?,
-        VERSION
-    ))
+        .collect_vec()
+}
+
+#[derive(Serialize)]
+struct SyncStats {
+    version: &'static str,
+    account: AccountStats,
+    exits: Vec<DumbedDownExitDescriptor>,
+    endpoints: EndpointStats,
+}
+
+#[derive(Serialize)]
+struct AccountStats {
+    userid: i32,
+    tier: String,
+    /// When the subscription expires, or null on the free tier.
+    expires_unix: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct EndpointStats {
+    /// The exit whose bridges are cached, if any.
+    exit: Option<String>,
+    bridges: Vec<BridgeStats>,
+    sticky_bridges: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct BridgeStats {
+    endpoint: String,
+    protocol: String,
+    is_direct: bool,
 }
 
 #[derive(Serialize)]
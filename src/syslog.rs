@@ -0,0 +1,38 @@
+//! Sending log output to syslog, and so to the systemd journal where there is one, for --log-target syslog.
+
+/// Connects to syslog, so that [send] can be used.
+#[cfg(unix)]
+pub fn open() -> anyhow::Result<()> {
+    // syslog keeps the pointer to the identifier, so it must live forever
+    static IDENT: &[u8] = b"geph4-client\0";
+    unsafe {
+        libc::openlog(
+            IDENT.as_ptr() as *const libc::c_char,
+            libc::LOG_PID | libc::LOG_NDELAY,
+            libc::LOG_DAEMON,
+        )
+    };
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn open() -> anyhow::Result<()> {
+    anyhow::bail!("syslog is only supported on Unix")
+}
+
+/// Sends a line to syslog, with the severity matching the log level.
+#[cfg(unix)]
+pub fn send(level: log::Level, line: &str) {
+    let priority = match level {
+        log::Level::Error => libc::LOG_ERR,
+        log::Level::Warn => libc::LOG_WARNING,
+        log::Level::Info => libc::LOG_INFO,
+        log::Level::Debug | log::Level::Trace => libc::LOG_DEBUG,
+    };
+    // interior NULs would cut the line short, so they are replaced
+    let line = std::ffi::CString::new(line.replace('\0', "\\0")).unwrap();
+    unsafe { libc::syslog(priority, c"%s".as_ptr(), line.as_ptr()) };
+}
+
+#[cfg(not(unix))]
+pub fn send(_level: log::Level, _line: &str) {}
@@ -0,0 +1,49 @@
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::config::{CommonOpt, ObfsLayer, VpnMode};
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub struct VersionOpt {
+    #[structopt(flatten)]
+    pub common: CommonOpt,
+
+    /// Prints the information as JSON.
+    #[structopt(long)]
+    pub json: bool,
+}
+
+/// What this build of the client is and what it supports.
+#[derive(Serialize)]
+struct VersionInfo {
+    version: &'static str,
+    os: &'static str,
+    arch: &'static str,
+    protocols: Vec<&'static str>,
+    vpn_modes: Vec<&'static str>,
+    binder_master_fingerprint: String,
+}
+
+/// Prints the client version along with the protocols, VPN modes, and binder key it was built with, to diagnose mismatches.
+pub fn main_version(opt: &VersionOpt) -> anyhow::Result<()> {
+    let info = VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        os: std::env::consts::OS,
+        arch: std::env::consts::ARCH,
+        protocols: [ObfsLayer::ObfsUdp, ObfsLayer::TlsMimic]
+            .iter()
+            .map(|layer| layer.bridge_protocol())
+            .collect(),
+        vpn_modes: VpnMode::available().to_vec(),
+        binder_master_fingerprint: opt.common.binder_master_fingerprint(),
+    };
+    if opt.json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+    } else {
+        println!("geph4-client {} ({}/{})", info.version, info.os, info.arch);
+        println!("protocols: {}", info.protocols.join(", "));
+        println!("VPN modes: {}", info.vpn_modes.join(", "));
+        println!("binder master key: {}", info.binder_master_fingerprint);
+    }
+    Ok(())
+}